@@ -0,0 +1,336 @@
+use chrono::{Local, TimeZone};
+use clap::ArgMatches;
+use lenote_client::Client;
+use lenote_common::api::*;
+use lenote_common::files::FileAttachment;
+use lenote_common::models::*;
+use lenote_common::tags::parse_tag;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Size of the pages of tag maps fetched while looking for enough notes to list
+const TAG_MAP_PAGE: i64 = 100;
+
+/// Number of notes fetched at once for the tag maps listed
+const NOTES_PAGE: usize = 100;
+
+/// A note as listed by `tag`, with its status in the tag
+#[derive(Serialize)]
+struct TaggedNote {
+    tag_map: TagMap,
+    note: Note,
+}
+
+pub async fn run(
+    client: &Client,
+    name: &str,
+    matches: &ArgMatches<'_>,
+) -> Result<(), failure::Error> {
+    let json = matches.is_present("json");
+    match name {
+        "add" => add(client, matches, json).await,
+        "tags" => tags(client, json).await,
+        "tag" => tag(client, matches, json).await,
+        "archive" => set_status(client, matches, TagMapStatus::Archived, json).await,
+        "unarchive" => set_status(client, matches, TagMapStatus::Active, json).await,
+        _ => Err(failure::format_err!("Unknown command {}", name)),
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), failure::Error> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn time_string(timestamp: i64) -> String {
+    Local
+        .timestamp(timestamp, 0)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+/// The tag without its `#`, as the API takes it in paths
+fn naked_tag(input: &str) -> Result<String, failure::Error> {
+    match parse_tag(input) {
+        Some(tag) => Ok(tag[1..].to_string()),
+        None => Err(failure::format_err!("Invalid tag {}", input)),
+    }
+}
+
+fn new_note(text: String, note_type: NoteType) -> Note {
+    Note {
+        id: 0,
+        client_id: format!("cli-{:016x}", rand::random::<u64>()),
+        text,
+        timestamp: 0,
+        note_type,
+        tags: HashSet::new(),
+    }
+}
+
+/// Adds the text given, the text piped to stdin, or an image
+async fn add(client: &Client, matches: &ArgMatches<'_>, json: bool) -> Result<(), failure::Error> {
+    let note = match (matches.value_of("text"), matches.value_of("image")) {
+        (_, Some(image)) => {
+            let path = Path::new(image);
+            let content = fs::read(path)
+                .map_err(|e| failure::format_err!("Failed to read {}: {}", image, e))?;
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            let uploaded = client.upload(&UploadImage, &file_name, &content).await?;
+            new_note(uploaded.path, NoteType::Image)
+        }
+        (Some(text), None) if text != "-" => new_note(text.to_string(), NoteType::Text),
+        _ => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text)?;
+            new_note(text.trim_end().to_string(), NoteType::Text)
+        }
+    };
+
+    if note.text.trim().is_empty() {
+        return Err(failure::format_err!("Nothing to add, the note is empty"));
+    }
+
+    let note = client.send(&SaveNote(note)).await?;
+    if json {
+        return print_json(&note);
+    }
+
+    let mut tags: Vec<&String> = note.tags.iter().collect();
+    tags.sort();
+    if tags.is_empty() {
+        println!("Added note {}", note.id);
+    } else {
+        let tags: Vec<&str> = tags.into_iter().map(String::as_str).collect();
+        println!("Added note {} to {}", note.id, tags.join(" "));
+    }
+
+    Ok(())
+}
+
+async fn tags(client: &Client, json: bool) -> Result<(), failure::Error> {
+    let mut tags = vec![];
+    let mut after = None;
+    loop {
+        let page = client
+            .send(&GetTags(GetTagsFilter {
+                after,
+                ..Default::default()
+            }))
+            .await?;
+        tags.extend(page.items);
+        match page.next_cursor {
+            Some(cursor) => after = Some(cursor),
+            None => break,
+        }
+    }
+    if json {
+        return print_json(&tags);
+    }
+
+    for tag in &tags {
+        let archived = tag
+            .maps
+            .iter()
+            .filter(|m| m.status == TagMapStatus::Archived)
+            .count();
+        println!(
+            "{:<32} {:>5} active {:>5} archived",
+            tag.display,
+            tag.maps.len() - archived,
+            archived
+        );
+    }
+
+    Ok(())
+}
+
+/// The notes the `tag` command lists
+#[derive(Debug, PartialEq)]
+struct TagListing {
+    tag: String,
+    limit: usize,
+    /// Lists only the notes with this status in the tag
+    status: Option<TagMapStatus>,
+}
+
+impl TagListing {
+    fn from_matches(matches: &ArgMatches<'_>) -> Result<Self, failure::Error> {
+        let status = if matches.is_present("active") {
+            Some(TagMapStatus::Active)
+        } else if matches.is_present("archived") {
+            Some(TagMapStatus::Archived)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            tag: naked_tag(matches.value_of("tag").unwrap())?,
+            limit: matches.value_of("limit").unwrap().parse()?,
+            status,
+        })
+    }
+}
+
+/// Lists the newest notes of a tag, optionally only the active or archived ones
+async fn tag(client: &Client, matches: &ArgMatches<'_>, json: bool) -> Result<(), failure::Error> {
+    let TagListing { tag, limit, status } = TagListing::from_matches(matches)?;
+
+    let mut tag_maps = vec![];
+    let mut before = None;
+    while tag_maps.len() < limit {
+        let page = client
+            .send(&GetTagMap {
+                tag: tag.clone(),
+                filter: GetTagMapFilter {
+                    before,
+                    limit: Some(TAG_MAP_PAGE),
+                    ..Default::default()
+                },
+            })
+            .await?;
+        tag_maps.extend(
+            page.items
+                .into_iter()
+                .filter(|m| status.is_none() || Some(m.status) == status),
+        );
+        match page.next_cursor {
+            Some(cursor) => before = Some(cursor),
+            None => break,
+        }
+    }
+    tag_maps.truncate(limit);
+
+    let mut found = HashMap::new();
+    for batch in tag_maps.chunks(NOTES_PAGE) {
+        let page = client
+            .send(&GetNotes(GetNotesFilter {
+                ids: Some(NoteIds(batch.iter().map(|m| m.note_id).collect())),
+                ..Default::default()
+            }))
+            .await?;
+        found.extend(page.items.into_iter().map(|n| (n.id, n)));
+    }
+
+    let notes: Vec<_> = tag_maps
+        .into_iter()
+        .filter_map(|tag_map| {
+            let note = found.remove(&tag_map.note_id)?;
+            Some(TaggedNote { tag_map, note })
+        })
+        .collect();
+
+    if json {
+        return print_json(&notes);
+    }
+
+    for TaggedNote { tag_map, note } in &notes {
+        let status = match tag_map.status {
+            TagMapStatus::Active => "active",
+            TagMapStatus::Archived => "archived",
+        };
+        println!("[{}] {} {}", note.id, time_string(note.timestamp), status);
+        let text = match note.note_type {
+            NoteType::Text => note.text.clone(),
+            NoteType::Image => format!("Image {}", note.text),
+            NoteType::File => match FileAttachment::from_text(&note.text) {
+                Some(file) => format!("File {}", file.name),
+                None => String::from("File"),
+            },
+        };
+        for line in text.lines() {
+            println!("    {}", line);
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+async fn set_status(
+    client: &Client,
+    matches: &ArgMatches<'_>,
+    status: TagMapStatus,
+    json: bool,
+) -> Result<(), failure::Error> {
+    let tag = naked_tag(matches.value_of("tag").unwrap())?;
+    let note_id: i64 = matches.value_of("note").unwrap().parse()?;
+
+    let tag_map = TagMap {
+        tag: format!("#{}", tag),
+        note_id,
+        status,
+        timestamp: 0,
+    };
+    let tag_map = client
+        .send(&SaveTagMap {
+            tag: tag.clone(),
+            tag_map,
+        })
+        .await?;
+    if json {
+        return print_json(&tag_map);
+    }
+
+    match status {
+        TagMapStatus::Active => println!("Made note {} active in #{}", note_id, tag),
+        TagMapStatus::Archived => println!("Archived note {} in #{}", note_id, tag),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag_listing(args: &[&str]) -> Result<TagListing, String> {
+        let matches = crate::app()
+            .get_matches_from_safe(["lenote", "tag"].iter().chain(args))
+            .map_err(|e| e.message)?;
+        let (_, matches) = matches.subcommand();
+        TagListing::from_matches(matches.unwrap()).map_err(|e| e.to_string())
+    }
+
+    fn listing(tag: &str, limit: usize, status: Option<TagMapStatus>) -> TagListing {
+        TagListing {
+            tag: tag.to_string(),
+            limit,
+            status,
+        }
+    }
+
+    #[test]
+    fn parses_tag_listings() {
+        assert_eq!(tag_listing(&["work"]), Ok(listing("work", 20, None)));
+        assert_eq!(
+            tag_listing(&["#work/sub", "--active"]),
+            Ok(listing("work/sub", 20, Some(TagMapStatus::Active)))
+        );
+        assert_eq!(
+            tag_listing(&["--archived", "--limit", "5", "work"]),
+            Ok(listing("work", 5, Some(TagMapStatus::Archived)))
+        );
+        assert_eq!(
+            tag_listing(&["work", "--json", "--limit=0"]),
+            Ok(listing("work", 0, None))
+        );
+    }
+
+    #[test]
+    fn refuses_invalid_tag_listings() {
+        let invalid = [
+            vec!["work", "--active", "--archived"],
+            vec!["work", "--limit", "-1"],
+            vec!["work", "--limit", "many"],
+            vec!["--active"],
+            vec!["not a tag!"],
+        ];
+        for args in &invalid {
+            assert!(tag_listing(args).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,41 @@
+//! Where the server is and how to log in to it, read from a JSON file such as
+//!
+//! ```json
+//! { "url": "http://localhost:8080", "token": "lenote_..." }
+//! ```
+//!
+//! The token is an API token created in the settings page of the app.
+
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub url: String,
+    pub token: String,
+}
+
+/// `$XDG_CONFIG_HOME/lenote/config.json`, or `~/.config/lenote/config.json`
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("lenote").join("config.json"))
+}
+
+pub fn load(path: &Path) -> Result<Config, failure::Error> {
+    let json = fs::read_to_string(path).map_err(|e| {
+        failure::format_err!(
+            "Failed to read the config file {}: {}\n\
+            It should look like {{ \"url\": \"http://localhost:8080\", \"token\": \"lenote_...\" }}",
+            path.display(),
+            e
+        )
+    })?;
+    serde_json::from_str(&json)
+        .map_err(|e| failure::format_err!("Invalid config file {}: {}", path.display(), e))
+}
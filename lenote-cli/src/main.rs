@@ -0,0 +1,127 @@
+mod commands;
+mod config;
+
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use lenote_client::Client;
+use std::path::PathBuf;
+
+fn tag_arg() -> Arg<'static, 'static> {
+    Arg::with_name("tag")
+        .value_name("TAG")
+        .required(true)
+        .help("The tag, with or without its #")
+}
+
+fn note_arg() -> Arg<'static, 'static> {
+    Arg::with_name("note")
+        .value_name("NOTE_ID")
+        .required(true)
+        .help("ID of the note, as listed by the tag command")
+        .validator(|v| match v.parse::<i64>() {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!("Invalid note ID: {}", v)),
+        })
+}
+
+fn app() -> clap::App<'static, 'static> {
+    clap::App::new("lenote")
+        .about("Adds and lists notes of a Lenote server")
+        .version("0.1.0")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .value_name("FILE")
+                .global(true)
+                .help("Path of the config file with the server URL and API token [default: ~/.config/lenote/config.json]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .global(true)
+                .help("Prints the responses of the server as JSON"),
+        )
+        .subcommand(
+            SubCommand::with_name("add")
+                .about("Adds a note, reading its text from stdin when none is given")
+                .arg(
+                    Arg::with_name("text")
+                        .value_name("TEXT")
+                        .help("Text of the note, - to read it from stdin"),
+                )
+                .arg(
+                    Arg::with_name("image")
+                        .long("image")
+                        .value_name("FILE")
+                        .conflicts_with("text")
+                        .help("Adds an image note instead")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("tags").about("Lists the tags in use"))
+        .subcommand(
+            SubCommand::with_name("tag")
+                .about("Lists the newest notes of a tag")
+                .arg(tag_arg())
+                .arg(
+                    Arg::with_name("active")
+                        .long("active")
+                        .help("Only lists the notes active in the tag"),
+                )
+                .arg(
+                    Arg::with_name("archived")
+                        .long("archived")
+                        .conflicts_with("active")
+                        .help("Only lists the notes archived in the tag"),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("COUNT")
+                        .default_value("20")
+                        .help("Most notes to list")
+                        .validator(|v| match v.parse::<usize>() {
+                            Ok(_) => Ok(()),
+                            Err(_) => Err(format!("Invalid count: {}", v)),
+                        })
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("archive")
+                .about("Archives a note in a tag")
+                .arg(tag_arg())
+                .arg(note_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("unarchive")
+                .about("Makes an archived note active in a tag again")
+                .arg(tag_arg())
+                .arg(note_arg()),
+        )
+}
+
+async fn run(matches: &ArgMatches<'static>) -> Result<(), failure::Error> {
+    let (name, sub_matches) = matches.subcommand();
+    let sub_matches = sub_matches.unwrap();
+
+    let config_path = match sub_matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => config::default_path()
+            .ok_or_else(|| failure::format_err!("No home directory to find the config in"))?,
+    };
+    let config = config::load(&config_path)?;
+
+    let client = Client::new(&config.url, &config.token);
+    commands::run(&client, name, sub_matches).await
+}
+
+#[actix_rt::main]
+async fn main() {
+    let matches = app().get_matches();
+    if let Err(e) = run(&matches).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,183 @@
+//! Async client for the API of a Lenote server, authenticated with an API token.
+//!
+//! Requests are made of the endpoints in `lenote_common::api`, so that the client
+//! always agrees with the routes and types of the server it's built with.
+//! It runs on the actix runtime, e.g. within `#[actix_rt::main]`.
+
+use awc::error::PayloadError;
+use awc::http::{self, StatusCode};
+use awc::ClientResponse;
+use bytes::Bytes;
+use futures::Stream;
+use lenote_common::api::{Endpoint, Method};
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(60);
+const RESPONSE_LIMIT: usize = 16 * 1024 * 1024;
+
+pub struct Client {
+    http: awc::Client,
+    url: String,
+}
+
+fn http_method(method: Method) -> http::Method {
+    match method {
+        Method::Get => http::Method::GET,
+        Method::Post => http::Method::POST,
+        Method::Put => http::Method::PUT,
+        Method::Delete => http::Method::DELETE,
+    }
+}
+
+impl Client {
+    /// A client for the server at `url`, such as `http://localhost:8080`
+    pub fn new(url: &str, token: &str) -> Self {
+        Self {
+            http: awc::Client::build()
+                .timeout(TIMEOUT)
+                .bearer_auth(token)
+                .finish(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn request<E: Endpoint>(&self, endpoint: &E) -> awc::ClientRequest {
+        self.http.request(
+            http_method(E::METHOD),
+            format!("{}{}", self.url, endpoint.url()),
+        )
+    }
+
+    /// Sends a request to an endpoint, with its JSON body if it has one
+    pub async fn send<E: Endpoint>(&self, endpoint: &E) -> Result<E::Response, failure::Error> {
+        let request = self.request(endpoint);
+        let response = match endpoint.body() {
+            Some(body) => request.send_json(body).await,
+            None => request.send().await,
+        }
+        .map_err(|e| failure::format_err!("Failed to reach the server: {}", e))?;
+
+        read_response(response).await
+    }
+
+    /// Uploads a file to an endpoint taking a multipart body, such as `UploadImage`
+    pub async fn upload<E: Endpoint>(
+        &self,
+        endpoint: &E,
+        file_name: &str,
+        content: &[u8],
+    ) -> Result<E::Response, failure::Error> {
+        let boundary = format!("lenote-{:016x}", rand::random::<u64>());
+        let mut body = format!(
+            "--{}\r\n\
+            Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+            Content-Type: application/octet-stream\r\n\r\n",
+            boundary,
+            file_name.replace(|c: char| c == '"' || c.is_control(), "")
+        )
+        .into_bytes();
+        body.extend_from_slice(content);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let response = self
+            .request(endpoint)
+            .content_type(format!("multipart/form-data; boundary={}", boundary))
+            .send_body(body)
+            .await
+            .map_err(|e| failure::format_err!("Failed to reach the server: {}", e))?;
+
+        read_response(response).await
+    }
+}
+
+/// Reads the JSON of a response, or turns a failed one into an error with the reason the server gave
+async fn read_response<S, T>(mut response: ClientResponse<S>) -> Result<T, failure::Error>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+    T: serde::de::DeserializeOwned,
+{
+    let body = response
+        .body()
+        .limit(RESPONSE_LIMIT)
+        .await
+        .map_err(|e| failure::format_err!("Failed to read the response: {}", e))?;
+
+    parse_response(response.status(), &body)
+}
+
+fn parse_response<T: serde::de::DeserializeOwned>(
+    status: StatusCode,
+    body: &[u8],
+) -> Result<T, failure::Error> {
+    if !status.is_success() {
+        let reason = String::from_utf8_lossy(body).trim().to_string();
+        return Err(match status {
+            StatusCode::UNAUTHORIZED => {
+                failure::format_err!("The server turned down the API token: {}", reason)
+            }
+            _ if reason.is_empty() => failure::format_err!("The server responded with {}", status),
+            _ => failure::format_err!("The server responded with {}: {}", status, reason),
+        });
+    }
+
+    // Endpoints without a response send an empty body
+    let json: &[u8] = if body.is_empty() { b"null" } else { body };
+    serde_json::from_slice(json)
+        .map_err(|e| failure::format_err!("Invalid response from the server: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lenote_common::models::TagColor;
+
+    fn error<T: serde::de::DeserializeOwned + std::fmt::Debug>(
+        status: StatusCode,
+        body: &str,
+    ) -> String {
+        parse_response::<T>(status, body.as_bytes())
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn reads_json_responses() {
+        let color: TagColor = parse_response(StatusCode::OK, br##"{"color":"#000000"}"##).unwrap();
+        assert_eq!(color.color, "#000000");
+
+        // Endpoints responding with nothing
+        parse_response::<()>(StatusCode::OK, b"").unwrap();
+        parse_response::<()>(StatusCode::OK, b"null").unwrap();
+        assert_eq!(
+            parse_response::<Option<i64>>(StatusCode::OK, b"").unwrap(),
+            None
+        );
+
+        assert!(
+            error::<TagColor>(StatusCode::OK, "").starts_with("Invalid response from the server")
+        );
+        assert!(error::<TagColor>(StatusCode::OK, "<html>")
+            .starts_with("Invalid response from the server"));
+    }
+
+    #[test]
+    fn explains_failed_responses() {
+        assert_eq!(
+            error::<()>(StatusCode::UNAUTHORIZED, "Invalid API token\n"),
+            "The server turned down the API token: Invalid API token"
+        );
+        assert_eq!(
+            error::<()>(StatusCode::FORBIDDEN, "The API token is read-only"),
+            "The server responded with 403 Forbidden: The API token is read-only"
+        );
+        assert_eq!(
+            error::<()>(StatusCode::BAD_GATEWAY, " "),
+            "The server responded with 502 Bad Gateway"
+        );
+        // A failure is never read as a response, even when it looks like one
+        assert_eq!(
+            error::<Option<i64>>(StatusCode::NOT_FOUND, "null"),
+            "The server responded with 404 Not Found: null"
+        );
+    }
+}
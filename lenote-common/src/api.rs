@@ -0,0 +1,580 @@
+//! The HTTP API of the server, defined once for the server, the UI and the native client.
+//!
+//! Each endpoint is a struct holding what a request to it is made of: the values in its
+//! path, its query and its JSON body. `Endpoint` ties it to its route, method and
+//! response, so that changing any of them breaks the build of everything calling it.
+
+use crate::files::FileAttachment;
+use crate::models::*;
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use serde::de::{self, DeserializeOwned, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+
+/// Characters escaped in a tag put in a path, `/` is kept as it separates child tags
+const TAG_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'?')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+pub trait Endpoint {
+    /// Pattern the server registers the endpoint at, in actix-web syntax
+    const ROUTE: &'static str;
+    const METHOD: Method;
+    /// JSON body of the request, `()` for none
+    type Body: Serialize;
+    /// JSON body of the response, `()` for none
+    type Response: DeserializeOwned;
+
+    /// Path of the request, with the values of the route filled in
+    fn path(&self) -> String;
+
+    fn query(&self) -> Option<String> {
+        None
+    }
+
+    fn body(&self) -> Option<&Self::Body> {
+        None
+    }
+
+    fn url(&self) -> String {
+        match self.query() {
+            Some(query) if !query.is_empty() => format!("{}?{}", self.path(), query),
+            _ => self.path(),
+        }
+    }
+}
+
+/// A tag as put in a path, without its `#`
+fn tag_path(tag: &str) -> String {
+    utf8_percent_encode(tag.trim_start_matches('#'), TAG_PATH).to_string()
+}
+
+fn query_string<Q: Serialize>(query: &Q) -> Option<String> {
+    serde_urlencoded::to_string(query).ok()
+}
+
+/// IDs of notes, written in a query as `1,2,3`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct NoteIds(pub Vec<i64>);
+
+impl Serialize for NoteIds {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ids: Vec<String> = self.0.iter().map(|id| id.to_string()).collect();
+        serializer.serialize_str(&ids.join(","))
+    }
+}
+
+impl<'de> Deserialize<'de> for NoteIds {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let ids = String::deserialize(deserializer)?;
+        ids.split(',')
+            .filter(|id| !id.is_empty())
+            .map(|id| id.trim().parse().map_err(de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(NoteIds)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetNotesFilter {
+    pub min_id: Option<i64>,
+    pub max_id: Option<i64>,
+    /// Only these notes, in a single page. The range, cursors and limit are then ignored.
+    pub ids: Option<NoteIds>,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetTagsFilter {
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GetTagMapFilter {
+    /// Also list the notes of child tags, e.g. #project/alpha for #project
+    pub children: Option<bool>,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+/// A page of notes, newest first unless `after` is given
+pub struct GetNotes(pub GetNotesFilter);
+
+impl Endpoint for GetNotes {
+    const ROUTE: &'static str = "/api/notes{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Page<Note>;
+
+    fn path(&self) -> String {
+        String::from("/api/notes")
+    }
+
+    fn query(&self) -> Option<String> {
+        query_string(&self.0)
+    }
+}
+
+/// Adds a note, responding with it as saved along with its tags
+pub struct SaveNote(pub Note);
+
+impl Endpoint for SaveNote {
+    const ROUTE: &'static str = "/api/notes{_:/?}";
+    const METHOD: Method = Method::Post;
+    type Body = Note;
+    type Response = Note;
+
+    fn path(&self) -> String {
+        String::from("/api/notes")
+    }
+
+    fn body(&self) -> Option<&Note> {
+        Some(&self.0)
+    }
+}
+
+/// Changes the text of the note with the ID of the note given
+pub struct UpdateNote(pub Note);
+
+impl Endpoint for UpdateNote {
+    const ROUTE: &'static str = "/api/notes/{id}{_:/?}";
+    const METHOD: Method = Method::Put;
+    type Body = Note;
+    type Response = Note;
+
+    fn path(&self) -> String {
+        format!("/api/notes/{}", self.0.id)
+    }
+
+    fn body(&self) -> Option<&Note> {
+        Some(&self.0)
+    }
+}
+
+/// Moves a note to the trash
+pub struct DeleteNote {
+    pub id: i64,
+}
+
+impl Endpoint for DeleteNote {
+    const ROUTE: &'static str = "/api/notes/{id}{_:/?}";
+    const METHOD: Method = Method::Delete;
+    type Body = ();
+    type Response = ();
+
+    fn path(&self) -> String {
+        format!("/api/notes/{}", self.id)
+    }
+}
+
+/// The earlier texts of a note, oldest first
+pub struct GetNoteRevisions {
+    pub id: i64,
+}
+
+impl Endpoint for GetNoteRevisions {
+    const ROUTE: &'static str = "/api/notes/{id}/revisions{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<NoteRevision>;
+
+    fn path(&self) -> String {
+        format!("/api/notes/{}/revisions", self.id)
+    }
+}
+
+/// Uploads an image, sent as the `file` part of a multipart body rather than as JSON
+pub struct UploadImage;
+
+impl Endpoint for UploadImage {
+    const ROUTE: &'static str = "/api/images{_:/?}";
+    const METHOD: Method = Method::Post;
+    type Body = ();
+    type Response = UploadedFile;
+
+    fn path(&self) -> String {
+        String::from("/api/images")
+    }
+}
+
+/// Uploads a file to attach, sent as the `file` part of a multipart body rather than as JSON
+pub struct UploadFile;
+
+impl Endpoint for UploadFile {
+    const ROUTE: &'static str = "/api/files{_:/?}";
+    const METHOD: Method = Method::Post;
+    type Body = ();
+    type Response = FileAttachment;
+
+    fn path(&self) -> String {
+        String::from("/api/files")
+    }
+}
+
+/// The notes in the trash, most recently deleted first
+pub struct GetTrash;
+
+impl Endpoint for GetTrash {
+    const ROUTE: &'static str = "/api/trash{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<TrashedNote>;
+
+    fn path(&self) -> String {
+        String::from("/api/trash")
+    }
+}
+
+/// Deletes every note in the trash forever
+pub struct EmptyTrash;
+
+impl Endpoint for EmptyTrash {
+    const ROUTE: &'static str = "/api/trash{_:/?}";
+    const METHOD: Method = Method::Delete;
+    type Body = ();
+    type Response = ();
+
+    fn path(&self) -> String {
+        String::from("/api/trash")
+    }
+}
+
+pub struct RestoreNote {
+    pub id: i64,
+}
+
+impl Endpoint for RestoreNote {
+    const ROUTE: &'static str = "/api/trash/{id}/restore{_:/?}";
+    const METHOD: Method = Method::Post;
+    type Body = ();
+    type Response = Note;
+
+    fn path(&self) -> String {
+        format!("/api/trash/{}/restore", self.id)
+    }
+}
+
+/// Deletes a note in the trash forever
+pub struct PurgeNote {
+    pub id: i64,
+}
+
+impl Endpoint for PurgeNote {
+    const ROUTE: &'static str = "/api/trash/{id}{_:/?}";
+    const METHOD: Method = Method::Delete;
+    type Body = ();
+    type Response = ();
+
+    fn path(&self) -> String {
+        format!("/api/trash/{}", self.id)
+    }
+}
+
+pub struct Search(pub SearchFilter);
+
+impl Endpoint for Search {
+    const ROUTE: &'static str = "/api/search{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<SearchResult>;
+
+    fn path(&self) -> String {
+        String::from("/api/search")
+    }
+
+    fn query(&self) -> Option<String> {
+        query_string(&self.0)
+    }
+}
+
+/// A page of the tags in use, in alphabetical order
+pub struct GetTags(pub GetTagsFilter);
+
+impl Endpoint for GetTags {
+    const ROUTE: &'static str = "/api/tags{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Page<Tag, String>;
+
+    fn path(&self) -> String {
+        String::from("/api/tags")
+    }
+
+    fn query(&self) -> Option<String> {
+        query_string(&self.0)
+    }
+}
+
+pub struct GetTagStats;
+
+impl Endpoint for GetTagStats {
+    const ROUTE: &'static str = "/api/tag-stats{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<TagStats>;
+
+    fn path(&self) -> String {
+        String::from("/api/tag-stats")
+    }
+}
+
+/// The colors new tags are given
+pub struct GetTagPalette;
+
+impl Endpoint for GetTagPalette {
+    const ROUTE: &'static str = "/api/tag-colors{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<String>;
+
+    fn path(&self) -> String {
+        String::from("/api/tag-colors")
+    }
+}
+
+/// A page of the notes mapped to a tag, newest first unless `after` is given.
+/// Tags are given with or without their `#` here and below.
+pub struct GetTagMap {
+    pub tag: String,
+    pub filter: GetTagMapFilter,
+}
+
+impl Endpoint for GetTagMap {
+    const ROUTE: &'static str = "/api/tags/{tag:.+}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Page<TagMap>;
+
+    fn path(&self) -> String {
+        format!("/api/tags/{}", tag_path(&self.tag))
+    }
+
+    fn query(&self) -> Option<String> {
+        query_string(&self.filter)
+    }
+}
+
+/// Changes the status of a note in a tag
+pub struct SaveTagMap {
+    pub tag: String,
+    pub tag_map: TagMap,
+}
+
+impl Endpoint for SaveTagMap {
+    const ROUTE: &'static str = "/api/tags/{tag:.+}";
+    const METHOD: Method = Method::Post;
+    type Body = TagMap;
+    type Response = TagMap;
+
+    fn path(&self) -> String {
+        format!("/api/tags/{}", tag_path(&self.tag))
+    }
+
+    fn body(&self) -> Option<&TagMap> {
+        Some(&self.tag_map)
+    }
+}
+
+pub struct GetTagColor {
+    pub tag: String,
+}
+
+impl Endpoint for GetTagColor {
+    const ROUTE: &'static str = "/api/tag-actions/color/{tag:.+}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = TagColor;
+
+    fn path(&self) -> String {
+        format!("/api/tag-actions/color/{}", tag_path(&self.tag))
+    }
+}
+
+pub struct SaveTagColor {
+    pub tag: String,
+    pub color: TagColor,
+}
+
+impl Endpoint for SaveTagColor {
+    const ROUTE: &'static str = "/api/tag-actions/color/{tag:.+}";
+    const METHOD: Method = Method::Put;
+    type Body = TagColor;
+    type Response = TagColor;
+
+    fn path(&self) -> String {
+        format!("/api/tag-actions/color/{}", tag_path(&self.tag))
+    }
+
+    fn body(&self) -> Option<&TagColor> {
+        Some(&self.color)
+    }
+}
+
+/// Renames a tag in every note, to a tag that isn't used yet
+pub struct RenameTag {
+    pub tag: String,
+    pub target: TagTarget,
+}
+
+impl Endpoint for RenameTag {
+    const ROUTE: &'static str = "/api/tag-actions/rename/{tag:.+}";
+    const METHOD: Method = Method::Post;
+    type Body = TagTarget;
+    type Response = TagTarget;
+
+    fn path(&self) -> String {
+        format!("/api/tag-actions/rename/{}", tag_path(&self.tag))
+    }
+
+    fn body(&self) -> Option<&TagTarget> {
+        Some(&self.target)
+    }
+}
+
+/// Merges a tag into a tag already in use
+pub struct MergeTag {
+    pub tag: String,
+    pub target: TagTarget,
+}
+
+impl Endpoint for MergeTag {
+    const ROUTE: &'static str = "/api/tag-actions/merge/{tag:.+}";
+    const METHOD: Method = Method::Post;
+    type Body = TagTarget;
+    type Response = TagTarget;
+
+    fn path(&self) -> String {
+        format!("/api/tag-actions/merge/{}", tag_path(&self.tag))
+    }
+
+    fn body(&self) -> Option<&TagTarget> {
+        Some(&self.target)
+    }
+}
+
+/// The user logged in
+pub struct GetMe;
+
+impl Endpoint for GetMe {
+    const ROUTE: &'static str = "/api/me{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = User;
+
+    fn path(&self) -> String {
+        String::from("/api/me")
+    }
+}
+
+/// The API tokens of the user logged in
+pub struct GetTokens;
+
+impl Endpoint for GetTokens {
+    const ROUTE: &'static str = "/api/tokens{_:/?}";
+    const METHOD: Method = Method::Get;
+    type Body = ();
+    type Response = Vec<ApiToken>;
+
+    fn path(&self) -> String {
+        String::from("/api/tokens")
+    }
+}
+
+pub struct CreateToken(pub NewApiToken);
+
+impl Endpoint for CreateToken {
+    const ROUTE: &'static str = "/api/tokens{_:/?}";
+    const METHOD: Method = Method::Post;
+    type Body = NewApiToken;
+    type Response = CreatedApiToken;
+
+    fn path(&self) -> String {
+        String::from("/api/tokens")
+    }
+
+    fn body(&self) -> Option<&NewApiToken> {
+        Some(&self.0)
+    }
+}
+
+pub struct DeleteToken {
+    pub id: i64,
+}
+
+impl Endpoint for DeleteToken {
+    const ROUTE: &'static str = "/api/tokens/{id}{_:/?}";
+    const METHOD: Method = Method::Delete;
+    type Body = ();
+    type Response = ();
+
+    fn path(&self) -> String {
+        format!("/api/tokens/{}", self.id)
+    }
+}
+
+/// Route of the stream of `LiveEvent`s, sent as server-sent events with a JSON `data` each.
+/// It isn't an `Endpoint` as it never ends, browsers read it with an `EventSource`.
+pub const EVENTS_ROUTE: &str = "/api/events{_:/?}";
+pub const EVENTS_PATH: &str = "/api/events";
+
+/// A change made by any client, pushed to every app open once it's committed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum LiveEvent {
+    /// A note was added or edited
+    NoteSaved(Note),
+    /// The status of a note in a tag changed
+    TagMapChanged(TagMap),
+    /// A note used a tag for the first time
+    TagCreated(String),
+    /// A tag was renamed or merged into `to`, rewriting the notes using it
+    TagRenamed { from: String, to: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn note_ids_in_queries() {
+        let endpoint = GetNotes(GetNotesFilter {
+            ids: Some(NoteIds(vec![3, 1, 20])),
+            ..Default::default()
+        });
+        assert_eq!(endpoint.url(), "/api/notes?ids=3%2C1%2C20");
+
+        let parse = serde_urlencoded::from_str::<GetNotesFilter>;
+        assert_eq!(
+            parse("ids=3%2C1%2C20&limit=2").unwrap().ids,
+            Some(NoteIds(vec![3, 1, 20]))
+        );
+        assert_eq!(parse("ids=").unwrap().ids, Some(NoteIds(vec![])));
+        assert_eq!(parse("limit=2").unwrap().ids, None);
+        assert!(parse("ids=1,x").is_err());
+    }
+}
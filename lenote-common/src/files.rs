@@ -0,0 +1,96 @@
+//! File attachments, shared by the server and the UI.
+//!
+//! The text of a file note is its `FileAttachment` as JSON. The file itself is stored
+//! as `/res/files/<ab>/<sha256>`, while its name only lives in the notes.
+
+use serde::{Deserialize, Serialize};
+
+pub const FILES_PATH: &str = "/res/files/";
+
+/// Longest file name kept, in characters
+pub const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileAttachment {
+    pub path: String,
+    pub name: String,
+    /// Size of the file, in bytes
+    pub size: u64,
+}
+
+impl FileAttachment {
+    /// Reads the attachment of a file note from its text
+    pub fn from_text(text: &str) -> Option<Self> {
+        serde_json::from_str(text).ok()
+    }
+
+    pub fn to_text(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Turns the name a file was uploaded with into one that is safe to show and save:
+/// without directories or control characters, and not too long
+pub fn clean_file_name(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = base
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let cleaned = cleaned.trim();
+
+    if cleaned.is_empty() || cleaned == "." || cleaned == ".." {
+        String::from("file")
+    } else {
+        cleaned.to_string()
+    }
+}
+
+/// Formats a size in bytes the way people read it, e.g. `1.5 MB`
+pub fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KB", "MB", "GB", "TB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cleans_file_names() {
+        assert_eq!(clean_file_name("report.pdf"), "report.pdf");
+        assert_eq!(clean_file_name("../../etc/passwd"), "passwd");
+        assert_eq!(clean_file_name("C:\\Users\\me\\a b.txt"), "a b.txt");
+        assert_eq!(clean_file_name(" new\nline\t.txt "), "newline.txt");
+        for name in &["", "dir/", "..", " . ", "\u{7}"] {
+            assert_eq!(clean_file_name(name), "file", "{:?}", name);
+        }
+        assert_eq!(
+            clean_file_name(&"é".repeat(MAX_NAME_LENGTH + 10))
+                .chars()
+                .count(),
+            MAX_NAME_LENGTH
+        );
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+        assert_eq!(format_size(2048 * 1024_u64.pow(4)), "2048.0 TB");
+    }
+}
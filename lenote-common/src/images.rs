@@ -0,0 +1,33 @@
+//! Paths of stored images and of their thumbnails, shared by the server and the UI.
+//!
+//! The thumbnails of `/res/images/<path>` are `/res/thumbs/<size>/<path>`,
+//! scaled down to fit in a `size` by `size` square. Photos get JPEG thumbnails,
+//! everything else gets PNG ones to keep sharp edges and transparency.
+
+pub const IMAGES_PATH: &str = "/res/images/";
+pub const THUMBNAILS_PATH: &str = "/res/thumbs/";
+
+/// Sizes of the thumbnails of each image, for regular and high density screens
+pub const THUMBNAIL_SIZES: [u32; 2] = [480, 960];
+
+/// Gets the path of the thumbnail of the image at `image_path`,
+/// or None if the path is not the one of a stored image
+pub fn thumbnail_path(image_path: &str, size: u32) -> Option<String> {
+    let relative = image_path.strip_prefix(IMAGES_PATH)?;
+    let stem = match relative.rfind('.') {
+        Some(dot) if dot > relative.rfind('/').map_or(0, |slash| slash + 1) => &relative[..dot],
+        _ => relative,
+    };
+
+    let lowercase = relative.to_ascii_lowercase();
+    let extension = if lowercase.ends_with(".jpg") || lowercase.ends_with(".jpeg") {
+        "jpg"
+    } else {
+        "png"
+    };
+
+    Some(format!(
+        "{}{}/{}.{}",
+        THUMBNAILS_PATH, size, stem, extension
+    ))
+}
@@ -1,3 +1,7 @@
 #[macro_use]
 extern crate anyhow;
+pub mod api;
+pub mod files;
+pub mod images;
 pub mod models;
+pub mod tags;
@@ -1,10 +1,15 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
+/// Markers wrapped around matched terms in `SearchResult::snippet`
+pub const SNIPPET_MATCH_START: &str = "\u{2}";
+pub const SNIPPET_MATCH_END: &str = "\u{3}";
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
 pub enum NoteType {
     Text = 0,
     Image = 1,
+    File = 2,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -25,6 +30,9 @@ pub enum TagMapStatus {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TagMap {
+    /// The tag the note is mapped to, which can be a child of the requested tag
+    #[serde(default)]
+    pub tag: String,
     pub note_id: i64,
     pub status: TagMapStatus,
     pub timestamp: i64,
@@ -33,10 +41,84 @@ pub struct TagMap {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Tag {
     pub tag: String,
+    /// The tag as first written, `tag` is its lowercase key
+    pub display: String,
     pub color: String,
     pub maps: Vec<TagMap>,
 }
 
+/// Body of the tag rename & merge requests, naming the resulting tag
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagTarget {
+    pub tag: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagColor {
+    pub color: String,
+}
+
+/// Aggregated usage of a tag, excluding trashed notes
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TagStats {
+    pub tag: String,
+    pub display: String,
+    pub color: String,
+    pub active_count: i64,
+    pub archived_count: i64,
+    pub first_used: i64,
+    pub last_used: i64,
+}
+
+/// One page of a paginated listing.
+/// `next_cursor` is None when there is nothing more to load in that direction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Page<T, C = i64> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<C>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NoteRevision {
+    pub id: i64,
+    pub note_id: i64,
+    pub text: String,
+    pub timestamp: i64,
+}
+
+/// Response to an image upload, with the path image notes refer to the file by
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UploadedFile {
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashedNote {
+    pub note: Note,
+    pub deleted_at: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchResult {
+    pub note: Note,
+    pub snippet: String,
+}
+
+/// Everything in the database, as written by the export.
+/// The maps in `tags` are timestamped with their note,
+/// the ones in `tag_history` with the time their status was changed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Export {
+    /// Version of the database schema the data was exported from
+    pub schema_version: usize,
+    pub exported_at: i64,
+    pub notes: Vec<Note>,
+    pub trashed_notes: Vec<TrashedNote>,
+    pub revisions: Vec<NoteRevision>,
+    pub tags: Vec<Tag>,
+    pub tag_history: Vec<TagMap>,
+}
+
 impl TagMapStatus {
     pub fn from(x: i32) -> anyhow::Result<Self> {
         match x {
@@ -47,12 +129,64 @@ impl TagMapStatus {
     }
 }
 
+impl TokenScope {
+    pub fn from(x: i32) -> anyhow::Result<Self> {
+        match x {
+            0 => Ok(Self::Read),
+            1 => Ok(Self::Write),
+            _ => Err(anyhow!("Cannot convert value {} to TokenScope", x)),
+        }
+    }
+}
+
 impl NoteType {
     pub fn from(x: i32) -> anyhow::Result<Self> {
         match x {
             0 => Ok(Self::Text),
             1 => Ok(Self::Image),
+            2 => Ok(Self::File),
             _ => Err(anyhow!("Cannot convert value {} to NoteType", x)),
         }
     }
 }
+
+/// An account that can log in. Every account sees all notes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub created_at: i64,
+}
+
+/// What an API token is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TokenScope {
+    /// Only reading notes and tags
+    Read = 0,
+    /// Reading as well as posting and changing notes and tags
+    Write = 1,
+}
+
+/// A long-lived token for the notes and tags API, as listed in the settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+/// Body of the request creating an API token
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NewApiToken {
+    pub name: String,
+    pub scope: TokenScope,
+}
+
+/// A token just created. `secret` is never shown again after this.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CreatedApiToken {
+    pub token: ApiToken,
+    pub secret: String,
+}
@@ -0,0 +1,252 @@
+//! Hashtag parsing shared by the server and the UI.
+//!
+//! A tag is `#` followed by letters, digits, `_` or `-` of any script,
+//! and can be nested into a path like `#project/alpha`.
+//! Tags are matched case-insensitively through `tag_key`,
+//! while the text keeps them the way they were written.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use unicode_xid::UnicodeXID;
+
+/// A tag as written in a text
+#[derive(Debug, Clone, PartialEq)]
+pub struct TagToken<'a> {
+    /// Byte range of the tag in the text, including the `#`
+    pub range: Range<usize>,
+    pub text: &'a str,
+}
+
+fn is_tag_char(c: char) -> bool {
+    c == '-' || UnicodeXID::is_xid_continue(c)
+}
+
+/// A `#` right after these is not a tag, e.g. `C#`, `&#39;` or `page/#anchor`
+fn can_precede_tag(c: char) -> bool {
+    !is_tag_char(c) && c != '&' && c != '/' && c != '#'
+}
+
+/// Returns the index after the tag path starting at `start`, or `start` if there is none
+fn tag_end(chars: &[(usize, char)], start: usize) -> usize {
+    let mut end = start;
+    loop {
+        while end < chars.len() && is_tag_char(chars[end].1) {
+            end += 1;
+        }
+
+        // Only take the `/` if another segment follows it
+        let nested = end > start
+            && end + 1 < chars.len()
+            && chars[end].1 == '/'
+            && is_tag_char(chars[end + 1].1);
+        if !nested {
+            return end;
+        }
+        end += 1;
+    }
+}
+
+/// Whether a URL like `https://...` starts at `start`
+fn starts_url(chars: &[(usize, char)], start: usize) -> bool {
+    if !chars[start].1.is_ascii_alphabetic() {
+        return false;
+    }
+
+    let mut i = start + 1;
+    while i < chars.len() && (chars[i].1.is_ascii_alphanumeric() || "+-.".contains(chars[i].1)) {
+        i += 1;
+    }
+
+    chars[i..].iter().take(3).map(|c| c.1).eq("://".chars())
+}
+
+/// Skips the inline code starting with the backticks at `start`.
+/// Like in Markdown, it ends at the next run of as many backticks,
+/// and without one the backticks are plain text.
+fn skip_code(chars: &[(usize, char)], start: usize) -> usize {
+    let count_run = |from: usize| chars[from..].iter().take_while(|c| c.1 == '`').count();
+
+    let fence = count_run(start);
+    let mut i = start + fence;
+    while i < chars.len() {
+        if chars[i].1 == '`' {
+            let run = count_run(i);
+            if run == fence {
+                return i + run;
+            }
+            i += run;
+        } else {
+            i += 1;
+        }
+    }
+
+    start + fence
+}
+
+/// Finds the tags in `text` in order of appearance, skipping the ones
+/// inside URLs, inline code, and escaped like `\#tag`.
+pub fn find_tags(text: &str) -> Vec<TagToken<'_>> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let byte_at = |i: usize| chars.get(i).map(|c| c.0).unwrap_or(text.len());
+    let mut tokens = vec![];
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i].1;
+        let at_word_start = i == 0 || !is_tag_char(chars[i - 1].1);
+
+        if c == '`' {
+            i = skip_code(&chars, i);
+        } else if c == '\\' {
+            // Skips the escaped character along with the backslash
+            i += 2;
+        } else if at_word_start && starts_url(&chars, i) {
+            while i < chars.len() && !chars[i].1.is_whitespace() {
+                i += 1;
+            }
+        } else if c == '#' && (i == 0 || can_precede_tag(chars[i - 1].1)) {
+            let end = tag_end(&chars, i + 1);
+            if end > i + 1 {
+                let range = chars[i].0..byte_at(end);
+                tokens.push(TagToken {
+                    text: &text[range.clone()],
+                    range,
+                });
+            }
+            i = end.max(i + 1);
+        } else {
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+/// The key a tag is matched and stored by, e.g. `#café` for `#Café`
+pub fn tag_key(tag: &str) -> String {
+    tag.to_lowercase()
+}
+
+/// Maps the keys of the tags in `text` to how each was first written
+pub fn parse_tags(text: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    for token in find_tags(text) {
+        result
+            .entry(tag_key(token.text))
+            .or_insert_with(|| token.text.to_string());
+    }
+
+    result
+}
+
+/// Turns user input such as `Meeting` or `#Meeting` into a tag as written,
+/// or None if the input is not exactly one tag.
+pub fn parse_tag(input: &str) -> Option<String> {
+    let input = input.trim();
+    let tag = if input.starts_with('#') {
+        input.to_string()
+    } else {
+        format!("#{}", input)
+    };
+
+    match find_tags(&tag).as_slice() {
+        [token] if token.range == (0..tag.len()) => Some(tag),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags(text: &str) -> Vec<&str> {
+        find_tags(text).into_iter().map(|t| t.text).collect()
+    }
+
+    #[test]
+    fn finds_tags_with_their_range() {
+        assert_eq!(
+            find_tags("a #tag"),
+            vec![TagToken {
+                range: 2..6,
+                text: "#tag"
+            }]
+        );
+        assert_eq!(
+            tags("#one two #three_3 #with-dash"),
+            vec!["#one", "#three_3", "#with-dash"]
+        );
+        assert_eq!(tags("# heading, a lone # and ##"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn skips_url_fragments() {
+        assert_eq!(tags("http://x/#section #real"), vec!["#real"]);
+        assert_eq!(tags("[docs](https://x.io/a#intro) #real"), vec!["#real"]);
+        assert_eq!(tags("page/#anchor"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn skips_inline_and_fenced_code() {
+        assert_eq!(tags("`#code` #real"), vec!["#real"]);
+        assert_eq!(tags("`` a ` #code `` #real"), vec!["#real"]);
+        assert_eq!(tags("```\n#fenced\n```\n#after"), vec!["#after"]);
+        // Without a closing run, backticks are plain text
+        assert_eq!(tags("` #open"), vec!["#open"]);
+    }
+
+    #[test]
+    fn skips_escaped_tags() {
+        assert_eq!(tags("\\#escaped #real"), vec!["#real"]);
+        assert_eq!(tags("\\\\#real"), vec!["#real"]);
+    }
+
+    #[test]
+    fn finds_tags_in_any_script() {
+        assert_eq!(
+            tags("#café #日本 #Ünïcode"),
+            vec!["#café", "#日本", "#Ünïcode"]
+        );
+    }
+
+    #[test]
+    fn finds_nested_tags() {
+        assert_eq!(tags("#a/b #x/y/z"), vec!["#a/b", "#x/y/z"]);
+        assert_eq!(tags("#a/ #b//c"), vec!["#a", "#b"]);
+    }
+
+    #[test]
+    fn stops_at_punctuation() {
+        assert_eq!(
+            tags("#work, #home. (#paren) #end!"),
+            vec!["#work", "#home", "#paren", "#end"]
+        );
+        assert_eq!(tags("#a/b."), vec!["#a/b"]);
+    }
+
+    #[test]
+    fn skips_hashes_inside_words() {
+        assert_eq!(tags("C# and F#"), Vec::<&str>::new());
+        assert_eq!(tags("it&#39;s"), Vec::<&str>::new());
+        assert_eq!(tags("a#b"), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn keys_are_case_folded_and_display_is_kept() {
+        assert_eq!(tag_key("#Café/Ünï"), "#café/ünï");
+
+        let parsed = parse_tags("#Work then #WORK and #home");
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["#work"], "#Work");
+        assert_eq!(parsed["#home"], "#home");
+    }
+
+    #[test]
+    fn parses_one_tag_from_input() {
+        assert_eq!(parse_tag("Meeting"), Some(String::from("#Meeting")));
+        assert_eq!(parse_tag(" #a/b "), Some(String::from("#a/b")));
+        assert_eq!(parse_tag("two tags"), None);
+        assert_eq!(parse_tag("#a/"), None);
+        assert_eq!(parse_tag(""), None);
+    }
+}
@@ -0,0 +1,673 @@
+//! User accounts and login sessions.
+//!
+//! Passwords are hashed with Argon2. Logging in hands out a random session token
+//! in an HttpOnly cookie, of which only the SHA-256 is stored, so that a copy of
+//! the database can't be used to log in. `RequireLogin` turns away `/api` and `/res`
+//! requests without a valid session, and sends the pages of the app to the login page.
+//!
+//! Automation can instead send an API token as `Authorization: Bearer <token>`.
+//! Tokens are hashed the same way as sessions, only work for the notes and tags
+//! API along with the image and file uploads notes are made of, and read-only
+//! tokens are limited to `GET` requests.
+
+use crate::database;
+use crate::db_pool::DbPool;
+use crate::{AppState, Rejection};
+use actix_service::{Service, Transform};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{http, web, HttpMessage, HttpRequest, HttpResponse, Result as WebResult};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use lenote_common::models::{ApiToken, TokenScope, User};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+const SESSION_COOKIE: &str = "lenote_session";
+const SESSION_TTL: i64 = 30 * 24 * 60 * 60;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Marks API tokens, so that they are easy to recognize when leaked
+const API_TOKEN_PREFIX: &str = "lenote_";
+// How often the last use of an API token is written down
+const TOKEN_USE_INTERVAL: i64 = 60;
+
+lazy_static! {
+    // Checked against when there is no such user, so that a failed login takes as long either way
+    static ref DUMMY_HASH: String = hash_password("not a password").unwrap();
+}
+
+pub fn hash_password(password: &str) -> Result<String, failure::Error> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>())
+        .map_err(|e| failure::format_err!("Failed to make a salt: {}", e))?;
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| failure::format_err!("Failed to hash the password: {}", e))?;
+
+    Ok(hash.to_string())
+}
+
+fn verify_password(hash: &str, password: &str) -> bool {
+    match PasswordHash::new(hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn new_api_token() -> String {
+    format!(
+        "{}{}",
+        API_TOKEN_PREFIX,
+        hex::encode(rand::random::<[u8; 32]>())
+    )
+}
+
+fn session_cookie(token: &str, max_age: i64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        SESSION_COOKIE,
+        token,
+        max_age,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Where to go after logging in, only ever a path on this server.
+/// Browsers read `\` as `/` and drop tabs and newlines from URLs,
+/// so `/\evil.com` would lead away from the server as surely as `//evil.com`.
+fn local_path(next: Option<&str>) -> &str {
+    let local = |next: &str| {
+        next.starts_with('/')
+            && !next.starts_with("//")
+            && !next.chars().any(|c| c == '\\' || c.is_control())
+            && matches!(
+                next.parse::<http::Uri>(),
+                Ok(uri) if uri.scheme().is_none() && uri.authority().is_none()
+            )
+    };
+
+    match next {
+        Some(next) if local(next) => next,
+        _ => "/",
+    }
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .header(http::header::LOCATION, location)
+        .finish()
+}
+
+fn login_page_url(next: &str, failed: bool) -> String {
+    let mut query = vec![("next", next)];
+    if failed {
+        query.push(("failed", "1"));
+    }
+
+    format!(
+        "/login?{}",
+        serde_urlencoded::to_string(&query).unwrap_or_default()
+    )
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+/// Checks the credentials of the login form, and starts a session for the user
+pub async fn http_login(
+    ctx: web::Data<AppState>,
+    form: web::Form<LoginForm>,
+) -> WebResult<HttpResponse> {
+    let form = form.into_inner();
+    let next = local_path(form.next.as_deref()).to_string();
+
+    let username = form.username.clone();
+    let found = ctx
+        .db
+        .read(move |conn| database::get_password_hash(conn, &username))
+        .await?;
+
+    let (user_id, hash) = match found {
+        Some((user_id, hash)) => (Some(user_id), hash),
+        None => (None, DUMMY_HASH.clone()),
+    };
+    let password = form.password;
+    let verified =
+        web::block(move || Ok::<_, failure::Error>(verify_password(&hash, &password))).await?;
+
+    let user_id = match user_id {
+        Some(user_id) if verified => user_id,
+        _ => {
+            warn!("Failed login for {}", form.username);
+            return Ok(redirect(&login_page_url(&next, true)));
+        }
+    };
+
+    let token = hex::encode(rand::random::<[u8; 32]>());
+    let token_hash = hash_token(&token);
+    ctx.db
+        .write(move |conn| {
+            database::delete_expired_sessions(conn)?;
+            database::save_session(conn, &token_hash, user_id, database::now() + SESSION_TTL)
+        })
+        .await?;
+
+    info!("{} logged in", form.username);
+    Ok(HttpResponse::SeeOther()
+        .header(http::header::LOCATION, next)
+        .header(
+            http::header::SET_COOKIE,
+            session_cookie(&token, SESSION_TTL, ctx.config.secure_cookies),
+        )
+        .finish())
+}
+
+/// Ends the session of the request, if any
+pub async fn http_logout(ctx: web::Data<AppState>, req: HttpRequest) -> WebResult<HttpResponse> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let token_hash = hash_token(cookie.value());
+        ctx.db
+            .write(move |conn| database::delete_session(conn, &token_hash))
+            .await?;
+    }
+
+    Ok(HttpResponse::SeeOther()
+        .header(http::header::LOCATION, "/login")
+        .header(
+            http::header::SET_COOKIE,
+            session_cookie("", 0, ctx.config.secure_cookies),
+        )
+        .finish())
+}
+
+/// Responds with the user logged in
+pub async fn http_get_me(req: HttpRequest) -> WebResult<web::Json<User>> {
+    match req.extensions().get::<User>() {
+        Some(user) => Ok(web::Json(user.clone())),
+        None => Err(Rejection::Unauthorized("Not logged in").into()),
+    }
+}
+
+/// What a request needs to be let through
+#[derive(Debug, PartialEq)]
+enum Access {
+    Public,
+    /// API calls and stored files, turned away without a session
+    Api {
+        /// Whether an API token can be used instead of a session
+        tokens: bool,
+    },
+    /// Pages of the app, sent to the login page without a session
+    Page,
+}
+
+fn access(path: &str) -> Access {
+    let under = |prefix: &str| path == prefix || path.starts_with(&format!("{}/", prefix));
+    let token_api = [
+        "/api/notes",
+        "/api/tags",
+        "/api/tag-actions",
+        "/api/images",
+        "/api/files",
+    ];
+    if token_api.iter().any(|prefix| under(prefix)) {
+        Access::Api { tokens: true }
+    } else if under("/api") || under("/res") {
+        Access::Api { tokens: false }
+    } else if path == "/" || under("/app") {
+        Access::Page
+    } else {
+        Access::Public
+    }
+}
+
+/// The token of an `Authorization: Bearer` header, if the request has one
+fn bearer_token(req: &ServiceRequest) -> Option<Result<String, Rejection>> {
+    let header = req.headers().get(http::header::AUTHORIZATION)?;
+    let token = header
+        .to_str()
+        .ok()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(|t| t.trim().to_string());
+    Some(token.ok_or(Rejection::Unauthorized("Expected a Bearer token")))
+}
+
+/// Finds the user of an API token, and checks the token may be used for the request
+async fn token_user(db: &DbPool, token: &str, method: &http::Method) -> WebResult<User> {
+    let token_hash = hash_token(token);
+    let found = db
+        .read(move |conn| database::get_token_user(conn, &token_hash))
+        .await?;
+    let (user, token): (User, ApiToken) = match found {
+        Some(found) => found,
+        None => return Err(Rejection::Unauthorized("Invalid API token").into()),
+    };
+
+    let reading = method == http::Method::GET || method == http::Method::HEAD;
+    if token.scope == TokenScope::Read && !reading {
+        return Err(Rejection::Forbidden("The API token is read-only").into());
+    }
+
+    let now = database::now();
+    if token.last_used_at.unwrap_or(0) + TOKEN_USE_INTERVAL <= now {
+        db.write(move |conn| database::set_api_token_used(conn, token.id))
+            .await?;
+    }
+
+    Ok(user)
+}
+
+/// Lets requests through only with a valid session or API token, see `access`.
+/// The user logged in is added to the extensions of the request.
+pub struct RequireLogin {
+    db: DbPool,
+}
+
+impl RequireLogin {
+    pub fn new(db: DbPool) -> Self {
+        Self { db }
+    }
+}
+
+impl<S, B> Transform<S> for RequireLogin
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+        + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type InitError = ();
+    type Transform = RequireLoginMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequireLoginMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            db: self.db.clone(),
+        })
+    }
+}
+
+pub struct RequireLoginMiddleware<S> {
+    service: Rc<RefCell<S>>,
+    db: DbPool,
+}
+
+impl<S, B> Service for RequireLoginMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>
+        + 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let access = access(req.path());
+        if let Access::Public = access {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+
+        let service = self.service.clone();
+        let db = self.db.clone();
+        let bearer = match access {
+            Access::Api { tokens } => bearer_token(&req).map(|token| (tokens, token)),
+            _ => None,
+        };
+        let token_hash = req.cookie(SESSION_COOKIE).map(|c| hash_token(c.value()));
+        Box::pin(async move {
+            if let Some((tokens, token)) = bearer {
+                if !tokens {
+                    return Err(Rejection::Forbidden("API tokens can't be used here").into());
+                }
+
+                let user = token_user(&db, &token?, req.method()).await?;
+                req.extensions_mut().insert(user);
+                let response = service.borrow_mut().call(req);
+                return response.await;
+            }
+
+            let user = match token_hash {
+                Some(token_hash) => {
+                    db.read(move |conn| database::get_session_user(conn, &token_hash))
+                        .await?
+                }
+                None => None,
+            };
+
+            match (user, access) {
+                (Some(user), _) => {
+                    req.extensions_mut().insert(user);
+                    let response = service.borrow_mut().call(req);
+                    response.await
+                }
+                (None, Access::Page) => {
+                    let next = req.uri().to_string();
+                    Err(redirect(&login_page_url(&next, false)).into())
+                }
+                (None, _) => Err(Rejection::Unauthorized("Not logged in").into()),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{app_state, TempDir};
+    use actix_web::http::header::{COOKIE, LOCATION, SET_COOKIE};
+    use actix_web::http::StatusCode;
+    use actix_web::{test, App};
+
+    const PASSWORD: &str = "correct horse";
+
+    async fn add_user(ctx: &web::Data<AppState>) -> i64 {
+        ctx.db
+            .write(|conn| {
+                let user_id = database::create_user(conn, "ann", &hash_password(PASSWORD)?)?;
+                Ok(user_id.unwrap())
+            })
+            .await
+            .unwrap()
+    }
+
+    fn login_form(password: &str, next: &str) -> web::Form<LoginForm> {
+        web::Form(LoginForm {
+            username: String::from("ann"),
+            password: password.to_string(),
+            next: Some(next.to_string()),
+        })
+    }
+
+    fn header(response: &HttpResponse, name: http::header::HeaderName) -> Option<&str> {
+        response.headers().get(name).and_then(|v| v.to_str().ok())
+    }
+
+    async fn let_through() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    /// Sends `req` to an app guarded by `RequireLogin` that answers everything let through
+    /// with 200, and returns the status and location of the response
+    async fn guarded(ctx: &web::Data<AppState>, req: test::TestRequest) -> (StatusCode, String) {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequireLogin::new(ctx.db.clone()))
+                .route("/{tail:.*}", web::to(let_through)),
+        )
+        .await;
+
+        let (status, headers) = match app.call(req.to_request()).await {
+            Ok(response) => (response.status(), response.headers().clone()),
+            Err(e) => {
+                let response = e.as_response_error().error_response();
+                (response.status(), response.headers().clone())
+            }
+        };
+        let location = headers
+            .get(LOCATION)
+            .map(|v| v.to_str().unwrap().to_string());
+        (status, location.unwrap_or_default())
+    }
+
+    #[test]
+    fn follows_only_local_paths_after_login() {
+        for path in &["/", "/app/tags?tag=a%2Fb", "/app/notes#1"] {
+            assert_eq!(local_path(Some(path)), *path);
+        }
+
+        let elsewhere = [
+            "",
+            "app",
+            "//evil.com",
+            "/\\evil.com",
+            "/\t/evil.com",
+            "/\n/evil.com",
+            "https://evil.com",
+            "javascript:alert(1)",
+            "/app/\\..",
+        ];
+        for path in &elsewhere {
+            assert_eq!(local_path(Some(path)), "/", "{:?}", path);
+        }
+        assert_eq!(local_path(None), "/");
+    }
+
+    #[actix_rt::test]
+    async fn logging_in_starts_a_session() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let user_id = add_user(&ctx).await;
+
+        let response = http_login(ctx.clone(), login_form(PASSWORD, "/app/tags"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SEE_OTHER);
+        assert_eq!(header(&response, LOCATION), Some("/app/tags"));
+
+        let cookie = header(&response, SET_COOKIE).unwrap();
+        assert!(cookie.contains("; HttpOnly"), "{}", cookie);
+        let token = cookie
+            .strip_prefix("lenote_session=")
+            .and_then(|c| c.split(';').next())
+            .unwrap();
+        let token_hash = hash_token(token);
+        let user = ctx
+            .db
+            .read(move |conn| database::get_session_user(conn, &token_hash))
+            .await
+            .unwrap();
+        assert_eq!(user.unwrap().id, user_id);
+
+        let session = test::TestRequest::get()
+            .uri("/app/tags")
+            .header(COOKIE, format!("lenote_session={}", token));
+        assert_eq!(guarded(&ctx, session).await.0, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn failed_logins_go_back_to_the_login_page() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        add_user(&ctx).await;
+
+        for form in [
+            login_form("wrong horse", "/app/tags"),
+            web::Form(LoginForm {
+                username: String::from("bob"),
+                password: PASSWORD.to_string(),
+                next: Some(String::from("/\\evil.com")),
+            }),
+        ] {
+            let next = local_path(form.next.as_deref()).to_string();
+            let response = http_login(ctx.clone(), form).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SEE_OTHER);
+            assert_eq!(
+                header(&response, LOCATION),
+                Some(login_page_url(&next, true).as_str())
+            );
+            assert_eq!(header(&response, SET_COOKIE), None);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn requests_without_a_session_are_turned_away() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+
+        let (status, _) = guarded(&ctx, test::TestRequest::get().uri("/api/notes")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, _) = guarded(
+            &ctx,
+            test::TestRequest::get().uri("/res/images/ab/abcd.png"),
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, location) =
+            guarded(&ctx, test::TestRequest::get().uri("/app/tags?tag=a")).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(location, "/login?next=%2Fapp%2Ftags%3Ftag%3Da");
+
+        let expired = test::TestRequest::get()
+            .uri("/")
+            .header(COOKIE, "lenote_session=unknown");
+        let (status, location) = guarded(&ctx, expired).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(location, "/login?next=%2F");
+
+        let (status, _) = guarded(&ctx, test::TestRequest::get().uri("/login")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn tokens_only_reach_the_notes_api() {
+        let token_api = [
+            "/api/notes",
+            "/api/notes/3",
+            "/api/tags/work/map",
+            "/api/tag-actions/rename/work",
+            "/api/images",
+            "/api/files",
+        ];
+        for path in &token_api {
+            assert_eq!(access(path), Access::Api { tokens: true }, "{}", path);
+        }
+
+        let session_api = [
+            "/api/tokens",
+            "/api/me",
+            "/api/export",
+            "/api/notesx",
+            "/res/images/ab/abcd.png",
+        ];
+        for path in &session_api {
+            assert_eq!(access(path), Access::Api { tokens: false }, "{}", path);
+        }
+
+        assert_eq!(access("/"), Access::Page);
+        assert_eq!(access("/app/tags"), Access::Page);
+        assert_eq!(access("/login"), Access::Public);
+    }
+
+    async fn add_token(ctx: &web::Data<AppState>, user_id: i64, scope: TokenScope) -> String {
+        let secret = new_api_token();
+        let token_hash = hash_token(&secret);
+        ctx.db
+            .write(move |conn| database::create_api_token(conn, user_id, "cli", &token_hash, scope))
+            .await
+            .unwrap();
+        secret
+    }
+
+    fn bearer(req: test::TestRequest, token: &str) -> test::TestRequest {
+        req.header(http::header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    #[actix_rt::test]
+    async fn read_only_tokens_can_only_read() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let user_id = add_user(&ctx).await;
+        let read = add_token(&ctx, user_id, TokenScope::Read).await;
+        let write = add_token(&ctx, user_id, TokenScope::Write).await;
+
+        let get = test::TestRequest::get().uri("/api/notes");
+        assert_eq!(guarded(&ctx, bearer(get, &read)).await.0, StatusCode::OK);
+        let changes = [
+            (http::Method::POST, "/api/notes"),
+            (http::Method::PUT, "/api/notes/1"),
+            (http::Method::DELETE, "/api/notes/1"),
+        ];
+        for (method, path) in changes.iter() {
+            let req = || test::TestRequest::with_uri(path).method(method.clone());
+            let (status, _) = guarded(&ctx, bearer(req(), &read)).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, path);
+            let (status, _) = guarded(&ctx, bearer(req(), &write)).await;
+            assert_eq!(status, StatusCode::OK, "{} {}", method, path);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn unknown_and_deleted_tokens_are_turned_away() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let user_id = add_user(&ctx).await;
+        let token = add_token(&ctx, user_id, TokenScope::Write).await;
+        let notes = || test::TestRequest::get().uri("/api/notes");
+
+        let unknown = bearer(notes(), &new_api_token());
+        assert_eq!(guarded(&ctx, unknown).await.0, StatusCode::UNAUTHORIZED);
+        let malformed = notes().header(http::header::AUTHORIZATION, "Basic abc");
+        assert_eq!(guarded(&ctx, malformed).await.0, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(
+            guarded(&ctx, bearer(notes(), &token)).await.0,
+            StatusCode::OK
+        );
+        ctx.db
+            .write(move |conn| database::delete_api_token(conn, user_id, 1))
+            .await
+            .unwrap();
+        let deleted = bearer(notes(), &token);
+        assert_eq!(guarded(&ctx, deleted).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_rt::test]
+    async fn tokens_cant_manage_tokens_or_export() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let user_id = add_user(&ctx).await;
+        let token = add_token(&ctx, user_id, TokenScope::Write).await;
+
+        let requests = [
+            (http::Method::GET, "/api/tokens"),
+            (http::Method::POST, "/api/tokens"),
+            (http::Method::DELETE, "/api/tokens/1"),
+            (http::Method::GET, "/api/export"),
+        ];
+        for (method, path) in requests.iter() {
+            let req = test::TestRequest::with_uri(path).method(method.clone());
+            let (status, _) = guarded(&ctx, bearer(req, &token)).await;
+            assert_eq!(status, StatusCode::FORBIDDEN, "{} {}", method, path);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn tokens_note_when_they_were_last_used() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let user_id = add_user(&ctx).await;
+        let token = add_token(&ctx, user_id, TokenScope::Read).await;
+        let last_used = || {
+            ctx.db
+                .read(move |conn| Ok(database::get_api_tokens(conn, user_id)?[0].last_used_at))
+        };
+
+        assert_eq!(last_used().await.unwrap(), None);
+        let req = bearer(test::TestRequest::get().uri("/api/tags"), &token);
+        assert_eq!(guarded(&ctx, req).await.0, StatusCode::OK);
+        let used_at = last_used().await.unwrap().unwrap();
+        assert!(database::now() - used_at < TOKEN_USE_INTERVAL);
+    }
+}
@@ -0,0 +1,536 @@
+//! Backup archives of a data directory.
+//!
+//! An archive is a `.tar.zst` holding a manifest, a snapshot of the database
+//! taken with SQLite's online backup API, and the files under `res/images` and `res/files`.
+//! The manifest records the schema version of the snapshot and a checksum
+//! of every file, which are checked before anything is restored.
+
+use crate::database;
+use crate::migrations;
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+const FORMAT_VERSION: u32 = 1;
+const MANIFEST_NAME: &str = "manifest.json";
+const DB_NAME: &str = "lenote.db";
+/// Directories of stored files, thumbnails are left out as they are made again
+const STORE_DIRS: &[&str] = &["res/images", "res/files"];
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format: u32,
+    pub created_at: i64,
+    /// Number of migrations applied to the database snapshot
+    pub schema_version: usize,
+    pub files: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// Path in the archive, which is also the path under the data directory
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String), failure::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+/// Lists the files under `dir` recursively, as `/` separated paths starting with `prefix`
+fn list_files(dir: &Path, prefix: &str, result: &mut Vec<String>) -> Result<(), failure::Error> {
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = format!("{}/{}", prefix, name);
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &path, result)?;
+        } else {
+            result.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether an archive path stays inside the directory it's extracted to
+fn is_safe_path(path: &str) -> bool {
+    !path.is_empty()
+        && Path::new(path)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut result = path.as_os_str().to_owned();
+    result.push(suffix);
+    PathBuf::from(result)
+}
+
+/// Writes a backup of the data directory `data` to `out`.
+/// The server can keep running while this is done.
+pub fn create(data: &Path, out: &Path) -> Result<Manifest, failure::Error> {
+    let db_path = data.join(DB_NAME);
+    if !db_path.exists() {
+        return Err(failure::format_err!(
+            "No database found at {}",
+            db_path.display()
+        ));
+    }
+
+    let snapshot = with_suffix(out, ".db-snapshot");
+    let partial = with_suffix(out, ".partial");
+    let result = write_archive(data, &db_path, &snapshot, &partial);
+    let _ = fs::remove_file(&snapshot);
+
+    match result {
+        Ok(manifest) => {
+            fs::rename(&partial, out)?;
+            Ok(manifest)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+fn write_archive(
+    data: &Path,
+    db_path: &Path,
+    snapshot: &Path,
+    out: &Path,
+) -> Result<Manifest, failure::Error> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    migrations::backup_db(&conn, snapshot)?;
+    drop(conn);
+
+    // The copy keeps the WAL mode of the database,
+    // which would leave `-wal` and `-shm` files next to it
+    let schema_version = {
+        let conn = Connection::open(snapshot)?;
+        conn.query_row("PRAGMA journal_mode = DELETE", NO_PARAMS, |_| Ok(()))?;
+        migrations::schema_version(&conn)?
+    };
+
+    let mut stored = vec![];
+    for dir in STORE_DIRS {
+        list_files(&data.join(dir), dir, &mut stored)?;
+    }
+    stored.sort();
+
+    let mut sources = vec![(DB_NAME.to_string(), snapshot.to_path_buf())];
+    sources.extend(stored.into_iter().map(|path| {
+        let source = data.join(&path);
+        (path, source)
+    }));
+
+    let mut files = vec![];
+    for (path, source) in &sources {
+        let (size, sha256) = hash_file(source)?;
+        files.push(ManifestFile {
+            path: path.clone(),
+            size,
+            sha256,
+        });
+    }
+
+    let manifest = Manifest {
+        format: FORMAT_VERSION,
+        created_at: database::now(),
+        schema_version,
+        files,
+    };
+
+    // The manifest goes first, so that a restore can turn down an archive
+    // it doesn't support before extracting anything else
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created_at as u64);
+    header.set_cksum();
+
+    let encoder = zstd::Encoder::new(File::create(out)?, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_data(&mut header, MANIFEST_NAME, manifest_json.as_slice())?;
+    for (path, source) in &sources {
+        builder.append_path_with_name(source, path)?;
+    }
+    builder.into_inner()?.finish()?;
+
+    Ok(manifest)
+}
+
+/// Checks that this server can restore an archive with this manifest
+fn check_manifest(manifest: &Manifest) -> Result<(), failure::Error> {
+    if manifest.format > FORMAT_VERSION {
+        return Err(failure::format_err!(
+            "The archive has format {}, which is newer than this server",
+            manifest.format
+        ));
+    }
+
+    if manifest.schema_version > migrations::latest_version() {
+        return Err(failure::format_err!(
+            "The archive has schema version {}, but this server only knows up to {}",
+            manifest.schema_version,
+            migrations::latest_version()
+        ));
+    }
+
+    if let Some(file) = manifest.files.iter().find(|f| !is_safe_path(&f.path)) {
+        return Err(failure::format_err!(
+            "The archive has an invalid path {}",
+            file.path
+        ));
+    }
+
+    if !manifest.files.iter().any(|f| f.path == DB_NAME) {
+        return Err(failure::format_err!("The archive has no database"));
+    }
+
+    Ok(())
+}
+
+/// Extracts the archive to `staging` and checks it against its manifest
+fn extract(archive: &Path, staging: &Path) -> Result<Manifest, failure::Error> {
+    let decoder = zstd::Decoder::new(File::open(archive)?)?;
+    let mut archive = tar::Archive::new(decoder);
+    let mut manifest: Option<Manifest> = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+
+        let path = entry.path()?.to_string_lossy().into_owned();
+
+        let listed = match &manifest {
+            Some(m) => m.files.iter().any(|f| f.path == path),
+            None => {
+                if path != MANIFEST_NAME {
+                    return Err(failure::format_err!("The archive has no manifest"));
+                }
+
+                let mut json = String::new();
+                entry.read_to_string(&mut json)?;
+                let m: Manifest = serde_json::from_str(&json)?;
+                check_manifest(&m)?;
+                manifest = Some(m);
+                continue;
+            }
+        };
+        if !listed || !entry.unpack_in(staging)? {
+            return Err(failure::format_err!(
+                "The archive has a file {} missing from its manifest",
+                path
+            ));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| failure::format_err!("The archive is empty"))?;
+
+    let mut extracted = vec![];
+    list_files(staging, "", &mut extracted)?;
+    let extracted: BTreeSet<&str> = extracted.iter().map(|p| &p[1..]).collect();
+    for file in &manifest.files {
+        if !extracted.contains(file.path.as_str()) {
+            return Err(failure::format_err!(
+                "The archive is missing the file {}",
+                file.path
+            ));
+        }
+
+        let (size, sha256) = hash_file(&staging.join(&file.path))?;
+        if size != file.size || sha256 != file.sha256 {
+            return Err(failure::format_err!(
+                "The file {} doesn't match the checksum in the manifest",
+                file.path
+            ));
+        }
+    }
+
+    let conn =
+        Connection::open_with_flags(staging.join(DB_NAME), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let schema_version = migrations::schema_version(&conn)?;
+    if schema_version != manifest.schema_version {
+        return Err(failure::format_err!(
+            "The database has schema version {}, but the manifest says {}",
+            schema_version,
+            manifest.schema_version
+        ));
+    }
+
+    Ok(manifest)
+}
+
+/// Restores the archive into the data directory `data`, which must not be in use by a server.
+/// An existing database is only replaced if `force` is set, and is then backed up first.
+/// Images and files already in the directory are kept.
+pub fn restore(archive: &Path, data: &Path, force: bool) -> Result<Manifest, failure::Error> {
+    let db_path = data.join(DB_NAME);
+    if db_path.exists() && !force {
+        return Err(failure::format_err!(
+            "A database already exists at {}, use --force to replace it",
+            db_path.display()
+        ));
+    }
+
+    let staging = data.join(format!(".restore-{}", database::now()));
+    fs::create_dir_all(&staging)?;
+    let result = extract(archive, &staging).and_then(|manifest| {
+        if db_path.exists() {
+            let conn = Connection::open(&db_path)?;
+            let backup_path = migrations::backup_dir(&db_path)
+                .join(format!("lenote-before-restore-{}.db", database::now()));
+            info!("Backing up the database to {}", backup_path.display());
+            migrations::backup_db(&conn, &backup_path)?;
+            drop(conn);
+
+            for suffix in &["-wal", "-shm"] {
+                let _ = fs::remove_file(with_suffix(&db_path, suffix));
+            }
+        }
+
+        for file in &manifest.files {
+            let target = data.join(&file.path);
+            if let Some(dir) = target.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            fs::rename(staging.join(&file.path), target)?;
+        }
+
+        Ok(manifest)
+    });
+    let _ = fs::remove_dir_all(&staging);
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A data directory with one note and one stored image
+    fn data_dir() -> TempDir {
+        let dir = TempDir::new();
+        let mut conn = Connection::open(dir.path().join(DB_NAME)).unwrap();
+        migrations::migrate(&mut conn, &dir.path().join("backups")).unwrap();
+        conn.execute(
+            "INSERT INTO notes(text, timestamp, note_type) VALUES('kept', 1, 0)",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+        fs::create_dir_all(dir.path().join("res/images/ab")).unwrap();
+        fs::write(dir.path().join("res/images/ab/cd.png"), b"image").unwrap();
+        dir
+    }
+
+    fn note_texts(db_path: &Path) -> Vec<String> {
+        let conn = Connection::open(db_path).unwrap();
+        let mut stmt = conn.prepare("SELECT text FROM notes").unwrap();
+        let texts = stmt
+            .query_map(NO_PARAMS, |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        texts
+    }
+
+    /// Writes an archive with `manifest` followed by `files`, without checking either
+    fn write_raw(out: &Path, manifest: &Manifest, files: &[(&str, &[u8])]) {
+        let encoder = zstd::Encoder::new(File::create(out).unwrap(), 0).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        let manifest_json = serde_json::to_vec(manifest).unwrap();
+        let entries = std::iter::once((MANIFEST_NAME, manifest_json.as_slice()));
+        for (path, data) in entries.chain(files.iter().copied()) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    fn manifest_for(files: &[(&str, &[u8])], schema_version: usize) -> Manifest {
+        Manifest {
+            format: FORMAT_VERSION,
+            created_at: 1,
+            schema_version,
+            files: files
+                .iter()
+                .map(|(path, data)| ManifestFile {
+                    path: path.to_string(),
+                    size: data.len() as u64,
+                    sha256: hex::encode(Sha256::digest(data)),
+                })
+                .collect(),
+        }
+    }
+
+    /// The bytes of a database at the latest schema version
+    fn db_bytes(dir: &TempDir) -> Vec<u8> {
+        let path = dir.path().join("source.db");
+        let mut conn = Connection::open(&path).unwrap();
+        migrations::migrate(&mut conn, &dir.path().join("backups")).unwrap();
+        drop(conn);
+        fs::read(&path).unwrap()
+    }
+
+    #[test]
+    fn restores_a_backup() {
+        let data = data_dir();
+        let out = TempDir::new();
+        let archive = out.path().join("backup.tar.zst");
+        let manifest = create(data.path(), &archive).unwrap();
+
+        assert_eq!(manifest.schema_version, migrations::latest_version());
+        let paths: Vec<_> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec![DB_NAME, "res/images/ab/cd.png"]);
+        assert!(!with_suffix(&archive, ".partial").exists());
+        assert!(!with_suffix(&archive, ".db-snapshot").exists());
+
+        let target = TempDir::new();
+        restore(&archive, target.path(), false).unwrap();
+        assert_eq!(note_texts(&target.path().join(DB_NAME)), vec!["kept"]);
+        assert_eq!(
+            fs::read(target.path().join("res/images/ab/cd.png")).unwrap(),
+            b"image"
+        );
+        // Only the restored files are left behind
+        let mut left = vec![];
+        list_files(target.path(), "", &mut left).unwrap();
+        assert_eq!(left.len(), 2, "{:?}", left);
+    }
+
+    #[test]
+    fn replaces_a_database_only_when_forced() {
+        let data = data_dir();
+        let out = TempDir::new();
+        let archive = out.path().join("backup.tar.zst");
+        create(data.path(), &archive).unwrap();
+
+        let target = data_dir();
+        let db_path = target.path().join(DB_NAME);
+        Connection::open(&db_path)
+            .unwrap()
+            .execute("UPDATE notes SET text = 'replaced'", NO_PARAMS)
+            .unwrap();
+
+        let e = restore(&archive, target.path(), false).unwrap_err();
+        assert!(e.to_string().contains("--force"), "{}", e);
+        assert_eq!(note_texts(&db_path), vec!["replaced"]);
+
+        restore(&archive, target.path(), true).unwrap();
+        assert_eq!(note_texts(&db_path), vec!["kept"]);
+
+        // The replaced database was backed up first
+        let backups: Vec<_> = fs::read_dir(migrations::backup_dir(&db_path))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.to_string_lossy().contains("before-restore"))
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(note_texts(&backups[0]), vec!["replaced"]);
+    }
+
+    #[test]
+    fn refuses_manifests_it_cannot_restore() {
+        let db: &[u8] = b"db";
+        let check = |edit: &dyn Fn(&mut Manifest)| {
+            let mut manifest = manifest_for(&[(DB_NAME, db)], 1);
+            edit(&mut manifest);
+            check_manifest(&manifest).unwrap_err().to_string()
+        };
+
+        assert!(check(&|m| m.format = FORMAT_VERSION + 1).contains("format"));
+        assert!(
+            check(&|m| m.schema_version = migrations::latest_version() + 1)
+                .contains("schema version")
+        );
+        for path in &["../lenote.db", "/etc/passwd", "res/../../x", ""] {
+            let e = check(&|m| m.files[0].path = path.to_string());
+            assert!(e.contains("invalid path"), "{}: {}", path, e);
+        }
+        assert!(check(&|m| m.files.clear()).contains("no database"));
+
+        assert!(check_manifest(&manifest_for(&[(DB_NAME, db)], 1)).is_ok());
+    }
+
+    #[test]
+    fn refuses_archives_that_do_not_match_their_manifest() {
+        let dir = TempDir::new();
+        let db = db_bytes(&dir);
+        let latest = migrations::latest_version();
+        let restore_raw = |manifest: &Manifest, files: &[(&str, &[u8])]| {
+            let archive = dir.path().join("raw.tar.zst");
+            write_raw(&archive, manifest, files);
+            let target = TempDir::new();
+            let result = restore(&archive, target.path(), false);
+            // Nothing is restored from an invalid archive
+            if result.is_err() {
+                assert!(!target.path().join(DB_NAME).exists());
+                assert_eq!(fs::read_dir(target.path()).unwrap().count(), 0);
+            }
+            result.map(|_| ()).map_err(|e| e.to_string())
+        };
+
+        let files: &[(&str, &[u8])] = &[(DB_NAME, &db), ("res/files/a.txt", b"a")];
+        assert_eq!(restore_raw(&manifest_for(files, latest), files), Ok(()));
+
+        // A file changed after the manifest was written
+        let changed: &[(&str, &[u8])] = &[(DB_NAME, &db), ("res/files/a.txt", b"b")];
+        let e = restore_raw(&manifest_for(files, latest), changed).unwrap_err();
+        assert!(e.contains("checksum"), "{}", e);
+
+        // A file left out of the archive
+        let e = restore_raw(&manifest_for(files, latest), &files[..1]).unwrap_err();
+        assert!(e.contains("missing the file"), "{}", e);
+
+        // A file left out of the manifest
+        let e = restore_raw(&manifest_for(&files[..1], latest), files).unwrap_err();
+        assert!(e.contains("missing from its manifest"), "{}", e);
+
+        // A database at another schema version than the manifest says
+        let e = restore_raw(&manifest_for(files, latest - 1), files).unwrap_err();
+        assert!(e.contains("schema version"), "{}", e);
+    }
+
+    #[test]
+    fn refuses_archives_without_a_manifest_first() {
+        let dir = TempDir::new();
+        let archive = dir.path().join("raw.tar.zst");
+        let encoder = zstd::Encoder::new(File::create(&archive).unwrap(), 0).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        let mut header = tar::Header::new_gnu();
+        header.set_size(2);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, DB_NAME, &b"db"[..])
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let target = TempDir::new();
+        let e = restore(&archive, target.path(), false).unwrap_err();
+        assert!(e.to_string().contains("no manifest"), "{}", e);
+        assert!(!target.path().join(DB_NAME).exists());
+    }
+}
@@ -0,0 +1,285 @@
+//! Maintenance subcommands, run instead of the server
+
+use crate::auth;
+use crate::backup;
+use crate::database;
+use crate::db_pool;
+use crate::export::{self, DirSink};
+use crate::import;
+use crate::migrations::{self, MigrationState};
+use clap::ArgMatches;
+use rusqlite::{Connection, OpenFlags};
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+pub fn run(name: &str, matches: &ArgMatches) -> Result<(), failure::Error> {
+    match name {
+        "migrate" => migrate(matches),
+        "backup" => backup(matches),
+        "restore" => restore(matches),
+        "export" => export(matches),
+        "import" => import(matches),
+        "user" => match matches.subcommand() {
+            ("add", Some(matches)) => add_user(matches),
+            ("passwd", Some(matches)) => change_password(matches),
+            ("remove", Some(matches)) => remove_user(matches),
+            ("list", Some(matches)) => list_users(matches),
+            (name, _) => Err(failure::format_err!("Unknown user command {}", name)),
+        },
+        _ => Err(failure::format_err!("Unknown command {}", name)),
+    }
+}
+
+fn data_dir(matches: &ArgMatches) -> PathBuf {
+    PathBuf::from(matches.value_of("data").expect("Missing DB parameter"))
+}
+
+fn db_path(matches: &ArgMatches) -> PathBuf {
+    data_dir(matches).join("lenote.db")
+}
+
+fn migrate(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let db_path = db_path(matches);
+    if !db_path.exists() {
+        return Err(failure::format_err!(
+            "No database found at {}",
+            db_path.display()
+        ));
+    }
+
+    if matches.is_present("status") {
+        let conn = Connection::open_with_flags(&db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        for (version, m, state) in migrations::get_status(&conn)? {
+            let state = match state {
+                MigrationState::Applied(Some(applied_at)) => format!("applied at {}", applied_at),
+                MigrationState::Applied(None) => String::from("applied"),
+                MigrationState::Pending => String::from("pending"),
+                MigrationState::Changed => String::from("CHANGED since it was applied"),
+            };
+            println!("{:>4}  {:<36} {}", version, m.name, state);
+        }
+
+        return Ok(());
+    }
+
+    let mut conn = Connection::open(&db_path)?;
+    if matches.is_present("dry-run") {
+        let pending = migrations::dry_run(&mut conn)?;
+        if pending.is_empty() {
+            println!("The database is up to date");
+        }
+        for (version, m) in pending {
+            println!("Would apply migration {} ({})", version, m.name);
+        }
+
+        return Ok(());
+    }
+
+    migrations::migrate(&mut conn, &migrations::backup_dir(&db_path))?;
+    println!("The database is up to date");
+    Ok(())
+}
+
+fn backup(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let out = Path::new(matches.value_of("out").expect("Missing out parameter"));
+    let manifest = backup::create(&data_dir(matches), out)?;
+    println!(
+        "Backed up schema version {} with {} files to {}",
+        manifest.schema_version,
+        manifest.files.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+fn restore(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let archive = Path::new(
+        matches
+            .value_of("archive")
+            .expect("Missing archive parameter"),
+    );
+    let manifest = backup::restore(archive, &data_dir(matches), matches.is_present("force"))?;
+    println!(
+        "Restored schema version {} with {} files from {}",
+        manifest.schema_version,
+        manifest.files.len(),
+        archive.display()
+    );
+    if manifest.schema_version < migrations::latest_version() {
+        println!("The database will be upgraded when the server starts");
+    }
+    Ok(())
+}
+
+/// Opens the database for a command that needs its schema to be up to date
+fn open_current_db(matches: &ArgMatches, read_only: bool) -> Result<Connection, failure::Error> {
+    let db_path = db_path(matches);
+    if !db_path.exists() {
+        return Err(failure::format_err!(
+            "No database found at {}",
+            db_path.display()
+        ));
+    }
+
+    let conn = if read_only {
+        db_pool::open_read_only(&db_path)?
+    } else {
+        db_pool::open_connection(&db_path)?
+    };
+
+    if migrations::schema_version(&conn)? != migrations::latest_version() {
+        return Err(failure::format_err!(
+            "The database schema is not up to date, run the migrate command first"
+        ));
+    }
+
+    Ok(conn)
+}
+
+fn export(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let out = PathBuf::from(matches.value_of("out").expect("Missing out parameter"));
+    if out.exists() && out.read_dir()?.next().is_some() {
+        return Err(failure::format_err!("{} is not empty", out.display()));
+    }
+
+    let mut conn = open_current_db(matches, true)?;
+    let export = export::collect(&mut conn)?;
+    let grouping = matches.value_of("by").unwrap_or("day").parse()?;
+    export::write(
+        &export,
+        &data_dir(matches),
+        grouping,
+        &mut DirSink(out.clone()),
+    )?;
+    println!(
+        "Exported {} notes and {} tags to {}",
+        export.notes.len(),
+        export.tags.len(),
+        out.display()
+    );
+    Ok(())
+}
+
+fn import(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let source = Path::new(
+        matches
+            .value_of("source")
+            .expect("Missing source parameter"),
+    );
+    if !source.is_dir() {
+        return Err(failure::format_err!(
+            "{} is not a directory",
+            source.display()
+        ));
+    }
+
+    let dry_run = matches.is_present("dry-run");
+    let mut conn = open_current_db(matches, false)?;
+    let report = import::import(
+        &mut conn,
+        &data_dir(matches),
+        source,
+        &crate::get_tag_colors(matches),
+        dry_run,
+    )?;
+
+    for skipped in &report.skipped {
+        println!("Skipped {}", skipped);
+    }
+    for tag in &report.new_tags {
+        println!("New tag {}", tag);
+    }
+    println!(
+        "{} {} notes ({} in the trash), {} revisions, {} images, {} files and {} new tags",
+        if dry_run { "Would import" } else { "Imported" },
+        report.notes,
+        report.trashed_notes,
+        report.revisions,
+        report.images,
+        report.files,
+        report.new_tags.len()
+    );
+    Ok(())
+}
+
+fn username(matches: &ArgMatches) -> String {
+    matches
+        .value_of("username")
+        .expect("Missing username parameter")
+        .to_string()
+}
+
+/// Reads a new password from stdin, or asks for it twice on the terminal
+fn read_password(matches: &ArgMatches) -> Result<String, failure::Error> {
+    let password = if matches.is_present("password-stdin") {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        line.trim_end_matches(&['\r', '\n'][..]).to_string()
+    } else {
+        let password = rpassword::prompt_password("Password: ")?;
+        if rpassword::prompt_password("Password again: ")? != password {
+            return Err(failure::format_err!("The passwords don't match"));
+        }
+        password
+    };
+
+    if password.chars().count() < auth::MIN_PASSWORD_LENGTH {
+        return Err(failure::format_err!(
+            "The password must have at least {} characters",
+            auth::MIN_PASSWORD_LENGTH
+        ));
+    }
+
+    Ok(password)
+}
+
+fn add_user(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let username = username(matches);
+    if username.trim().is_empty() || username.trim() != username {
+        return Err(failure::format_err!(
+            "The username can't be empty or start or end with spaces"
+        ));
+    }
+
+    let conn = open_current_db(matches, false)?;
+    let password_hash = auth::hash_password(&read_password(matches)?)?;
+    match database::create_user(&mut &conn, &username, &password_hash)? {
+        Some(_) => println!("Added {}", username),
+        None => return Err(failure::format_err!("{} already exists", username)),
+    }
+
+    Ok(())
+}
+
+fn change_password(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let username = username(matches);
+    let conn = open_current_db(matches, false)?;
+    if database::get_password_hash(&mut &conn, &username)?.is_none() {
+        return Err(failure::format_err!("There is no user {}", username));
+    }
+
+    let password_hash = auth::hash_password(&read_password(matches)?)?;
+    database::set_password_hash(&mut &conn, &username, &password_hash)?;
+    println!("Changed the password of {}", username);
+    Ok(())
+}
+
+fn remove_user(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let username = username(matches);
+    let conn = open_current_db(matches, false)?;
+    if !database::delete_user(&mut &conn, &username)? {
+        return Err(failure::format_err!("There is no user {}", username));
+    }
+
+    println!("Removed {}", username);
+    Ok(())
+}
+
+fn list_users(matches: &ArgMatches) -> Result<(), failure::Error> {
+    let conn = open_current_db(matches, true)?;
+    for user in database::get_users(&mut &conn)? {
+        println!("{}", user.username);
+    }
+
+    Ok(())
+}
@@ -1,137 +1,99 @@
+use crate::paging::Cursor;
 use lenote_common::models::*;
-use rusqlite::{params, NO_PARAMS};
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::ops::Deref;
 use std::rc::Rc;
 use std::time::SystemTime;
 
-const EVOLUTIONS: [&'static str; 5] = [
-    // Version 1
-    "CREATE TABLE notes(
-        id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-        text VARCHAR NOT NULL,
-        timestamp BIGINT
-    )",
-    // Version 2
-    "CREATE TABLE tags(
-        tag VARCHAR NOT NULL PRIMARY KEY,
-        color VARCHAR NOT NULL
-    )",
-    // Version 3
-    "CREATE TABLE tag_map(
-        tag VARCHAR NOT NULL,
-        note_id BIGINT NOT NULL,
-        status INT NOT NULL,
-        PRIMARY KEY (tag, note_id),
-        FOREIGN KEY(note_id) REFERENCES notes(id),
-        FOREIGN KEY(tag) REFERENCES tags(tag)
-    )",
-    // Version 4
-    "CREATE TABLE tag_map_history(
-        id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
-        tag VARCHAR NOT NULL,
-        note_id BIGINT NOT NULL,
-        status INT NOT NULL,
-        timestamp BIGINT,
-        FOREIGN KEY(note_id) REFERENCES notes(id),
-        FOREIGN KEY(tag) REFERENCES tags(tag)
-    )",
-    // Version 5
-    "ALTER TABLE notes ADD COLUMN note_type INTEGER NOT NULL DEFAULT 0",
-];
-
-fn now() -> i64 {
+pub fn now() -> i64 {
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64
 }
 
-#[derive(Debug, failure::Fail)]
-#[fail(display = "DB Error: {}", message)]
-struct DBError {
-    message: String,
+pub fn tx(conn: &mut rusqlite::Connection) -> Result<rusqlite::Transaction<'_>, failure::Error> {
+    Ok(conn.transaction()?)
+}
+
+pub fn commit(tx: rusqlite::Transaction) -> Result<(), failure::Error> {
+    Ok(tx.commit()?)
 }
 
-fn init_version_table(conn: &mut rusqlite::Connection) -> Result<(), failure::Error> {
-    conn.execute("CREATE TABLE IF NOT EXISTS db_version(v INT)", NO_PARAMS)?;
+pub fn save_note<Conn: Deref<Target = rusqlite::Connection>>(
+    note: &Note,
+    conn: &mut Conn,
+) -> Result<i64, failure::Error> {
     conn.execute(
-        "INSERT INTO db_version(v) SELECT 0 WHERE NOT EXISTS(SELECT 1 FROM db_version)",
-        NO_PARAMS,
+        "INSERT INTO notes(text, timestamp, note_type) VALUES(?1, ?2, ?3)",
+        params![&note.text, &note.timestamp, note.note_type as i32],
     )?;
 
-    return Ok(());
-}
-
-fn get_db_version(conn: &mut rusqlite::Connection) -> Result<usize, failure::Error> {
-    let version: i32 = conn.query_row("SELECT v FROM db_version", NO_PARAMS, |row| row.get(0))?;
-    return Ok(version.try_into().unwrap());
+    Ok(conn.last_insert_rowid())
 }
 
-fn evolve(
-    conn: &mut rusqlite::Connection,
-    query: &str,
-    version: usize,
+pub fn update_note_text<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+    text: &str,
 ) -> Result<(), failure::Error> {
-    let tx = conn.transaction()?;
-    let version: i32 = version.try_into().unwrap();
-
-    tx.execute(query, NO_PARAMS)?;
-    let affected = tx.execute(
-        "UPDATE db_version SET v = ?1 WHERE v = ?2",
-        params![version, version - 1],
+    conn.execute(
+        "UPDATE notes SET text = ?1 WHERE id = ?2",
+        params![text, &note_id],
     )?;
 
-    if affected != 1 {
-        return Err(DBError {
-            message: format!("Failed to evolve database to version {}", version),
-        }
-        .into());
-    }
-
-    tx.commit()?;
-
-    println!("Evolved database to version {}", version);
-    return Ok(());
-}
-
-fn evolve_all_versions(conn: &mut rusqlite::Connection) -> Result<(), failure::Error> {
-    let db_version: usize = get_db_version(conn)?;
-    let max_version = EVOLUTIONS.len();
-
-    for v in db_version..max_version {
-        evolve(conn, EVOLUTIONS[v], v + 1)?;
-    }
-
-    return Ok(());
+    Ok(())
 }
 
-pub fn init(conn: &mut rusqlite::Connection) -> Result<(), failure::Error> {
-    init_version_table(conn)?;
-    evolve_all_versions(conn)?;
-    rusqlite::vtab::array::load_module(conn)?;
-    return Ok(());
+/// Keeps the current text of a note as a revision before it gets replaced
+pub fn save_note_revision<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+    text: &str,
+) -> Result<(), failure::Error> {
+    save_note_revision_at(conn, note_id, text, now())
 }
 
-pub fn tx(conn: &mut rusqlite::Connection) -> Result<rusqlite::Transaction, failure::Error> {
-    Ok(conn.transaction()?)
-}
+pub fn save_note_revision_at<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+    text: &str,
+    timestamp: i64,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "INSERT INTO note_revisions(note_id, text, timestamp) VALUES(?1, ?2, ?3)",
+        params![&note_id, text, &timestamp],
+    )?;
 
-pub fn commit(tx: rusqlite::Transaction) -> Result<(), failure::Error> {
-    Ok(tx.commit()?)
+    Ok(())
 }
 
-pub fn save_note<Conn: Deref<Target = rusqlite::Connection>>(
-    note: &Note,
+pub fn get_note_revisions<Conn: Deref<Target = rusqlite::Connection>>(
     conn: &mut Conn,
-) -> Result<i64, failure::Error> {
-    conn.execute(
-        "INSERT INTO notes(text, timestamp, note_type) VALUES(?1, ?2, ?3)",
-        params![&note.text, &note.timestamp, note.note_type as i32],
+    note_id: i64,
+) -> Result<Vec<NoteRevision>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, note_id, text, timestamp FROM note_revisions
+        WHERE note_id = ?1
+        ORDER BY id ASC",
     )?;
+    let iter = stmt.query_map(params![&note_id], |row| {
+        Ok(NoteRevision {
+            id: row.get(0)?,
+            note_id: row.get(1)?,
+            text: row.get(2)?,
+            timestamp: row.get(3)?,
+        })
+    })?;
 
-    Ok(conn.last_insert_rowid())
+    let mut result = vec![];
+    for revision in iter {
+        result.push(revision?);
+    }
+
+    Ok(result)
 }
 
 pub fn get_tags_for_note<Conn: Deref<Target = rusqlite::Connection>>(
@@ -141,7 +103,7 @@ pub fn get_tags_for_note<Conn: Deref<Target = rusqlite::Connection>>(
     let mut stmt = conn.prepare("SELECT note_id, tag FROM tag_map WHERE note_id IN rarray(?1)")?;
 
     let note_ids_param = note_ids
-        .into_iter()
+        .iter()
         .map(|i| rusqlite::types::Value::from(*i))
         .collect();
     let note_ids_ptr = Rc::new(note_ids_param);
@@ -157,61 +119,385 @@ pub fn get_tags_for_note<Conn: Deref<Target = rusqlite::Connection>>(
     Ok(result)
 }
 
+fn note_from_row(row: &rusqlite::Row) -> rusqlite::Result<Note> {
+    Ok(Note {
+        id: row.get(0)?,
+        client_id: String::from(""),
+        text: row.get(1)?,
+        timestamp: row.get(2)?,
+        note_type: NoteType::from(row.get(3)?).unwrap(),
+        tags: HashSet::new(),
+    })
+}
+
+/// Turns the rows of a query that fetched `limit + 1` rows into a page.
+/// The extra row only tells us whether there is a next page.
+fn to_page<T, C>(mut rows: Vec<T>, limit: i64, cursor_of: impl Fn(&T) -> C) -> Page<T, C> {
+    let limit: usize = limit.try_into().unwrap();
+    let next_cursor = if rows.len() > limit {
+        rows.truncate(limit);
+        rows.last().map(cursor_of)
+    } else {
+        None
+    };
+
+    Page {
+        items: rows,
+        next_cursor,
+    }
+}
+
+/// Gets a page of notes with IDs between `min_id` and `max_id`, in ascending ID order.
+/// The first page holds the latest notes, `next_cursor` points to older notes,
+/// unless paging `After` a note, in which case it points to newer notes.
 pub fn get_notes<Conn: Deref<Target = rusqlite::Connection>>(
     conn: &mut Conn,
     min_id: i64,
     max_id: i64,
+    cursor: &Cursor<i64>,
+    limit: i64,
+) -> Result<Page<Note>, failure::Error> {
+    let (bound_cond, order, bound) = match cursor {
+        Cursor::First => ("id <= ?3", "DESC", max_id),
+        Cursor::Before(id) => ("id < ?3", "DESC", *id),
+        Cursor::After(id) => ("id > ?3", "ASC", *id),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, text, timestamp, note_type FROM notes
+        WHERE id BETWEEN ?1 AND ?2 AND {} AND deleted_at IS NULL
+        ORDER BY id {} LIMIT ?4",
+        bound_cond, order
+    ))?;
+
+    let note_iters = stmt.query_map(
+        params![&min_id, &max_id, &bound, &(limit + 1)],
+        note_from_row,
+    )?;
+
+    let mut result = vec![];
+    for note in note_iters {
+        result.push(note?);
+    }
+
+    drop(stmt);
+    let mut page = to_page(result, limit, |n| n.id);
+    fill_tags_for_notes(&mut page.items, conn)?;
+    if order == "DESC" {
+        page.items.reverse();
+    }
+
+    Ok(page)
+}
+
+/// Gets the notes with the given IDs that are not in the trash, in ascending ID order
+pub fn get_notes_by_ids<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_ids: &[i64],
 ) -> Result<Vec<Note>, failure::Error> {
     let mut stmt = conn.prepare(
-        "SELECT id, text, timestamp, note_type FROM notes 
-        WHERE id BETWEEN ?1 AND ?2
-        ORDER BY id DESC LIMIT 500",
+        "SELECT id, text, timestamp, note_type FROM notes
+        WHERE id IN rarray(?1) AND deleted_at IS NULL
+        ORDER BY id",
     )?;
 
-    let note_iters = stmt.query_map(params![&min_id, &max_id], |row| {
-        Ok(Note {
-            id: row.get(0)?,
-            client_id: String::from(""),
-            text: row.get(1)?,
-            timestamp: row.get(2)?,
-            note_type: NoteType::from(row.get(3)?).unwrap(),
-            tags: HashSet::new(),
-        })
-    })?;
+    let note_ids_param = note_ids
+        .iter()
+        .map(|i| rusqlite::types::Value::from(*i))
+        .collect();
+    let note_ids_ptr = Rc::new(note_ids_param);
+    let note_iters = stmt.query_map(params![&note_ids_ptr], note_from_row)?;
 
     let mut result = vec![];
-    let mut id_to_index = HashMap::new();
-    let mut note_ids = vec![];
     for note in note_iters {
         result.push(note?);
-        let note_id = result.last().unwrap().id;
-        id_to_index.insert(note_id, result.len() - 1);
-        note_ids.push(note_id);
     }
 
     drop(stmt);
+    fill_tags_for_notes(&mut result, conn)?;
+    Ok(result)
+}
+
+/// Gets a single note, unless it is in the trash
+pub fn get_note<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+) -> Result<Option<Note>, failure::Error> {
+    Ok(get_notes(conn, note_id, note_id, &Cursor::First, 1)?
+        .items
+        .pop())
+}
+
+fn fill_tags_for_notes<'a, Conn: Deref<Target = rusqlite::Connection>>(
+    notes: impl IntoIterator<Item = &'a mut Note>,
+    conn: &mut Conn,
+) -> Result<(), failure::Error> {
+    let mut id_to_note = HashMap::new();
+    for note in notes {
+        id_to_note.insert(note.id, note);
+    }
+
+    let note_ids: Vec<i64> = id_to_note.keys().cloned().collect();
     let tag_map = get_tags_for_note(&note_ids, conn)?;
 
     for tm in tag_map {
-        let note_id = tm.0;
-        let note = &mut result[id_to_index[&note_id]];
-        note.tags.insert(tm.1);
+        if let Some(note) = id_to_note.get_mut(&tm.0) {
+            note.tags.insert(tm.1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Full-text search over text notes.
+/// `query` must already be a valid FTS5 query expression.
+/// Results are ordered by relevance, best match first.
+pub fn search_notes<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<SearchResult>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT n.id, n.text, n.timestamp, n.note_type,
+            snippet(notes_fts, 0, ?2, ?3, '...', 16)
+        FROM notes_fts f
+            INNER JOIN notes n ON f.rowid = n.id
+        WHERE notes_fts MATCH ?1 AND n.deleted_at IS NULL
+        ORDER BY f.rank
+        LIMIT ?4",
+    )?;
+
+    let iter = stmt.query_map(
+        params![query, SNIPPET_MATCH_START, SNIPPET_MATCH_END, &limit],
+        |row| {
+            Ok(SearchResult {
+                note: note_from_row(row)?,
+                snippet: row.get(4)?,
+            })
+        },
+    )?;
+
+    let mut result = vec![];
+    for r in iter {
+        result.push(r?);
+    }
+
+    drop(stmt);
+    fill_tags_for_notes(result.iter_mut().map(|r| &mut r.note), conn)?;
+
+    Ok(result)
+}
+
+/// Moves a note to the trash. Returns false if there is no such note outside the trash.
+pub fn trash_note<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+) -> Result<bool, failure::Error> {
+    trash_note_at(conn, note_id, now())
+}
+
+/// Puts a note in the trash as of `deleted_at`. Returns false if it is already in the trash.
+pub fn trash_note_at<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+    deleted_at: i64,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "UPDATE notes SET deleted_at = ?1 WHERE id = ?2 AND deleted_at IS NULL",
+        params![&deleted_at, &note_id],
+    )?;
+
+    Ok(affected == 1)
+}
+
+/// Takes a note out of the trash. Returns false if the note is not in the trash.
+pub fn restore_note<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "UPDATE notes SET deleted_at = NULL WHERE id = ?1 AND deleted_at IS NOT NULL",
+        params![&note_id],
+    )?;
+
+    Ok(affected == 1)
+}
+
+pub fn get_trashed_notes<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<TrashedNote>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, text, timestamp, note_type, deleted_at FROM notes
+        WHERE deleted_at IS NOT NULL
+        ORDER BY deleted_at DESC, id DESC",
+    )?;
+
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(TrashedNote {
+            note: note_from_row(row)?,
+            deleted_at: row.get(4)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for trashed in iter {
+        result.push(trashed?);
+    }
+
+    drop(stmt);
+    fill_tags_for_notes(result.iter_mut().map(|t| &mut t.note), conn)?;
+
+    Ok(result)
+}
+
+/// Permanently removes a trashed note along with its tag maps, tag history and revisions.
+/// Returns the removed note, or None if the note is not in the trash.
+pub fn purge_note<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+) -> Result<Option<Note>, failure::Error> {
+    let note = conn.query_row(
+        "SELECT id, text, timestamp, note_type FROM notes
+        WHERE id = ?1 AND deleted_at IS NOT NULL",
+        params![&note_id],
+        note_from_row,
+    );
+
+    let note = match note {
+        Ok(note) => note,
+        Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    conn.execute("DELETE FROM tag_map WHERE note_id = ?1", params![&note_id])?;
+    conn.execute(
+        "DELETE FROM tag_map_history WHERE note_id = ?1",
+        params![&note_id],
+    )?;
+    conn.execute(
+        "DELETE FROM note_revisions WHERE note_id = ?1",
+        params![&note_id],
+    )?;
+    conn.execute("DELETE FROM notes WHERE id = ?1", params![&note_id])?;
+
+    Ok(Some(note))
+}
+
+pub fn get_trashed_note_ids<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<i64>, failure::Error> {
+    let mut stmt = conn.prepare("SELECT id FROM notes WHERE deleted_at IS NOT NULL")?;
+    let iter = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+
+    let mut result = vec![];
+    for id in iter {
+        result.push(id?);
+    }
+
+    Ok(result)
+}
+
+/// Records a stored image or attached file, with no note referring to it yet
+pub fn save_blob<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    path: &str,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "INSERT OR IGNORE INTO blobs(path, ref_count, created_at) VALUES(?1, 0, ?2)",
+        params![path, &now()],
+    )?;
+    // An unused file uploaded again is kept as long as a new upload
+    conn.execute(
+        "UPDATE blobs SET created_at = ?2 WHERE path = ?1 AND ref_count <= 0",
+        params![path, &now()],
+    )?;
+
+    Ok(())
+}
+
+/// Counts one more note referring to a stored file. Returns false if there is no such file.
+pub fn add_blob_ref<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    path: &str,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "UPDATE blobs SET ref_count = ref_count + 1 WHERE path = ?1",
+        params![path],
+    )?;
+
+    Ok(affected == 1)
+}
+
+/// Counts one less note referring to a stored file, and forgets the file once unused.
+/// Returns true if the file is to be removed.
+pub fn release_blob<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    path: &str,
+) -> Result<bool, failure::Error> {
+    conn.execute(
+        "UPDATE blobs SET ref_count = ref_count - 1 WHERE path = ?1",
+        params![path],
+    )?;
+    let affected = conn.execute(
+        "DELETE FROM blobs WHERE path = ?1 AND ref_count <= 0",
+        params![path],
+    )?;
+
+    Ok(affected == 1)
+}
+
+/// Forgets the files uploaded before `before` that no note ever referred to,
+/// and returns their paths
+pub fn take_unused_blobs<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    before: i64,
+) -> Result<Vec<String>, failure::Error> {
+    let mut stmt =
+        conn.prepare("SELECT path FROM blobs WHERE ref_count <= 0 AND created_at < ?1")?;
+    let iter = stmt.query_map(params![&before], |row| row.get(0))?;
+
+    let mut result = vec![];
+    for path in iter {
+        result.push(path?);
     }
 
-    result.reverse();
+    drop(stmt);
+    conn.execute(
+        "DELETE FROM blobs WHERE ref_count <= 0 AND created_at < ?1",
+        params![&before],
+    )?;
+
+    Ok(result)
+}
+
+pub fn get_blob_paths<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<String>, failure::Error> {
+    let mut stmt = conn.prepare("SELECT path FROM blobs ORDER BY path")?;
+    let iter = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+
+    let mut result = vec![];
+    for path in iter {
+        result.push(path?);
+    }
 
-    return Ok(result);
+    Ok(result)
 }
 
+/// Saves the tags and their maps, and returns the tags that didn't exist yet
 pub fn save_tags<Conn: Deref<Target = rusqlite::Connection>>(
     tags: &Vec<Tag>,
     conn: &mut Conn,
-) -> Result<(), failure::Error> {
+) -> Result<Vec<String>, failure::Error> {
+    let mut created = vec![];
     for tag in tags {
-        conn.execute(
-            "INSERT OR IGNORE INTO tags(tag, color) VALUES(?1, ?2)",
-            params![&tag.tag, &tag.color,],
+        let inserted = conn.execute(
+            "INSERT OR IGNORE INTO tags(tag, display, color) VALUES(?1, ?2, ?3)",
+            params![&tag.tag, &tag.display, &tag.color],
         )?;
+        if inserted > 0 {
+            created.push(tag.tag.clone());
+        }
 
         for map in &tag.maps {
             conn.execute(
@@ -221,105 +507,895 @@ pub fn save_tags<Conn: Deref<Target = rusqlite::Connection>>(
         }
     }
 
-    Ok(())
+    Ok(created)
 }
 
-pub fn get_tags<Conn: Deref<Target = rusqlite::Connection>>(
+pub fn tag_exists<Conn: Deref<Target = rusqlite::Connection>>(
     conn: &mut Conn,
-) -> Result<Vec<Tag>, failure::Error> {
-    let mut stmt = conn.prepare(
-        "SELECT t.tag, t.color, m.note_id, m.status, n.timestamp
-        FROM tag_map m
-            INNER JOIN tags t ON m.tag = t.tag
-            INNER JOIN notes n ON m.note_id = n.id
-        ORDER BY m.note_id DESC
-        LIMIT 500",
+    tag: &str,
+) -> Result<bool, failure::Error> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM tags WHERE tag = ?1",
+        params![tag],
+        |row| row.get(0),
     )?;
 
-    let iter = stmt.query_map(NO_PARAMS, |row| {
-        Ok((
-            row.get::<_, String>(0)?,
-            row.get::<_, String>(1)?,
-            TagMap {
-                note_id: row.get(2)?,
-                status: TagMapStatus::from(row.get(3)?).unwrap(),
-                timestamp: row.get(4)?,
-            },
-        ))
-    })?;
+    Ok(count > 0)
+}
 
-    let mut result = vec![];
-    let mut tag_to_index = HashMap::new();
-    for pair in iter {
-        let pair = pair?;
-        let index: usize = if let Some(x) = tag_to_index.get(&pair.0) {
-            *x
-        } else {
-            result.push(Tag {
-                tag: pair.0,
-                color: pair.1,
-                maps: vec![],
-            });
-
-            tag_to_index.insert(result.last().unwrap().tag.clone(), result.len() - 1);
-            result.len() - 1
-        };
+pub fn set_tag_display<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    display: &str,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "UPDATE tags SET display = ?2 WHERE tag = ?1",
+        params![tag, display],
+    )?;
+
+    Ok(())
+}
+
+pub fn get_tag_color<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+) -> Result<Option<String>, failure::Error> {
+    let color = conn.query_row(
+        "SELECT color FROM tags WHERE tag = ?1",
+        params![tag],
+        |row| row.get(0),
+    );
 
-        result[index].maps.push(pair.2);
+    match color {
+        Ok(color) => Ok(Some(color)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
     }
+}
 
-    result.sort_by(|a, b| {
-        a.tag
-            .partial_cmp(&b.tag)
-            .unwrap_or(std::cmp::Ordering::Equal)
-    });
-    Ok(result)
+/// Returns false if the tag does not exist
+pub fn save_tag_color<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    color: &str,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "UPDATE tags SET color = ?1 WHERE tag = ?2",
+        params![color, tag],
+    )?;
+
+    Ok(affected == 1)
 }
 
-pub fn get_tag_map<Conn: Deref<Target = rusqlite::Connection>>(
+/// Gets the ID and text of every text note using a tag, including notes in the trash
+pub fn get_note_texts_for_tag<Conn: Deref<Target = rusqlite::Connection>>(
     conn: &mut Conn,
     tag: &str,
-) -> Result<Vec<TagMap>, failure::Error> {
+) -> Result<Vec<(i64, String)>, failure::Error> {
     let mut stmt = conn.prepare(
-        "SELECT m.note_id, m.status, n.timestamp
+        "SELECT n.id, n.text
         FROM tag_map m
-            INNER JOIN tags t ON m.tag = t.tag
             INNER JOIN notes n ON m.note_id = n.id
-        WHERE m.tag = ?1
-        ORDER BY m.note_id DESC",
+        WHERE m.tag = ?1 AND n.note_type = ?2",
     )?;
-    let iter = stmt.query_map(params![tag], |row| {
-        Ok(TagMap {
-            note_id: row.get(0)?,
-            status: TagMapStatus::from(row.get::<_, i32>(1)?).unwrap(),
-            timestamp: row.get(2)?,
-        })
+    let iter = stmt.query_map(params![tag, NoteType::Text as i32], |row| {
+        Ok((row.get(0)?, row.get(1)?))
     })?;
 
     let mut result = vec![];
-    for tag_map in iter {
-        result.push(tag_map?);
+    for note in iter {
+        result.push(note?);
     }
 
     Ok(result)
 }
 
-pub fn save_tag_map<Conn: Deref<Target = rusqlite::Connection>>(
+/// Moves all tag maps and tag history of `from` over to `to`, then removes `from`.
+/// If `to` does not exist yet, it is created with the color of `from`.
+/// When a note is mapped to both tags, the existing `to` map is kept,
+/// and it is made active if either map was active.
+pub fn move_tag<Conn: Deref<Target = rusqlite::Connection>>(
     conn: &mut Conn,
-    tag: &str,
-    tag_map: &TagMap,
+    from: &str,
+    to: &str,
+    to_display: &str,
 ) -> Result<(), failure::Error> {
-    info!("Updating tag map status");
     conn.execute(
-        "UPDATE tag_map SET status = ?1 WHERE tag = ?2 AND note_id = ?3",
-        params![tag_map.status as i32, tag, &tag_map.note_id],
+        "INSERT OR IGNORE INTO tags(tag, display, color)
+        SELECT ?2, ?3, color FROM tags WHERE tag = ?1",
+        params![from, to, to_display],
     )?;
-
+    conn.execute(
+        "UPDATE tag_map SET status = ?3
+        WHERE tag = ?2 AND note_id IN (
+            SELECT note_id FROM tag_map WHERE tag = ?1 AND status = ?3
+        )",
+        params![from, to, TagMapStatus::Active as i32],
+    )?;
+    conn.execute(
+        "INSERT OR IGNORE INTO tag_map(tag, note_id, status)
+        SELECT ?2, note_id, status FROM tag_map WHERE tag = ?1",
+        params![from, to],
+    )?;
+    conn.execute("DELETE FROM tag_map WHERE tag = ?1", params![from])?;
+    conn.execute(
+        "UPDATE tag_map_history SET tag = ?2 WHERE tag = ?1",
+        params![from, to],
+    )?;
+    conn.execute("DELETE FROM tags WHERE tag = ?1", params![from])?;
+
+    Ok(())
+}
+
+pub fn delete_tag_maps_for_note<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    note_id: i64,
+    tags: &[String],
+) -> Result<(), failure::Error> {
+    for tag in tags {
+        conn.execute(
+            "DELETE FROM tag_map WHERE tag = ?1 AND note_id = ?2",
+            params![tag, &note_id],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Gets a page of tags in alphabetical order, each with all of its tag maps.
+/// Tags only used by trashed notes are left out.
+pub fn get_tags<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    cursor: &Cursor<String>,
+    limit: i64,
+) -> Result<Page<Tag, String>, failure::Error> {
+    let (bound_cond, order, bound) = match cursor {
+        Cursor::First => ("t.tag >= ?1", "ASC", ""),
+        Cursor::Before(tag) => ("t.tag < ?1", "DESC", tag.as_str()),
+        Cursor::After(tag) => ("t.tag > ?1", "ASC", tag.as_str()),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT t.tag, COALESCE(t.display, t.tag), t.color
+        FROM tags t
+        WHERE {} AND EXISTS(
+            SELECT 1 FROM tag_map m
+                INNER JOIN notes n ON m.note_id = n.id
+            WHERE m.tag = t.tag AND n.deleted_at IS NULL
+        )
+        ORDER BY t.tag {}
+        LIMIT ?2",
+        bound_cond, order
+    ))?;
+
+    let iter = stmt.query_map(params![bound, &(limit + 1)], |row| {
+        Ok(Tag {
+            tag: row.get(0)?,
+            display: row.get(1)?,
+            color: row.get(2)?,
+            maps: vec![],
+        })
+    })?;
+
+    let mut result = vec![];
+    for tag in iter {
+        result.push(tag?);
+    }
+
+    drop(stmt);
+    let mut page = to_page(result, limit, |t| t.tag.clone());
+    if order == "DESC" {
+        page.items.reverse();
+    }
+
+    let mut tag_to_index = HashMap::new();
+    for (i, tag) in page.items.iter().enumerate() {
+        tag_to_index.insert(tag.tag.clone(), i);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT m.tag, m.note_id, m.status, n.timestamp
+        FROM tag_map m
+            INNER JOIN notes n ON m.note_id = n.id
+        WHERE m.tag IN rarray(?1) AND n.deleted_at IS NULL
+        ORDER BY m.note_id DESC",
+    )?;
+
+    let tags_param = page
+        .items
+        .iter()
+        .map(|t| rusqlite::types::Value::from(t.tag.clone()))
+        .collect();
+    let tags_ptr = Rc::new(tags_param);
+    let iter = stmt.query_map(params![&tags_ptr], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            TagMap {
+                tag: row.get(0)?,
+                note_id: row.get(1)?,
+                status: TagMapStatus::from(row.get(2)?).unwrap(),
+                timestamp: row.get(3)?,
+            },
+        ))
+    })?;
+
+    for pair in iter {
+        let pair = pair?;
+        page.items[tag_to_index[&pair.0]].maps.push(pair.1);
+    }
+
+    Ok(page)
+}
+
+pub fn get_tag_stats<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<TagStats>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT t.tag, COALESCE(t.display, t.tag), t.color,
+            SUM(CASE WHEN m.status = ?1 THEN 1 ELSE 0 END),
+            SUM(CASE WHEN m.status = ?2 THEN 1 ELSE 0 END),
+            MIN(n.timestamp),
+            MAX(n.timestamp)
+        FROM tags t
+            INNER JOIN tag_map m ON m.tag = t.tag
+            INNER JOIN notes n ON m.note_id = n.id
+        WHERE n.deleted_at IS NULL
+        GROUP BY t.tag
+        ORDER BY t.tag",
+    )?;
+
+    let iter = stmt.query_map(
+        params![TagMapStatus::Active as i32, TagMapStatus::Archived as i32],
+        |row| {
+            Ok(TagStats {
+                tag: row.get(0)?,
+                display: row.get(1)?,
+                color: row.get(2)?,
+                active_count: row.get(3)?,
+                archived_count: row.get(4)?,
+                first_used: row.get(5)?,
+                last_used: row.get(6)?,
+            })
+        },
+    )?;
+
+    let mut result = vec![];
+    for stats in iter {
+        result.push(stats?);
+    }
+
+    Ok(result)
+}
+
+/// Gets a page of the notes mapped to `tag`, newest first.
+/// With `include_children`, notes mapped to child tags such as `#tag/child` are included,
+/// and a note with several matching tags gets a map for each of them.
+pub fn get_tag_map<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    include_children: bool,
+    cursor: &Cursor<i64>,
+    limit: i64,
+) -> Result<Page<TagMap>, failure::Error> {
+    let (bound_cond, order, bound) = match cursor {
+        Cursor::First => ("m.note_id <= ?2", "DESC", i64::MAX),
+        Cursor::Before(id) => ("m.note_id < ?2", "DESC", *id),
+        Cursor::After(id) => ("m.note_id > ?2", "ASC", *id),
+    };
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT m.tag, m.note_id, m.status, n.timestamp
+        FROM tag_map m
+            INNER JOIN tags t ON m.tag = t.tag
+            INNER JOIN notes n ON m.note_id = n.id
+        WHERE (m.tag = ?1 OR (?4 AND substr(m.tag, 1, length(?1) + 1) = ?1 || '/'))
+            AND {} AND n.deleted_at IS NULL
+        ORDER BY m.note_id {}, m.tag
+        LIMIT ?3",
+        bound_cond, order
+    ))?;
+    let iter = stmt.query_map(
+        params![tag, &bound, &(limit + 1), &include_children],
+        |row| {
+            Ok(TagMap {
+                tag: row.get(0)?,
+                note_id: row.get(1)?,
+                status: TagMapStatus::from(row.get::<_, i32>(2)?).unwrap(),
+                timestamp: row.get(3)?,
+            })
+        },
+    )?;
+
+    let mut result = vec![];
+    for tag_map in iter {
+        result.push(tag_map?);
+    }
+
+    // The cursor is a note ID, so the maps of one note must not be split across pages
+    let limit: usize = limit.try_into().unwrap();
+    let next_cursor = if result.len() > limit {
+        let boundary = result[limit].note_id;
+        let mut end = limit;
+        while end > 1 && result[end - 1].note_id == boundary {
+            end -= 1;
+        }
+        result.truncate(end);
+        result.last().map(|m| m.note_id)
+    } else {
+        None
+    };
+
+    if order == "ASC" {
+        result.reverse();
+    }
+
+    Ok(Page {
+        items: result,
+        next_cursor,
+    })
+}
+
+/// Changes the status of a note in a tag. Returns false if the note is not in the tag.
+pub fn save_tag_map<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    tag_map: &TagMap,
+) -> Result<bool, failure::Error> {
+    info!("Updating tag map status");
+    if !set_tag_map_status(conn, tag, tag_map.note_id, tag_map.status)? {
+        return Ok(false);
+    }
+
     info!("Saving tag map history");
+    save_tag_map_history(conn, tag, tag_map.note_id, tag_map.status, now())?;
+    Ok(true)
+}
+
+pub fn set_tag_map_status<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    note_id: i64,
+    status: TagMapStatus,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "UPDATE tag_map SET status = ?1 WHERE tag = ?2 AND note_id = ?3",
+        params![status as i32, tag, &note_id],
+    )?;
+
+    Ok(affected > 0)
+}
+
+pub fn save_tag_map_history<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    tag: &str,
+    note_id: i64,
+    status: TagMapStatus,
+    timestamp: i64,
+) -> Result<(), failure::Error> {
     conn.execute(
         "INSERT INTO tag_map_history(tag, note_id, status, timestamp) VALUES(?1, ?2, ?3, ?4)",
-        params![tag, &tag_map.note_id, tag_map.status as i32, &now()],
+        params![tag, &note_id, status as i32, &timestamp],
+    )?;
+
+    Ok(())
+}
+
+/// Gets all notes outside of the trash, oldest first
+pub fn get_all_notes<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<Note>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, text, timestamp, note_type FROM notes
+        WHERE deleted_at IS NULL
+        ORDER BY id ASC",
+    )?;
+    let iter = stmt.query_map(NO_PARAMS, note_from_row)?;
+
+    let mut result = vec![];
+    for note in iter {
+        result.push(note?);
+    }
+
+    drop(stmt);
+    fill_tags_for_notes(&mut result, conn)?;
+
+    Ok(result)
+}
+
+pub fn get_all_note_revisions<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<NoteRevision>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, note_id, text, timestamp FROM note_revisions
+        ORDER BY id ASC",
+    )?;
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(NoteRevision {
+            id: row.get(0)?,
+            note_id: row.get(1)?,
+            text: row.get(2)?,
+            timestamp: row.get(3)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for revision in iter {
+        result.push(revision?);
+    }
+
+    Ok(result)
+}
+
+/// Gets every tag with all of its tag maps, including the ones of trashed notes
+pub fn get_all_tags<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<Tag>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT tag, COALESCE(display, tag), color FROM tags
+        ORDER BY tag",
+    )?;
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(Tag {
+            tag: row.get(0)?,
+            display: row.get(1)?,
+            color: row.get(2)?,
+            maps: vec![],
+        })
+    })?;
+
+    let mut result = vec![];
+    for tag in iter {
+        result.push(tag?);
+    }
+
+    let mut tag_to_index = HashMap::new();
+    for (i, tag) in result.iter().enumerate() {
+        tag_to_index.insert(tag.tag.clone(), i);
+    }
+
+    let mut stmt = conn.prepare(
+        "SELECT m.tag, m.note_id, m.status, n.timestamp
+        FROM tag_map m
+            INNER JOIN notes n ON m.note_id = n.id
+        ORDER BY m.note_id",
+    )?;
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(TagMap {
+            tag: row.get(0)?,
+            note_id: row.get(1)?,
+            status: TagMapStatus::from(row.get(2)?).unwrap(),
+            timestamp: row.get(3)?,
+        })
+    })?;
+
+    for tag_map in iter {
+        let tag_map = tag_map?;
+        if let Some(&i) = tag_to_index.get(&tag_map.tag) {
+            result[i].maps.push(tag_map);
+        }
+    }
+
+    Ok(result)
+}
+
+/// Gets every change of tag map status, in the order they were made.
+/// Each map is timestamped with the time of the change.
+pub fn get_tag_map_history<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<TagMap>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT tag, note_id, status, COALESCE(timestamp, 0) FROM tag_map_history
+        ORDER BY id ASC",
+    )?;
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(TagMap {
+            tag: row.get(0)?,
+            note_id: row.get(1)?,
+            status: TagMapStatus::from(row.get(2)?).unwrap(),
+            timestamp: row.get(3)?,
+        })
+    })?;
+
+    let mut result = vec![];
+    for tag_map in iter {
+        result.push(tag_map?);
+    }
+
+    Ok(result)
+}
+
+/// Adds an account. Returns None if the username is taken.
+pub fn create_user<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    username: &str,
+    password_hash: &str,
+) -> Result<Option<i64>, failure::Error> {
+    let affected = conn.execute(
+        "INSERT OR IGNORE INTO users(username, password_hash, created_at) VALUES(?1, ?2, ?3)",
+        params![username, password_hash, &now()],
+    )?;
+    if affected == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(conn.last_insert_rowid()))
+}
+
+pub fn get_users<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<Vec<User>, failure::Error> {
+    let mut stmt = conn.prepare("SELECT id, username, created_at FROM users ORDER BY username")?;
+    let iter = stmt.query_map(NO_PARAMS, |row| {
+        Ok(User {
+            id: row.get(0)?,
+            username: row.get(1)?,
+            created_at: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+        })
+    })?;
+
+    let mut result = vec![];
+    for user in iter {
+        result.push(user?);
+    }
+
+    Ok(result)
+}
+
+/// Gets the ID and password hash of an account, to check a login against
+pub fn get_password_hash<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    username: &str,
+) -> Result<Option<(i64, String)>, failure::Error> {
+    let found = conn
+        .query_row(
+            "SELECT id, password_hash FROM users WHERE username = ?1",
+            params![username],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?;
+
+    Ok(found)
+}
+
+/// Changes the password of an account, logging it out everywhere.
+/// Returns false if there is no such account.
+pub fn set_password_hash<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    username: &str,
+    password_hash: &str,
+) -> Result<bool, failure::Error> {
+    conn.execute(
+        "DELETE FROM sessions WHERE user_id IN (SELECT id FROM users WHERE username = ?1)",
+        params![username],
+    )?;
+    let affected = conn.execute(
+        "UPDATE users SET password_hash = ?2 WHERE username = ?1",
+        params![username, password_hash],
+    )?;
+
+    Ok(affected == 1)
+}
+
+/// Removes an account along with its sessions and API tokens.
+/// Returns false if there is no such account.
+pub fn delete_user<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    username: &str,
+) -> Result<bool, failure::Error> {
+    for table in &["sessions", "api_tokens"] {
+        conn.execute(
+            &format!(
+                "DELETE FROM {} WHERE user_id IN (SELECT id FROM users WHERE username = ?1)",
+                table
+            ),
+            params![username],
+        )?;
+    }
+    let affected = conn.execute("DELETE FROM users WHERE username = ?1", params![username])?;
+
+    Ok(affected == 1)
+}
+
+pub fn save_session<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    token_hash: &str,
+    user_id: i64,
+    expires_at: i64,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "INSERT INTO sessions(token_hash, user_id, created_at, expires_at) VALUES(?1, ?2, ?3, ?4)",
+        params![token_hash, &user_id, &now(), &expires_at],
+    )?;
+
+    Ok(())
+}
+
+/// Gets the user logged in with a session, unless the session expired
+pub fn get_session_user<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    token_hash: &str,
+) -> Result<Option<User>, failure::Error> {
+    let found = conn
+        .query_row(
+            "SELECT u.id, u.username, u.created_at FROM sessions s
+            INNER JOIN users u ON u.id = s.user_id
+            WHERE s.token_hash = ?1 AND s.expires_at > ?2",
+            params![token_hash, &now()],
+            |row| {
+                Ok(User {
+                    id: row.get(0)?,
+                    username: row.get(1)?,
+                    created_at: row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+                })
+            },
+        )
+        .optional()?;
+
+    Ok(found)
+}
+
+pub fn delete_session<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    token_hash: &str,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "DELETE FROM sessions WHERE token_hash = ?1",
+        params![token_hash],
+    )?;
+
+    Ok(())
+}
+
+pub fn delete_expired_sessions<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+) -> Result<usize, failure::Error> {
+    Ok(conn.execute(
+        "DELETE FROM sessions WHERE expires_at <= ?1",
+        params![&now()],
+    )?)
+}
+
+fn api_token_from_row(row: &rusqlite::Row) -> Result<ApiToken, rusqlite::Error> {
+    Ok(ApiToken {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        scope: TokenScope::from(row.get(2)?).unwrap(),
+        created_at: row.get(3)?,
+        last_used_at: row.get(4)?,
+    })
+}
+
+pub fn create_api_token<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    user_id: i64,
+    name: &str,
+    token_hash: &str,
+    scope: TokenScope,
+) -> Result<ApiToken, failure::Error> {
+    let created_at = now();
+    conn.execute(
+        "INSERT INTO api_tokens(user_id, name, token_hash, scope, created_at)
+        VALUES(?1, ?2, ?3, ?4, ?5)",
+        params![&user_id, name, token_hash, &(scope as i32), &created_at],
+    )?;
+
+    Ok(ApiToken {
+        id: conn.last_insert_rowid(),
+        name: name.to_string(),
+        scope,
+        created_at,
+        last_used_at: None,
+    })
+}
+
+pub fn get_api_tokens<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    user_id: i64,
+) -> Result<Vec<ApiToken>, failure::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT id, name, scope, created_at, last_used_at FROM api_tokens
+        WHERE user_id = ?1 ORDER BY created_at, id",
+    )?;
+    let iter = stmt.query_map(params![&user_id], api_token_from_row)?;
+
+    let mut result = vec![];
+    for token in iter {
+        result.push(token?);
+    }
+
+    Ok(result)
+}
+
+/// Revokes a token of the user. Returns false if the user has no such token.
+pub fn delete_api_token<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    user_id: i64,
+    token_id: i64,
+) -> Result<bool, failure::Error> {
+    let affected = conn.execute(
+        "DELETE FROM api_tokens WHERE id = ?1 AND user_id = ?2",
+        params![&token_id, &user_id],
+    )?;
+
+    Ok(affected == 1)
+}
+
+/// Gets an API token by its hash, along with the user it belongs to
+pub fn get_token_user<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    token_hash: &str,
+) -> Result<Option<(User, ApiToken)>, failure::Error> {
+    let found = conn
+        .query_row(
+            "SELECT t.id, t.name, t.scope, t.created_at, t.last_used_at,
+                u.id, u.username, u.created_at
+            FROM api_tokens t
+            INNER JOIN users u ON u.id = t.user_id
+            WHERE t.token_hash = ?1",
+            params![token_hash],
+            |row| {
+                let user = User {
+                    id: row.get(5)?,
+                    username: row.get(6)?,
+                    created_at: row.get::<_, Option<i64>>(7)?.unwrap_or(0),
+                };
+                Ok((user, api_token_from_row(row)?))
+            },
+        )
+        .optional()?;
+
+    Ok(found)
+}
+
+pub fn set_api_token_used<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    token_id: i64,
+) -> Result<(), failure::Error> {
+    conn.execute(
+        "UPDATE api_tokens SET last_used_at = ?2 WHERE id = ?1",
+        params![&token_id, &now()],
     )?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_pool::open_test_db;
+
+    fn text_note(text: &str) -> Note {
+        Note {
+            id: 0,
+            client_id: String::new(),
+            text: text.to_string(),
+            timestamp: 1,
+            note_type: NoteType::Text,
+            tags: HashSet::new(),
+        }
+    }
+
+    fn ids(page: &Page<Note>) -> Vec<i64> {
+        page.items.iter().map(|n| n.id).collect()
+    }
+
+    #[test]
+    fn notes_are_paged_from_the_latest() {
+        let mut conn = open_test_db();
+        let mut tx = tx(&mut conn).unwrap();
+        for i in 0..5 {
+            save_note(&text_note(&format!("note {}", i)), &mut tx).unwrap();
+        }
+
+        let first = get_notes(&mut tx, 1, i64::MAX, &Cursor::First, 2).unwrap();
+        assert_eq!(ids(&first), vec![4, 5]);
+        assert_eq!(first.next_cursor, Some(4));
+
+        let second = get_notes(&mut tx, 1, i64::MAX, &Cursor::Before(4), 2).unwrap();
+        assert_eq!(ids(&second), vec![2, 3]);
+        assert_eq!(second.next_cursor, Some(2));
+
+        let last = get_notes(&mut tx, 1, i64::MAX, &Cursor::Before(2), 2).unwrap();
+        assert_eq!(ids(&last), vec![1]);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn notes_are_paged_after_a_cursor() {
+        let mut conn = open_test_db();
+        let mut tx = tx(&mut conn).unwrap();
+        for i in 0..5 {
+            save_note(&text_note(&format!("note {}", i)), &mut tx).unwrap();
+        }
+
+        let page = get_notes(&mut tx, 1, i64::MAX, &Cursor::After(1), 2).unwrap();
+        assert_eq!(ids(&page), vec![2, 3]);
+        assert_eq!(page.next_cursor, Some(3));
+
+        let bounded = get_notes(&mut tx, 2, 4, &Cursor::First, 10).unwrap();
+        assert_eq!(ids(&bounded), vec![2, 3, 4]);
+        assert_eq!(bounded.next_cursor, None);
+    }
+
+    fn tag_with_notes(tag: &str, note_ids: &[i64]) -> Tag {
+        Tag {
+            tag: tag.to_string(),
+            display: tag.to_uppercase(),
+            color: String::from("#000000"),
+            maps: note_ids
+                .iter()
+                .map(|&note_id| TagMap {
+                    tag: tag.to_string(),
+                    note_id,
+                    status: TagMapStatus::Active,
+                    timestamp: 1,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn tag_stats_count_the_notes_outside_the_trash() {
+        let mut conn = open_test_db();
+        let mut tx = tx(&mut conn).unwrap();
+        for i in 1..=5 {
+            let mut note = text_note(&format!("note {}", i));
+            note.timestamp = i * 10;
+            save_note(&note, &mut tx).unwrap();
+        }
+        let tags = vec![
+            tag_with_notes("#a", &[1, 2, 3, 5]),
+            tag_with_notes("#b", &[4]),
+        ];
+        save_tags(&tags, &mut tx).unwrap();
+        assert!(set_tag_map_status(&mut tx, "#a", 2, TagMapStatus::Archived).unwrap());
+        assert!(trash_note(&mut tx, 5).unwrap());
+
+        let stats = get_tag_stats(&mut tx).unwrap();
+        let counts: Vec<_> = stats
+            .iter()
+            .map(|s| {
+                (
+                    s.tag.as_str(),
+                    s.display.as_str(),
+                    s.active_count,
+                    s.archived_count,
+                    s.active_count + s.archived_count,
+                    s.first_used,
+                    s.last_used,
+                )
+            })
+            .collect();
+        assert_eq!(
+            counts,
+            vec![("#a", "#A", 2, 1, 3, 10, 30), ("#b", "#B", 1, 0, 1, 40, 40)]
+        );
+    }
+
+    #[test]
+    fn notes_by_ids_leave_out_the_trash() {
+        let mut conn = open_test_db();
+        let mut tx = tx(&mut conn).unwrap();
+        for i in 0..5 {
+            save_note(&text_note(&format!("note {}", i)), &mut tx).unwrap();
+        }
+        let tag = Tag {
+            tag: String::from("#t3"),
+            display: String::from("#t3"),
+            color: String::from("#000000"),
+            maps: vec![TagMap {
+                tag: String::from("#t3"),
+                note_id: 4,
+                status: TagMapStatus::Active,
+                timestamp: 1,
+            }],
+        };
+        save_tags(&vec![tag], &mut tx).unwrap();
+        assert!(trash_note(&mut tx, 2).unwrap());
+
+        let notes = get_notes_by_ids(&mut tx, &[5, 4, 2, 42, 4]).unwrap();
+        assert_eq!(notes.iter().map(|n| n.id).collect::<Vec<_>>(), vec![4, 5]);
+        assert!(notes[0].tags.contains("#t3"));
+        assert!(get_notes_by_ids(&mut tx, &[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+//! SQLite connections shared by the request handlers.
+//! The database runs in WAL mode so that readers don't block the single writer,
+//! and all queries run on the blocking thread pool instead of the async workers.
+
+use crate::migrations;
+use actix_web::error::BlockingError;
+use actix_web::web;
+use rusqlite::{Connection, OpenFlags, NO_PARAMS};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::Duration;
+
+// How long a connection waits for a lock held by another connection
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Idle connections, handed out one request at a time
+struct Slots {
+    conns: Mutex<Vec<Connection>>,
+    available: Condvar,
+}
+
+impl Slots {
+    fn new(conns: Vec<Connection>) -> Arc<Self> {
+        Arc::new(Self {
+            conns: Mutex::new(conns),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Blocks until a connection is free.
+    /// A panic elsewhere can't leave the list of connections half changed,
+    /// so a poisoned lock is simply taken over.
+    fn get(self: &Arc<Self>) -> PooledConnection {
+        let mut conns = self.conns.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(conn) = conns.pop() {
+                return PooledConnection {
+                    conn: Some(conn),
+                    slots: self.clone(),
+                };
+            }
+
+            conns = self
+                .available
+                .wait(conns)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn put(&self, conn: Connection) {
+        self.conns
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(conn);
+        self.available.notify_one();
+    }
+}
+
+/// A connection borrowed from the pool, returned to it when dropped,
+/// including when the query using it panics.
+pub struct PooledConnection {
+    conn: Option<Connection>,
+    slots: Arc<Slots>,
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.slots.put(conn);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct DbPool {
+    writer: Arc<Slots>,
+    readers: Arc<Slots>,
+}
+
+/// Opens a connection, also used by the maintenance commands outside of any pool
+pub fn open_connection(path: &Path) -> Result<Connection, failure::Error> {
+    init_connection(Connection::open(path)?)
+}
+
+fn init_connection(conn: Connection) -> Result<Connection, failure::Error> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    rusqlite::vtab::array::load_module(&conn)?;
+    Ok(conn)
+}
+
+/// A new in-memory database with every migration applied
+#[cfg(test)]
+pub fn open_test_db() -> Connection {
+    let mut conn = init_connection(Connection::open_in_memory().unwrap()).unwrap();
+    migrations::migrate(&mut conn, Path::new("backups")).unwrap();
+    conn
+}
+
+/// Opens a read-only connection outside of any pool, for the maintenance commands
+pub fn open_read_only(path: &Path) -> Result<Connection, failure::Error> {
+    init_connection(Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
+
+impl DbPool {
+    /// Opens the database at `path`, bringing its schema up to date,
+    /// with one writer connection and `reader_count` read-only connections.
+    pub fn open(path: &Path, reader_count: usize) -> Result<Self, failure::Error> {
+        let mut writer = open_connection(path)?;
+        let journal_mode: String =
+            writer.query_row("PRAGMA journal_mode = WAL", NO_PARAMS, |row| row.get(0))?;
+        info!("Database journal mode: {}", journal_mode);
+        migrations::migrate(&mut writer, &migrations::backup_dir(path))?;
+
+        let mut readers = Vec::with_capacity(reader_count);
+        for _ in 0..reader_count {
+            let reader = open_connection(path)?;
+            reader.execute_batch("PRAGMA query_only = ON")?;
+            readers.push(reader);
+        }
+
+        Ok(Self {
+            writer: Slots::new(vec![writer]),
+            readers: Slots::new(readers),
+        })
+    }
+
+    async fn run<F, T>(slots: Arc<Slots>, f: F) -> Result<T, BlockingError<failure::Error>>
+    where
+        F: FnOnce(&mut PooledConnection) -> Result<T, failure::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        web::block(move || {
+            let mut conn = slots.get();
+            f(&mut conn)
+        })
+        .await
+    }
+
+    /// Runs read-only queries on one of the reader connections
+    pub async fn read<F, T>(&self, f: F) -> Result<T, BlockingError<failure::Error>>
+    where
+        F: FnOnce(&mut PooledConnection) -> Result<T, failure::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run(self.readers.clone(), f).await
+    }
+
+    /// Runs queries on the writer connection, one caller at a time
+    pub async fn write<F, T>(&self, f: F) -> Result<T, BlockingError<failure::Error>>
+    where
+        F: FnOnce(&mut PooledConnection) -> Result<T, failure::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run(self.writer.clone(), f).await
+    }
+}
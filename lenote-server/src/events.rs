@@ -0,0 +1,116 @@
+//! Pushes the changes made by any client to every app open, as server-sent events
+
+use crate::AppState;
+use actix_web::{http, web, HttpResponse};
+use bytes::Bytes;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use lenote_common::api::LiveEvent;
+use lenote_common::models::Note;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Comments sent to idle streams keep proxies from closing them, and find the closed ones
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// How long browsers wait before reconnecting a dropped stream, in milliseconds
+const RETRY_DELAY: u64 = 5000;
+
+/// The open event streams, shared by all workers
+#[derive(Clone, Default)]
+pub struct Broadcaster {
+    clients: Arc<Mutex<Vec<mpsc::UnboundedSender<Bytes>>>>,
+}
+
+impl Broadcaster {
+    /// Must be created within the actix system, which runs the pings
+    pub fn new() -> Self {
+        let broadcaster = Self::default();
+        let pinged = broadcaster.clone();
+        actix_rt::spawn(async move {
+            let mut interval = actix_rt::time::interval(PING_INTERVAL);
+            loop {
+                interval.tick().await;
+                pinged.send_bytes(Bytes::from_static(b": ping\n\n"));
+            }
+        });
+
+        broadcaster
+    }
+
+    pub(crate) fn subscribe(&self) -> mpsc::UnboundedReceiver<Bytes> {
+        let (sender, receiver) = mpsc::unbounded();
+        // Only fails when the receiver is gone, which it can't be yet
+        let _ = sender.unbounded_send(Bytes::from(format!("retry: {}\n\n", RETRY_DELAY)));
+        self.clients.lock().unwrap().push(sender);
+
+        receiver
+    }
+
+    fn send_bytes(&self, message: Bytes) {
+        // Streams of clients that went away are dropped as they're found closed
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| client.unbounded_send(message.clone()).is_ok());
+    }
+
+    /// Sends an event to every stream, once its change is committed
+    pub fn send(&self, event: &LiveEvent) {
+        match serde_json::to_string(event) {
+            Ok(json) => self.send_bytes(Bytes::from(format!("data: {}\n\n", json))),
+            Err(e) => warn!("Failed to serialize {:?}: {}", event, e),
+        }
+    }
+
+    /// Sends the tags a saved note used for the first time, then the note
+    pub fn note_saved(&self, note: &Note, created_tags: Vec<String>) {
+        for tag in created_tags {
+            self.send(&LiveEvent::TagCreated(tag));
+        }
+        self.send(&LiveEvent::NoteSaved(note.clone()));
+    }
+}
+
+pub async fn http_events(ctx: web::Data<AppState>) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header(http::header::CACHE_CONTROL, "no-cache")
+        .streaming(ctx.events.subscribe().map(Ok::<_, actix_web::Error>))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(receiver: &mut mpsc::UnboundedReceiver<Bytes>) -> String {
+        let message = receiver.try_recv().unwrap();
+        String::from_utf8(message.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn subscribers_get_the_retry_delay_then_every_event() {
+        let events = Broadcaster::default();
+        let mut first = events.subscribe();
+        let mut second = events.subscribe();
+        assert_eq!(next(&mut first), "retry: 5000\n\n");
+        assert_eq!(next(&mut second), "retry: 5000\n\n");
+
+        events.send(&LiveEvent::TagCreated(String::from("#job")));
+        assert_eq!(next(&mut first), "data: {\"TagCreated\":\"#job\"}\n\n");
+        assert_eq!(next(&mut second), "data: {\"TagCreated\":\"#job\"}\n\n");
+        assert!(first.try_recv().is_err());
+    }
+
+    #[test]
+    fn drops_the_streams_of_clients_gone() {
+        let events = Broadcaster::default();
+        let mut kept = events.subscribe();
+        drop(events.subscribe());
+        assert_eq!(events.clients.lock().unwrap().len(), 2);
+
+        events.send(&LiveEvent::TagCreated(String::from("#job")));
+        assert_eq!(events.clients.lock().unwrap().len(), 1);
+        next(&mut kept);
+        assert_eq!(next(&mut kept), "data: {\"TagCreated\":\"#job\"}\n\n");
+    }
+}
@@ -0,0 +1,434 @@
+//! Export of all notes and tags in readable form.
+//!
+//! An export holds `lenote.json`, a lossless dump of the database,
+//! Markdown files with the notes grouped per day or per tag,
+//! and the image files of the notes under `images`. Attached files go under
+//! `files/<hash>/<name>`, so that they keep their names.
+
+use crate::database;
+use crate::file_store;
+use crate::migrations;
+use crate::note_api;
+use chrono::{DateTime, Local, TimeZone};
+use lenote_common::files::{format_size, FileAttachment};
+use lenote_common::models::*;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+pub const JSON_NAME: &str = "lenote.json";
+pub const IMAGES_DIR: &str = "images";
+pub const FILES_DIR: &str = "files";
+const TAGS_DIR: &str = "tags";
+const UNTAGGED_NAME: &str = "untagged.md";
+
+/// How notes are split into Markdown files
+#[derive(Clone, Copy)]
+pub enum Grouping {
+    Day,
+    Tag,
+}
+
+impl FromStr for Grouping {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "tag" => Ok(Self::Tag),
+            _ => Err(failure::format_err!("Unknown grouping {}", s)),
+        }
+    }
+}
+
+/// Where the files of an export are written to, by their `/` separated path
+pub trait ExportSink {
+    fn add_file(&mut self, path: &str, contents: &[u8]) -> Result<(), failure::Error>;
+    fn copy_file(&mut self, path: &str, source: &Path) -> Result<(), failure::Error>;
+}
+
+/// Writes the export into a directory
+pub struct DirSink(pub PathBuf);
+
+impl DirSink {
+    fn target(&self, path: &str) -> Result<PathBuf, failure::Error> {
+        let target = self.0.join(path);
+        if let Some(dir) = target.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        Ok(target)
+    }
+}
+
+impl ExportSink for DirSink {
+    fn add_file(&mut self, path: &str, contents: &[u8]) -> Result<(), failure::Error> {
+        fs::write(self.target(path)?, contents)?;
+        Ok(())
+    }
+
+    fn copy_file(&mut self, path: &str, source: &Path) -> Result<(), failure::Error> {
+        fs::copy(source, self.target(path)?)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> ExportSink for tar::Builder<W> {
+    fn add_file(&mut self, path: &str, contents: &[u8]) -> Result<(), failure::Error> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(database::now() as u64);
+        header.set_cksum();
+        self.append_data(&mut header, path, contents)?;
+        Ok(())
+    }
+
+    fn copy_file(&mut self, path: &str, source: &Path) -> Result<(), failure::Error> {
+        self.append_path_with_name(source, path)?;
+        Ok(())
+    }
+}
+
+/// Reads everything there is to export from the database, in one transaction
+/// so that notes saved in the meantime can't leave it inconsistent
+pub fn collect(conn: &mut rusqlite::Connection) -> Result<Export, failure::Error> {
+    let mut tx = database::tx(conn)?;
+    let export = Export {
+        schema_version: migrations::schema_version(&tx)?,
+        exported_at: database::now(),
+        notes: database::get_all_notes(&mut tx)?,
+        trashed_notes: database::get_trashed_notes(&mut tx)?,
+        revisions: database::get_all_note_revisions(&mut tx)?,
+        tags: database::get_all_tags(&mut tx)?,
+        tag_history: database::get_tag_map_history(&mut tx)?,
+    };
+    database::commit(tx)?;
+
+    Ok(export)
+}
+
+fn local_time(timestamp: i64) -> DateTime<Local> {
+    Local.timestamp(timestamp, 0)
+}
+
+/// Renders a note for a Markdown file at a depth of `depth` directories,
+/// given the paths its image or file was copied to
+fn note_markdown(note: &Note, attachments: &HashMap<i64, String>, depth: usize) -> String {
+    let path = match attachments.get(&note.id) {
+        Some(path) => format!("{}{}", "../".repeat(depth), path),
+        None => return note.text.clone(),
+    };
+
+    match FileAttachment::from_text(&note.text) {
+        Some(file) if note.note_type == NoteType::File => {
+            format!("[{}](<{}>) ({})", file.name, path, format_size(file.size))
+        }
+        _ => format!("![]({})", path),
+    }
+}
+
+/// Finds the stored image or file of a note, and where to copy it to in the export
+fn attachment_paths(data: &Path, note: &Note) -> Option<(PathBuf, String)> {
+    match note.note_type {
+        NoteType::Image => {
+            let source = note_api::img_file_path(data, note)?;
+            let file_name = source.file_name()?.to_string_lossy().into_owned();
+            Some((source, format!("{}/{}", IMAGES_DIR, file_name)))
+        }
+        NoteType::File => {
+            let file = FileAttachment::from_text(&note.text)?;
+            let source = file_store::file_path(data, &file.path)?;
+            let hash = source.file_name()?.to_string_lossy().into_owned();
+            Some((source, format!("{}/{}/{}", FILES_DIR, hash, file.name)))
+        }
+        NoteType::Text => None,
+    }
+}
+
+fn day_markdown(day: &str, notes: &[&Note], attachments: &HashMap<i64, String>) -> String {
+    let mut result = format!("# {}\n", day);
+    for note in notes {
+        result.push_str(&format!(
+            "\n## {}\n\n{}\n",
+            local_time(note.timestamp).format("%H:%M"),
+            note_markdown(note, attachments, 0)
+        ));
+    }
+
+    result
+}
+
+fn tag_markdown(
+    tag: &Tag,
+    notes: &HashMap<i64, &Note>,
+    attachments: &HashMap<i64, String>,
+    depth: usize,
+) -> String {
+    let mut result = format!("# {}\n", tag.display);
+    for map in &tag.maps {
+        // Maps of trashed notes are only kept in the JSON dump
+        let note = match notes.get(&map.note_id) {
+            Some(note) => note,
+            None => continue,
+        };
+
+        let status = match map.status {
+            TagMapStatus::Active => "",
+            TagMapStatus::Archived => " (archived)",
+        };
+        result.push_str(&format!(
+            "\n## {}{}\n\n{}\n",
+            local_time(note.timestamp).format("%Y-%m-%d %H:%M"),
+            status,
+            note_markdown(note, attachments, depth)
+        ));
+    }
+
+    result
+}
+
+/// Writes the export, with its images and files taken from the data directory `data`
+pub fn write<S: ExportSink>(
+    export: &Export,
+    data: &Path,
+    grouping: Grouping,
+    sink: &mut S,
+) -> Result<(), failure::Error> {
+    sink.add_file(JSON_NAME, &serde_json::to_vec_pretty(export)?)?;
+
+    let all_notes = export
+        .notes
+        .iter()
+        .chain(export.trashed_notes.iter().map(|t| &t.note));
+    let mut attachments = HashMap::new();
+    for note in all_notes.filter(|n| n.note_type != NoteType::Text) {
+        let (source, path) = match attachment_paths(data, note) {
+            Some((source, path)) if source.exists() => (source, path),
+            _ => {
+                warn!("Missing file of note {}: {}", note.id, note.text);
+                continue;
+            }
+        };
+
+        sink.copy_file(&path, &source)?;
+        attachments.insert(note.id, path);
+    }
+
+    match grouping {
+        Grouping::Day => {
+            let mut days: BTreeMap<String, Vec<&Note>> = BTreeMap::new();
+            for note in &export.notes {
+                let day = local_time(note.timestamp).format("%Y-%m-%d").to_string();
+                days.entry(day).or_default().push(note);
+            }
+
+            for (day, notes) in days {
+                let markdown = day_markdown(&day, &notes, &attachments);
+                sink.add_file(&format!("{}.md", day), markdown.as_bytes())?;
+            }
+        }
+        Grouping::Tag => {
+            let notes: HashMap<i64, &Note> = export.notes.iter().map(|n| (n.id, n)).collect();
+            for tag in &export.tags {
+                if !tag.maps.iter().any(|m| notes.contains_key(&m.note_id)) {
+                    continue;
+                }
+
+                // `#project/alpha` goes to `tags/project/alpha.md`
+                let name = tag.tag.trim_start_matches('#');
+                let depth = name.split('/').count();
+                let markdown = tag_markdown(tag, &notes, &attachments, depth);
+                sink.add_file(&format!("{}/{}.md", TAGS_DIR, name), markdown.as_bytes())?;
+            }
+
+            let untagged: Vec<&Note> = export.notes.iter().filter(|n| n.tags.is_empty()).collect();
+            if !untagged.is_empty() {
+                let mut markdown = String::from("# Untagged\n");
+                for note in untagged {
+                    markdown.push_str(&format!(
+                        "\n## {}\n\n{}\n",
+                        local_time(note.timestamp).format("%Y-%m-%d %H:%M"),
+                        note_markdown(note, &attachments, 0)
+                    ));
+                }
+                sink.add_file(UNTAGGED_NAME, markdown.as_bytes())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the export as a `.tar.zst` archive to the file `out`
+pub fn write_archive(
+    export: &Export,
+    data: &Path,
+    grouping: Grouping,
+    out: &Path,
+) -> Result<(), failure::Error> {
+    let mut builder = tar::Builder::new(zstd::Encoder::new(fs::File::create(out)?, 0)?);
+    write(export, data, grouping, &mut builder)?;
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_pool::open_test_db;
+    use crate::tag_api;
+    use crate::testing::TempDir;
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn add_note(
+        conn: &mut rusqlite::Connection,
+        note_type: NoteType,
+        text: &str,
+        timestamp: i64,
+    ) -> i64 {
+        let mut tx = database::tx(conn).unwrap();
+        let mut note = Note {
+            id: 0,
+            client_id: String::new(),
+            text: text.to_string(),
+            timestamp,
+            note_type,
+            tags: Default::default(),
+        };
+        note.id = database::save_note(&note, &mut tx).unwrap();
+        let (note, _) =
+            tag_api::save_tags_for_note(note, &[String::from("#000000")], &mut tx).unwrap();
+        database::commit(tx).unwrap();
+        note.id
+    }
+
+    fn store(data: &Path, url_path: &str, contents: &[u8]) {
+        let path = file_store::resource_file(data, url_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    fn read(dir: &Path, path: &str) -> String {
+        fs::read_to_string(dir.join(path)).unwrap()
+    }
+
+    fn day(timestamp: i64) -> String {
+        local_time(timestamp).format("%Y-%m-%d.md").to_string()
+    }
+
+    /// Notes with tags, an image, a file and a trashed note, and their stored files
+    fn notes(data: &Path) -> Export {
+        store(data, "/res/images/ab/abcd.png", b"png");
+        store(data, "/res/files/cd/cdef", b"text");
+        let file = FileAttachment {
+            path: String::from("/res/files/cd/cdef"),
+            name: String::from("a.txt"),
+            size: 4,
+        };
+
+        let mut conn = open_test_db();
+        let first = add_note(&mut conn, NoteType::Text, "#work first", 10 * DAY);
+        add_note(
+            &mut conn,
+            NoteType::Image,
+            "/res/images/ab/abcd.png",
+            10 * DAY,
+        );
+        add_note(&mut conn, NoteType::File, &file.to_text(), 20 * DAY);
+        add_note(&mut conn, NoteType::Text, "#work/sub second", 20 * DAY);
+        let trashed = add_note(&mut conn, NoteType::Text, "#work trashed", 20 * DAY);
+
+        let archived = TagMap {
+            tag: String::from("#work"),
+            note_id: first,
+            status: TagMapStatus::Archived,
+            timestamp: 0,
+        };
+        assert!(database::save_tag_map(&mut &conn, "#work", &archived).unwrap());
+        assert!(database::trash_note(&mut &conn, trashed).unwrap());
+
+        collect(&mut conn).unwrap()
+    }
+
+    #[test]
+    fn writes_notes_per_day() {
+        let data = TempDir::new();
+        let out = TempDir::new();
+        let export = notes(data.path());
+        write(
+            &export,
+            data.path(),
+            Grouping::Day,
+            &mut DirSink(out.path().to_path_buf()),
+        )
+        .unwrap();
+
+        let dump: Export = serde_json::from_str(&read(out.path(), JSON_NAME)).unwrap();
+        assert_eq!(dump.notes.len(), 4);
+        assert_eq!(dump.trashed_notes.len(), 1);
+        assert_eq!(dump.trashed_notes[0].note.text, "#work trashed");
+        assert_eq!(dump.tags.len(), 2);
+
+        let first_day = read(out.path(), &day(10 * DAY));
+        assert!(first_day.contains("\n#work first\n"), "{}", first_day);
+        assert!(
+            first_day.contains("\n![](images/abcd.png)\n"),
+            "{}",
+            first_day
+        );
+        let second_day = read(out.path(), &day(20 * DAY));
+        assert!(
+            second_day.contains("[a.txt](<files/cdef/a.txt>)"),
+            "{}",
+            second_day
+        );
+        assert!(
+            second_day.contains("\n#work/sub second\n"),
+            "{}",
+            second_day
+        );
+        assert!(!second_day.contains("trashed"), "{}", second_day);
+
+        assert_eq!(read(out.path(), "images/abcd.png"), "png");
+        assert_eq!(read(out.path(), "files/cdef/a.txt"), "text");
+    }
+
+    #[test]
+    fn writes_notes_per_tag() {
+        let data = TempDir::new();
+        let out = TempDir::new();
+        let export = notes(data.path());
+        write(
+            &export,
+            data.path(),
+            Grouping::Tag,
+            &mut DirSink(out.path().to_path_buf()),
+        )
+        .unwrap();
+
+        let work = read(out.path(), "tags/work.md");
+        assert!(work.starts_with("# #work\n"), "{}", work);
+        assert!(work.contains(" (archived)\n\n#work first\n"), "{}", work);
+        assert!(!work.contains("trashed"), "{}", work);
+        let sub = read(out.path(), "tags/work/sub.md");
+        assert!(sub.contains("\n#work/sub second\n"), "{}", sub);
+
+        // Attachments are linked relative to the Markdown file they are in
+        let untagged = read(out.path(), UNTAGGED_NAME);
+        assert!(
+            untagged.contains("\n![](images/abcd.png)\n"),
+            "{}",
+            untagged
+        );
+        assert!(
+            untagged.contains("[a.txt](<files/cdef/a.txt>)"),
+            "{}",
+            untagged
+        );
+        assert_eq!(read(out.path(), "files/cdef/a.txt"), "text");
+    }
+}
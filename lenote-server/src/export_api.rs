@@ -0,0 +1,43 @@
+use crate::export::{self, Grouping};
+use crate::file_store;
+use crate::{AppState, Rejection};
+use actix_files::NamedFile;
+use actix_web::{web, HttpRequest, HttpResponse, Result as WebResult};
+use serde::Deserialize;
+use std::fs::File;
+
+#[derive(Deserialize)]
+pub struct ExportFilter {
+    /// `day` or `tag`
+    pub by: Option<String>,
+}
+
+/// Sends an export of all notes and tags as a `.tar.zst` archive.
+/// The archive is written to a temporary file first, then streamed from it.
+pub async fn http_export(
+    req: HttpRequest,
+    ctx: web::Data<AppState>,
+    filter: web::Query<ExportFilter>,
+) -> WebResult<HttpResponse> {
+    let grouping = match filter.by.as_deref().unwrap_or("day").parse::<Grouping>() {
+        Ok(grouping) => grouping,
+        Err(_) => return Err(Rejection::BadRequest("Export by day or tag").into()),
+    };
+
+    let export = ctx.db.read(|conn| export::collect(conn)).await?;
+    let file_name = format!("lenote-export-{}.tar.zst", export.exported_at);
+    let data = ctx.config.data.clone();
+    let file = web::block(move || -> Result<File, failure::Error> {
+        let path = file_store::new_temp_path(&data)?;
+        let result = export::write_archive(&export, &data, grouping, &path)
+            .and_then(|()| Ok(File::open(&path)?));
+        // The open file can still be read once its name is removed
+        file_store::remove(&path);
+        result
+    })
+    .await?;
+
+    NamedFile::from_file(file, file_name)?
+        .set_content_type("application/zstd".parse().unwrap())
+        .into_response(&req)
+}
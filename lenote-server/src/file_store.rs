@@ -0,0 +1,188 @@
+//! Content-addressed storage of attached files, and the uploads shared with the image store.
+//!
+//! An attached file is stored once, as `res/files/<ab>/<sha256>` where `ab` are
+//! the first two characters of the hash. Files are stored without an extension,
+//! so they are only ever served as downloads; their names live in the notes.
+//! Like images, the `blobs` table counts the notes using each file, and a file
+//! is removed once no note refers to it anymore.
+
+use crate::database;
+use crate::image_store;
+use lenote_common::files::FILES_PATH;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::prelude::*;
+
+// How long an uploaded file is kept while no note refers to it
+const UNUSED_FILE_TTL: i64 = 24 * 60 * 60;
+
+/// Maps a path under `/res` to its file in the data directory
+pub fn resource_file(data: &Path, url_path: &str) -> PathBuf {
+    data.join(url_path.trim_start_matches('/'))
+}
+
+pub fn new_temp_path(data: &Path) -> io::Result<PathBuf> {
+    let temp_dir = data.join("tmp");
+    fs::create_dir_all(&temp_dir)?;
+    Ok(temp_dir.join(format!("{:016x}", rand::random::<u64>())))
+}
+
+pub fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Failed to remove {}: {}", path.display(), e);
+    }
+}
+
+/// The path file notes refer to the file with the given hash by
+pub fn url_path(hash: &str) -> String {
+    format!("{}{}/{}", FILES_PATH, &hash[..2], hash)
+}
+
+/// Maps the path a file note refers to back to its file.
+/// Returns None for anything that does not look like a path we generated.
+pub fn file_path(data: &Path, url_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(url_path.strip_prefix(FILES_PATH)?);
+    let components: Vec<Component> = relative.components().collect();
+    let valid =
+        components.len() == 2 && components.iter().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        return None;
+    }
+
+    Some(resource_file(data, url_path))
+}
+
+/// An upload being written to a temporary file, outside of the files served under `res`
+pub struct BlobWriter {
+    file: File,
+    temp_path: PathBuf,
+    size: u64,
+}
+
+impl BlobWriter {
+    pub async fn create(data: &Path) -> io::Result<Self> {
+        let temp_path = new_temp_path(data)?;
+        Ok(Self {
+            file: File::create(&temp_path).await?,
+            temp_path,
+            size: 0,
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.size += chunk.len() as u64;
+        self.file.write_all(chunk).await
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Ends the upload, returning the path of its temporary file
+    pub async fn finish(mut self) -> io::Result<PathBuf> {
+        self.file.flush().await?;
+        Ok(self.temp_path)
+    }
+
+    pub async fn discard(self) {
+        drop(self.file);
+        remove(&self.temp_path);
+    }
+}
+
+/// A file ready to be added to the store, in a temporary file
+pub struct PreparedFile {
+    pub hash: String,
+    pub size: u64,
+    temp_path: PathBuf,
+}
+
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Prepares a finished upload for the store, taking over its temporary file
+pub fn prepare_upload(temp_path: PathBuf) -> io::Result<PreparedFile> {
+    match hash_file(&temp_path) {
+        Ok(hash) => Ok(PreparedFile {
+            hash,
+            size: fs::metadata(&temp_path)?.len(),
+            temp_path,
+        }),
+        Err(e) => {
+            remove(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+/// Copies a file to prepare it for the store
+pub fn prepare_file(data: &Path, source: &Path) -> io::Result<PreparedFile> {
+    let temp_path = new_temp_path(data)?;
+    if let Err(e) = fs::copy(source, &temp_path) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    prepare_upload(temp_path)
+}
+
+impl PreparedFile {
+    pub fn url_path(&self) -> String {
+        url_path(&self.hash)
+    }
+
+    /// Moves the file into the store, or drops it if the same file is already there.
+    /// Returns whether the file was added.
+    pub fn place(self, data: &Path) -> io::Result<bool> {
+        let target = resource_file(data, &self.url_path());
+        if target.exists() {
+            remove(&self.temp_path);
+            return Ok(false);
+        }
+
+        fs::create_dir_all(target.parent().unwrap())?;
+        fs::rename(&self.temp_path, &target)?;
+        Ok(true)
+    }
+
+    pub fn discard(self) {
+        remove(&self.temp_path);
+    }
+}
+
+/// Removes a stored image or file no note refers to anymore
+pub fn remove_stored(data: &Path, url_path: &str) {
+    if let Some(file_path) = file_path(data, url_path) {
+        info!("Removing file {}", file_path.display());
+        remove(&file_path);
+    } else {
+        image_store::remove_file(data, url_path);
+    }
+}
+
+/// Removes the images and files that were uploaded but never used in a note,
+/// along with whatever uploads were left unfinished
+pub fn remove_unused<Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    data: &Path,
+) -> Result<usize, failure::Error> {
+    let unused = database::take_unused_blobs(conn, database::now() - UNUSED_FILE_TTL)?;
+    for url_path in &unused {
+        remove_stored(data, url_path);
+    }
+
+    let temp_dir = data.join("tmp");
+    if temp_dir.exists() {
+        fs::remove_dir_all(&temp_dir)?;
+    }
+
+    Ok(unused.len())
+}
@@ -0,0 +1,420 @@
+//! Content-addressed storage of image files.
+//!
+//! An image is stored once, as `res/images/<ab>/<sha256>.<ext>` where `ab` are
+//! the first two characters of the hash, and its extension comes from the format
+//! sniffed from its content rather than from anything the client claims.
+//! Before being stored, an image is stripped of its metadata, such as EXIF and GPS tags,
+//! and scaled down into thumbnails under `res/thumbs`.
+//! The `blobs` table counts the notes using each file, and a file is removed
+//! once no note refers to it anymore. To keep those counts in line with the files,
+//! files are only added to and removed from the store on the writer connection.
+
+use crate::file_store::{new_temp_path, remove, resource_file};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageReader, Limits};
+use img_parts::{jpeg::Jpeg, png::Png, webp::WebP, Bytes, ImageEXIF};
+use lenote_common::images::{thumbnail_path, IMAGES_PATH, THUMBNAIL_SIZES};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Cursor;
+use std::path::{Component, Path, PathBuf};
+
+// Largest width and height of images we are willing to decode
+const MAX_DIMENSION: u32 = 16384;
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+    Bmp,
+}
+
+impl ImageFormat {
+    /// Tells the format of an image from its first bytes
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(Self::Png)
+        } else if header.starts_with(b"\xff\xd8\xff") {
+            Some(Self::Jpeg)
+        } else if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+            Some(Self::Gif)
+        } else if header.len() >= 12 && &header[..4] == b"RIFF" && &header[8..12] == b"WEBP" {
+            Some(Self::Webp)
+        } else if header.starts_with(b"BM") {
+            Some(Self::Bmp)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Png => "png",
+            Self::Jpeg => "jpg",
+            Self::Gif => "gif",
+            Self::Webp => "webp",
+            Self::Bmp => "bmp",
+        }
+    }
+
+    fn codec(self) -> image::ImageFormat {
+        match self {
+            Self::Png => image::ImageFormat::Png,
+            Self::Jpeg => image::ImageFormat::Jpeg,
+            Self::Gif => image::ImageFormat::Gif,
+            Self::Webp => image::ImageFormat::WebP,
+            Self::Bmp => image::ImageFormat::Bmp,
+        }
+    }
+}
+
+/// An image identified by its content
+pub struct ImageBlob {
+    pub hash: String,
+    pub format: ImageFormat,
+}
+
+impl ImageBlob {
+    /// The path image notes refer to the image by
+    pub fn url_path(&self) -> String {
+        format!(
+            "{}{}/{}.{}",
+            IMAGES_PATH,
+            &self.hash[..2],
+            self.hash,
+            self.format.extension()
+        )
+    }
+}
+
+/// Maps the path an image note refers to back to its file, including the ones stored
+/// directly under `res/images` before the store was content-addressed.
+/// Returns None for anything that does not look like a path we generated.
+pub fn file_path(data: &Path, url_path: &str) -> Option<PathBuf> {
+    let relative = Path::new(url_path.strip_prefix(IMAGES_PATH)?);
+    let components: Vec<Component> = relative.components().collect();
+    let valid = (1..=2).contains(&components.len())
+        && components.iter().all(|c| matches!(c, Component::Normal(_)));
+    if !valid {
+        return None;
+    }
+
+    Some(resource_file(data, url_path))
+}
+
+/// Lists the thumbnail files of an image, along with their sizes
+fn thumbnail_files(data: &Path, url_path: &str) -> Vec<(u32, PathBuf)> {
+    THUMBNAIL_SIZES
+        .iter()
+        .filter_map(|&size| Some((size, resource_file(data, &thumbnail_path(url_path, size)?))))
+        .collect()
+}
+
+fn is_jpeg_file(path: &Path) -> bool {
+    path.extension().unwrap_or_default() == "jpg"
+}
+
+fn decode(bytes: &[u8], format: ImageFormat) -> Option<(DynamicImage, Orientation)> {
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format.codec());
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    reader.limits(limits);
+
+    let mut decoder = reader.into_decoder().ok()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let image = DynamicImage::from_decoder(decoder).ok()?;
+    Some((image, orientation))
+}
+
+fn encode(image: &DynamicImage, jpeg: bool) -> Result<Vec<u8>, failure::Error> {
+    let mut buf = vec![];
+    if jpeg {
+        let encoder = JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY);
+        image.to_rgb8().write_with_encoder(encoder)?;
+    } else {
+        image.write_with_encoder(PngEncoder::new(&mut buf))?;
+    }
+
+    Ok(buf)
+}
+
+/// Drops the metadata of an image without touching its pixels.
+/// Returns None if the image could not be taken apart.
+fn strip_metadata(bytes: Vec<u8>, format: ImageFormat) -> Option<Vec<u8>> {
+    let bytes = Bytes::from(bytes);
+    let stripped = match format {
+        ImageFormat::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(bytes).ok()?;
+            // APP1 holds EXIF and XMP, APP13 holds IPTC, followed by comments
+            for marker in &[0xe1, 0xed, 0xfe] {
+                jpeg.remove_segments_by_marker(*marker);
+            }
+            jpeg.encoder().bytes()
+        }
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(bytes).ok()?;
+            for kind in &[*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"] {
+                png.remove_chunks_by_type(*kind);
+            }
+            png.encoder().bytes()
+        }
+        ImageFormat::Webp => {
+            let mut webp = WebP::from_bytes(bytes).ok()?;
+            webp.remove_chunks_by_id(*b"XMP ");
+            webp.set_exif(None);
+            webp.encoder().bytes()
+        }
+        // Neither carries EXIF data
+        ImageFormat::Gif | ImageFormat::Bmp => bytes,
+    };
+
+    Some(stripped.to_vec())
+}
+
+/// Writes a thumbnail to a temporary file, and returns its path
+fn write_thumbnail(
+    data: &Path,
+    image: &DynamicImage,
+    size: u32,
+    jpeg: bool,
+) -> Result<PathBuf, failure::Error> {
+    let thumbnail = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image.clone()
+    };
+
+    let temp_path = new_temp_path(data)?;
+    if let Err(e) = encode(&thumbnail, jpeg).and_then(|bytes| Ok(fs::write(&temp_path, bytes)?)) {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    Ok(temp_path)
+}
+
+/// An image ready to be added to the store, along with its thumbnails, in temporary files
+pub struct PreparedImage {
+    pub blob: ImageBlob,
+    temp_path: PathBuf,
+    thumbnails: Vec<PathBuf>,
+}
+
+/// Takes an image apart to store it: strips its metadata, applies its orientation,
+/// and writes it with its thumbnails to temporary files.
+/// Returns None if the content is not an image we can read.
+fn prepare(data: &Path, bytes: Vec<u8>) -> Result<Option<PreparedImage>, failure::Error> {
+    let format = match ImageFormat::sniff(&bytes) {
+        Some(format) => format,
+        None => return Ok(None),
+    };
+
+    let (mut image, orientation) = match decode(&bytes, format) {
+        Some(decoded) => decoded,
+        None => return Ok(None),
+    };
+
+    // A rotated photo would show sideways once stripped of its orientation tag,
+    // so its pixels are turned instead
+    let stripped = if orientation == Orientation::NoTransforms {
+        strip_metadata(bytes, format).map(|bytes| (bytes, format))
+    } else {
+        None
+    };
+    let (bytes, format) = match stripped {
+        Some(stripped) => stripped,
+        None => {
+            image.apply_orientation(orientation);
+            let jpeg = format == ImageFormat::Jpeg;
+            let format = if jpeg { format } else { ImageFormat::Png };
+            (encode(&image, jpeg)?, format)
+        }
+    };
+
+    let blob = ImageBlob {
+        hash: hex::encode(Sha256::digest(&bytes)),
+        format,
+    };
+    let temp_path = new_temp_path(data)?;
+    let mut prepared = PreparedImage {
+        thumbnails: vec![],
+        temp_path: temp_path.clone(),
+        blob,
+    };
+
+    let url_path = prepared.blob.url_path();
+    let result = fs::write(&temp_path, &bytes)
+        .map_err(failure::Error::from)
+        .and_then(|_| {
+            for (size, file) in thumbnail_files(data, &url_path) {
+                let thumbnail = write_thumbnail(data, &image, size, is_jpeg_file(&file))?;
+                prepared.thumbnails.push(thumbnail);
+            }
+            Ok(())
+        });
+    if let Err(e) = result {
+        prepared.discard();
+        return Err(e);
+    }
+
+    Ok(Some(prepared))
+}
+
+/// Reads a file to prepare it for the store, see `prepare`
+pub fn prepare_file(data: &Path, source: &Path) -> Result<Option<PreparedImage>, failure::Error> {
+    prepare(data, fs::read(source)?)
+}
+
+impl PreparedImage {
+    /// Moves the image into the store, or drops it if the same image is already there.
+    /// Returns whether the image was added.
+    pub fn place(self, data: &Path) -> Result<bool, failure::Error> {
+        let url_path = self.blob.url_path();
+        let target = resource_file(data, &url_path);
+        let added = !target.exists();
+
+        // Images stored before thumbnails were made may still lack them
+        let targets = thumbnail_files(data, &url_path)
+            .into_iter()
+            .map(|(_, file)| file);
+        let moves = std::iter::once((self.temp_path, target))
+            .chain(self.thumbnails.into_iter().zip(targets));
+        for (temp_path, target) in moves {
+            if target.exists() {
+                remove(&temp_path);
+            } else {
+                fs::create_dir_all(target.parent().unwrap())?;
+                fs::rename(&temp_path, &target)?;
+            }
+        }
+
+        Ok(added)
+    }
+
+    pub fn discard(self) {
+        if self.temp_path.exists() {
+            remove(&self.temp_path);
+        }
+        for thumbnail in &self.thumbnails {
+            remove(thumbnail);
+        }
+    }
+}
+
+/// Prepares a finished upload for the store, see `prepare`, and removes its temporary file
+pub fn prepare_upload(
+    data: &Path,
+    temp_path: &Path,
+) -> Result<Option<PreparedImage>, failure::Error> {
+    let result = prepare_file(data, temp_path);
+    remove(temp_path);
+    result
+}
+
+/// Removes the file of an image no note refers to anymore, along with its thumbnails
+pub fn remove_file(data: &Path, url_path: &str) {
+    if let Some(file_path) = file_path(data, url_path) {
+        info!("Removing image file {}", file_path.display());
+        remove(&file_path);
+        for (_, thumbnail) in thumbnail_files(data, url_path) {
+            if thumbnail.exists() {
+                remove(&thumbnail);
+            }
+        }
+    }
+}
+
+/// Makes the thumbnails missing for a stored image, such as one stored before thumbnails
+/// were made. The image itself is left untouched. Returns false if there were none missing.
+fn make_missing_thumbnails(data: &Path, url_path: &str) -> Result<bool, failure::Error> {
+    let missing: Vec<(u32, PathBuf)> = thumbnail_files(data, url_path)
+        .into_iter()
+        .filter(|(_, file)| !file.exists())
+        .collect();
+    let source = match file_path(data, url_path) {
+        Some(source) if !missing.is_empty() => source,
+        _ => return Ok(false),
+    };
+
+    let bytes = fs::read(&source)?;
+    let decoded = ImageFormat::sniff(&bytes).and_then(|format| decode(&bytes, format));
+    let (mut image, orientation) =
+        decoded.ok_or_else(|| failure::format_err!("Not an image we can read"))?;
+    image.apply_orientation(orientation);
+
+    for (size, file) in missing {
+        let thumbnail = write_thumbnail(data, &image, size, is_jpeg_file(&file))?;
+        fs::create_dir_all(file.parent().unwrap())?;
+        fs::rename(&thumbnail, &file)?;
+    }
+
+    Ok(true)
+}
+
+/// Makes the thumbnails missing for any of the stored images at `url_paths`.
+/// Returns how many images got new thumbnails.
+pub fn make_all_missing_thumbnails(data: &Path, url_paths: &[String]) -> usize {
+    let mut made = 0;
+    for url_path in url_paths {
+        match make_missing_thumbnails(data, url_path) {
+            Ok(true) => made += 1,
+            Ok(false) => {}
+            Err(e) => warn!("Failed to make the thumbnails of {}: {}", url_path, e),
+        }
+    }
+
+    made
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_the_images_it_can_decode() {
+        let formats = [
+            ImageFormat::Png,
+            ImageFormat::Jpeg,
+            ImageFormat::Gif,
+            ImageFormat::Webp,
+            ImageFormat::Bmp,
+        ];
+        for &format in &formats {
+            let mut encoded = Cursor::new(vec![]);
+            DynamicImage::new_rgb8(2, 2)
+                .write_to(&mut encoded, format.codec())
+                .unwrap();
+            assert_eq!(ImageFormat::sniff(encoded.get_ref()), Some(format));
+        }
+    }
+
+    #[test]
+    fn sniffs_only_the_start_of_the_content() {
+        assert_eq!(ImageFormat::sniff(b"GIF87a..."), Some(ImageFormat::Gif));
+        assert_eq!(
+            ImageFormat::sniff(b"RIFF\0\0\0\0WEBPVP8 "),
+            Some(ImageFormat::Webp)
+        );
+
+        let not_images: &[&[u8]] = &[
+            b"",
+            b"\x89PNG",
+            b"\xff\xd8",
+            b"GIF90a",
+            // A RIFF file of another kind, and one cut short
+            b"RIFF\0\0\0\0WAVEfmt ",
+            b"RIFF\0\0\0\0WEB",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+            b"hello \x89PNG\r\n\x1a\n",
+        ];
+        for header in not_images {
+            assert_eq!(ImageFormat::sniff(header), None, "{:?}", header);
+        }
+    }
+}
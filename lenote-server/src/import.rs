@@ -0,0 +1,674 @@
+//! Import of notes from an export, or from a directory of Markdown and text files.
+//!
+//! Imported notes keep their timestamps but get new IDs, and their tags are parsed
+//! from their text again. The image and attached files they refer to are added
+//! to the store, unless already there. All changes to the database are made in one transaction.
+
+use crate::database;
+use crate::export;
+use crate::file_store::{self, PreparedFile};
+use crate::image_store::{self, PreparedImage};
+use crate::tag_api;
+use lenote_common::files::{clean_file_name, FileAttachment};
+use lenote_common::models::*;
+use lenote_common::tags::parse_tags;
+use regex::Regex;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const TEXT_EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// A note read from the source, along with what belongs to it there
+struct SourceNote {
+    /// Where the note was read from, for the report
+    origin: String,
+    note: Note,
+    deleted_at: Option<i64>,
+    revisions: Vec<NoteRevision>,
+    /// Tag maps of the note, to keep the ones that were archived
+    maps: Vec<TagMap>,
+    history: Vec<TagMap>,
+    blobs: Vec<SourceBlob>,
+}
+
+/// An image or attached file prepared for the store
+enum PreparedBlob {
+    Image(PreparedImage),
+    File(PreparedFile),
+}
+
+impl PreparedBlob {
+    fn url_path(&self) -> String {
+        match self {
+            Self::Image(image) => image.blob.url_path(),
+            Self::File(file) => file.url_path(),
+        }
+    }
+
+    fn place(self, data: &Path) -> Result<bool, failure::Error> {
+        match self {
+            Self::Image(image) => image.place(data),
+            Self::File(file) => Ok(file.place(data)?),
+        }
+    }
+
+    fn discard(self) {
+        match self {
+            Self::Image(image) => image.discard(),
+            Self::File(file) => file.discard(),
+        }
+    }
+}
+
+/// A file a note refers to
+struct SourceBlob {
+    /// How the note refers to the file in the source
+    link: String,
+    blob: PreparedBlob,
+}
+
+/// Reads an image file of the source, returning None if it is not an image
+fn read_image(data: &Path, link: &str, file: &Path) -> Result<Option<SourceBlob>, failure::Error> {
+    Ok(
+        image_store::prepare_file(data, file)?.map(|image| SourceBlob {
+            link: link.to_string(),
+            blob: PreparedBlob::Image(image),
+        }),
+    )
+}
+
+struct Source {
+    notes: Vec<SourceNote>,
+    /// Tags whose display and color are kept when they are new to the database
+    tags: HashMap<String, Tag>,
+    skipped: Vec<String>,
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub notes: usize,
+    pub trashed_notes: usize,
+    pub revisions: usize,
+    pub images: usize,
+    pub files: usize,
+    pub new_tags: Vec<String>,
+    /// What was left out of the import, and why
+    pub skipped: Vec<String>,
+}
+
+fn new_note(text: String, timestamp: i64, note_type: NoteType) -> Note {
+    Note {
+        id: 0,
+        client_id: String::new(),
+        text,
+        timestamp,
+        note_type,
+        tags: HashSet::new(),
+    }
+}
+
+fn read_export(dir: &Path, data: &Path) -> Result<Source, failure::Error> {
+    let export: Export = serde_json::from_slice(&fs::read(dir.join(export::JSON_NAME))?)?;
+
+    let mut maps: HashMap<i64, Vec<TagMap>> = HashMap::new();
+    for map in export.tags.iter().flat_map(|t| t.maps.iter()) {
+        maps.entry(map.note_id).or_default().push(map.clone());
+    }
+
+    let mut history: HashMap<i64, Vec<TagMap>> = HashMap::new();
+    for map in export.tag_history {
+        history.entry(map.note_id).or_default().push(map);
+    }
+
+    let mut revisions: HashMap<i64, Vec<NoteRevision>> = HashMap::new();
+    for revision in export.revisions {
+        revisions
+            .entry(revision.note_id)
+            .or_default()
+            .push(revision);
+    }
+
+    let mut all_notes: Vec<(Note, Option<i64>)> = export
+        .notes
+        .into_iter()
+        .map(|n| (n, None))
+        .chain(
+            export
+                .trashed_notes
+                .into_iter()
+                .map(|t| (t.note, Some(t.deleted_at))),
+        )
+        .collect();
+    // Keeps the order of the notes, trashed or not
+    all_notes.sort_by_key(|(n, _)| n.id);
+
+    let mut notes = vec![];
+    let mut skipped = vec![];
+    for (note, deleted_at) in all_notes {
+        let origin = format!("note {}", note.id);
+        let mut blobs = vec![];
+        if note.note_type == NoteType::Image {
+            let file_name = note.text.rsplit('/').next().unwrap_or_default();
+            let source = dir.join(export::IMAGES_DIR).join(file_name);
+            if file_name.is_empty() || !source.is_file() {
+                skipped.push(format!("{}: missing image file {}", origin, note.text));
+                continue;
+            }
+
+            match read_image(data, &note.text, &source)? {
+                Some(image) => blobs.push(image),
+                None => {
+                    skipped.push(format!("{}: not an image {}", origin, note.text));
+                    continue;
+                }
+            }
+        }
+
+        if note.note_type == NoteType::File {
+            // The file is found by the hash at the end of its path, and the name it was saved as
+            let source = FileAttachment::from_text(&note.text).and_then(|file| {
+                let hash = file.path.rsplit('/').next().unwrap_or_default();
+                let name = clean_file_name(&file.name);
+                let source = dir.join(export::FILES_DIR).join(hash).join(name);
+                let valid = !hash.is_empty() && !hash.starts_with('.') && source.is_file();
+                Some(source).filter(|_| valid)
+            });
+            let source = match source {
+                Some(source) => source,
+                None => {
+                    skipped.push(format!("{}: missing file {}", origin, note.text));
+                    continue;
+                }
+            };
+
+            blobs.push(SourceBlob {
+                link: note.text.clone(),
+                blob: PreparedBlob::File(file_store::prepare_file(data, &source)?),
+            });
+        }
+
+        notes.push(SourceNote {
+            origin,
+            deleted_at,
+            revisions: revisions.remove(&note.id).unwrap_or_default(),
+            maps: maps.remove(&note.id).unwrap_or_default(),
+            history: history.remove(&note.id).unwrap_or_default(),
+            blobs,
+            note: new_note(note.text, note.timestamp, note.note_type),
+        });
+    }
+
+    Ok(Source {
+        notes,
+        tags: export
+            .tags
+            .into_iter()
+            .map(|t| (t.tag.clone(), t))
+            .collect(),
+        skipped,
+    })
+}
+
+fn list_text_files(dir: &Path, result: &mut Vec<PathBuf>) -> Result<(), failure::Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Skips hidden files and directories such as `.git`
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+
+        let path = entry.path();
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        if path.is_dir() {
+            list_text_files(&path, result)?;
+        } else if TEXT_EXTENSIONS.contains(&extension.as_ref()) {
+            result.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// The file a relative link in a file of the directory `base` points to,
+/// as long as it is inside the canonical directory `dir`
+fn linked_file(dir: &Path, base: &Path, link: &str) -> Option<PathBuf> {
+    if link.contains("://") || link.starts_with('/') {
+        return None;
+    }
+
+    let source = base.join(link).canonicalize().ok()?;
+    Some(source).filter(|s| s.starts_with(dir) && s.is_file())
+}
+
+/// Reads each file as a note, timestamped with the time it was last modified
+fn read_text_files(dir: &Path, data: &Path) -> Result<Source, failure::Error> {
+    lazy_static! {
+        static ref IMAGE_LINK_RE: Regex = Regex::new(r"!\[[^\]]*\]\(([^)\s]+)\)").unwrap();
+    }
+
+    // Links are checked against the canonical path, with `..` and symbolic links resolved
+    let dir = dir.canonicalize()?;
+    let mut files = vec![];
+    list_text_files(&dir, &mut files)?;
+    files.sort();
+
+    let mut notes = vec![];
+    let mut skipped = vec![];
+    for path in files {
+        let origin = path
+            .strip_prefix(&dir)
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text.trim_end().to_string(),
+            Err(e) => {
+                skipped.push(format!("{}: {}", origin, e));
+                continue;
+            }
+        };
+
+        if text.trim().is_empty() {
+            skipped.push(format!("{}: empty", origin));
+            continue;
+        }
+
+        let timestamp = fs::metadata(&path)?
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+
+        // Only images inside the directory are copied, links to anything else are kept as they are
+        let base = path.parent().unwrap_or(&dir);
+        let mut blobs: Vec<SourceBlob> = vec![];
+        for caps in IMAGE_LINK_RE.captures_iter(&text) {
+            let link = &caps[1];
+            if blobs.iter().any(|i| i.link == link) {
+                continue;
+            }
+            if let Some(source) = linked_file(&dir, base, link) {
+                blobs.extend(read_image(data, link, &source)?);
+            }
+        }
+
+        notes.push(SourceNote {
+            origin,
+            deleted_at: None,
+            revisions: vec![],
+            maps: vec![],
+            history: vec![],
+            blobs,
+            note: new_note(text, timestamp, NoteType::Text),
+        });
+    }
+
+    // IDs are given in this order, which is the order notes are listed in
+    notes.sort_by_key(|n| n.note.timestamp);
+
+    Ok(Source {
+        notes,
+        tags: HashMap::new(),
+        skipped,
+    })
+}
+
+/// Saves the notes, recording the images and files to add to the store into `blobs`.
+/// Text notes keep the images they link to for good, as their links are not tracked.
+fn save_notes(
+    tx: &mut rusqlite::Transaction,
+    source: Source,
+    colors: &[String],
+    blobs: &mut Vec<PreparedBlob>,
+    report: &mut ImportReport,
+) -> Result<(), failure::Error> {
+    for mut source_note in source.notes {
+        let mut note = source_note.note;
+        for source_blob in source_note.blobs.drain(..) {
+            let new_link = source_blob.blob.url_path();
+            database::save_blob(tx, &new_link)?;
+            database::add_blob_ref(tx, &new_link)?;
+
+            note.text = match (note.note_type, &source_blob.blob) {
+                (NoteType::Image, _) => new_link,
+                (NoteType::File, PreparedBlob::File(file)) => {
+                    let name = FileAttachment::from_text(&note.text)
+                        .map(|f| clean_file_name(&f.name))
+                        .unwrap_or_default();
+                    report.files += 1;
+                    FileAttachment {
+                        path: new_link,
+                        name,
+                        size: file.size,
+                    }
+                    .to_text()
+                }
+                _ => note.text.replace(
+                    &format!("]({})", source_blob.link),
+                    &format!("]({})", new_link),
+                ),
+            };
+            if let PreparedBlob::Image(_) = source_blob.blob {
+                report.images += 1;
+            }
+            blobs.push(source_blob.blob);
+        }
+
+        let mut new_tags = vec![];
+        let mut tags: Vec<(String, String)> = parse_tags(&note.text).into_iter().collect();
+        tags.sort();
+        for (tag, display) in tags {
+            if !database::tag_exists(tx, &tag)? {
+                // Keeps how the tag was written and colored in the source
+                if let Some(source_tag) = source.tags.get(&tag) {
+                    new_tags.push(Tag {
+                        maps: vec![],
+                        ..source_tag.clone()
+                    });
+                }
+                report.new_tags.push(display);
+            }
+        }
+        database::save_tags(&new_tags, tx)?;
+
+        note.id = database::save_note(&note, tx)?;
+        let (note, _) = tag_api::save_tags_for_note(note, colors, tx)?;
+
+        source_note.maps.retain(|m| note.tags.contains(&m.tag));
+        for map in source_note.maps {
+            if map.status != TagMapStatus::Active {
+                database::set_tag_map_status(tx, &map.tag, note.id, map.status)?;
+            }
+        }
+
+        source_note.history.retain(|m| note.tags.contains(&m.tag));
+        for map in source_note.history {
+            database::save_tag_map_history(tx, &map.tag, note.id, map.status, map.timestamp)?;
+        }
+
+        for revision in &source_note.revisions {
+            database::save_note_revision_at(tx, note.id, &revision.text, revision.timestamp)?;
+        }
+
+        if let Some(deleted_at) = source_note.deleted_at {
+            database::trash_note_at(tx, note.id, deleted_at)?;
+            report.trashed_notes += 1;
+        }
+
+        info!("Imported {} as note {}", source_note.origin, note.id);
+        report.notes += 1;
+        report.revisions += source_note.revisions.len();
+    }
+
+    Ok(())
+}
+
+/// Imports the export or directory of text files at `source` into the data directory `data`.
+/// With `dry_run`, nothing is changed and the report tells what would have been imported.
+pub fn import(
+    conn: &mut Connection,
+    data: &Path,
+    source: &Path,
+    colors: &[String],
+    dry_run: bool,
+) -> Result<ImportReport, failure::Error> {
+    let source = if source.join(export::JSON_NAME).is_file() {
+        read_export(source, data)?
+    } else {
+        read_text_files(source, data)?
+    };
+
+    let mut report = ImportReport {
+        skipped: source.skipped.clone(),
+        ..Default::default()
+    };
+
+    let mut tx = database::tx(conn)?;
+    let mut blobs = vec![];
+    save_notes(&mut tx, source, colors, &mut blobs, &mut report)?;
+    if dry_run {
+        blobs.into_iter().for_each(PreparedBlob::discard);
+        // Dropping the transaction rolls it back
+        return Ok(report);
+    }
+
+    // The files are stored before committing, so that no note is left without its file.
+    // Only the files new to the store are removed if anything fails.
+    let mut added = vec![];
+    let mut blobs = blobs.into_iter();
+    let result = blobs
+        .try_for_each(|blob| {
+            let url_path = blob.url_path();
+            if blob.place(data)? {
+                added.push(url_path);
+            }
+            Ok::<_, failure::Error>(())
+        })
+        .and_then(|_| database::commit(tx));
+
+    if result.is_err() {
+        blobs.for_each(PreparedBlob::discard);
+        for url_path in added {
+            file_store::remove_stored(data, &url_path);
+        }
+    }
+
+    result.map(|_| report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_pool::open_test_db;
+    use crate::export::{DirSink, Grouping};
+    use crate::paging::Cursor;
+    use crate::testing::TempDir;
+    use image::{DynamicImage, ImageFormat};
+    use rusqlite::NO_PARAMS;
+
+    fn colors() -> Vec<String> {
+        vec![String::from("#000000")]
+    }
+
+    fn add_note(conn: &mut Connection, note_type: NoteType, text: &str, timestamp: i64) -> i64 {
+        let mut tx = database::tx(conn).unwrap();
+        let mut note = new_note(text.to_string(), timestamp, note_type);
+        note.id = database::save_note(&note, &mut tx).unwrap();
+        let (note, _) = tag_api::save_tags_for_note(note, &colors(), &mut tx).unwrap();
+        database::commit(tx).unwrap();
+        note.id
+    }
+
+    fn note_count(conn: &Connection) -> i64 {
+        conn.query_row("SELECT COUNT(*) FROM notes", NO_PARAMS, |row| row.get(0))
+            .unwrap()
+    }
+
+    fn png() -> Vec<u8> {
+        let mut encoded = std::io::Cursor::new(vec![]);
+        DynamicImage::new_rgb8(2, 2)
+            .write_to(&mut encoded, ImageFormat::Png)
+            .unwrap();
+        encoded.into_inner()
+    }
+
+    /// Adds a file with `contents` to the store of `data`, as a note would refer to it
+    fn store_file(data: &Path, contents: &[u8]) -> FileAttachment {
+        let source = data.join("source");
+        fs::write(&source, contents).unwrap();
+        let file = file_store::prepare_file(data, &source).unwrap();
+        let attachment = FileAttachment {
+            path: file.url_path(),
+            name: String::from("a.txt"),
+            size: file.size,
+        };
+        assert!(file.place(data).unwrap());
+        fs::remove_file(source).unwrap();
+        attachment
+    }
+
+    fn export_to(conn: &mut Connection, data: &Path, out: &Path) {
+        let export = export::collect(conn).unwrap();
+        let mut sink = DirSink(out.to_path_buf());
+        export::write(&export, data, Grouping::Day, &mut sink).unwrap();
+    }
+
+    #[test]
+    fn imports_an_export_with_new_ids() {
+        let data = TempDir::new();
+        let out = TempDir::new();
+        let mut conn = open_test_db();
+        let first = add_note(&mut conn, NoteType::Text, "#work first", 100);
+        let second = add_note(&mut conn, NoteType::Text, "#work second", 200);
+        let trashed = add_note(&mut conn, NoteType::Text, "#work gone", 300);
+        database::save_note_revision_at(&mut &conn, second, "#work draft", 150).unwrap();
+        let archived = TagMap {
+            tag: String::from("#work"),
+            note_id: first,
+            status: TagMapStatus::Archived,
+            timestamp: 0,
+        };
+        assert!(database::save_tag_map(&mut &conn, "#work", &archived).unwrap());
+        assert!(database::save_tag_color(&mut &conn, "#work", "#123456").unwrap());
+        assert!(database::trash_note_at(&mut &conn, trashed, 400).unwrap());
+        export_to(&mut conn, data.path(), out.path());
+
+        let target = TempDir::new();
+        let mut imported = open_test_db();
+        add_note(&mut imported, NoteType::Text, "#other", 1);
+        let report = import(&mut imported, target.path(), out.path(), &colors(), false).unwrap();
+        assert_eq!(report.notes, 3);
+        assert_eq!(report.trashed_notes, 1);
+        assert_eq!(report.revisions, 1);
+        assert_eq!(report.new_tags, vec!["#work"]);
+
+        let db = &mut &imported;
+        let notes: Vec<_> = database::get_all_notes(db)
+            .unwrap()
+            .into_iter()
+            .map(|n| (n.id, n.text, n.timestamp))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![
+                (1, String::from("#other"), 1),
+                (first + 1, String::from("#work first"), 100),
+                (second + 1, String::from("#work second"), 200),
+            ]
+        );
+
+        let trash = database::get_trashed_notes(db).unwrap();
+        assert_eq!(trash.len(), 1);
+        assert_eq!(trash[0].note.id, trashed + 1);
+        assert_eq!(trash[0].note.text, "#work gone");
+        assert_eq!(trash[0].note.timestamp, 300);
+        assert_eq!(trash[0].deleted_at, 400);
+
+        let revisions = database::get_note_revisions(db, second + 1).unwrap();
+        assert_eq!(revisions.len(), 1);
+        assert_eq!(revisions[0].text, "#work draft");
+        assert_eq!(revisions[0].timestamp, 150);
+
+        let maps = database::get_tag_map(db, "#work", false, &Cursor::First, 10).unwrap();
+        let statuses: Vec<_> = maps.items.iter().map(|m| (m.note_id, m.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![
+                (second + 1, TagMapStatus::Active),
+                (first + 1, TagMapStatus::Archived)
+            ]
+        );
+        let history = database::get_tag_map_history(db).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].note_id, first + 1);
+        assert_eq!(
+            database::get_tag_color(db, "#work").unwrap().unwrap(),
+            "#123456"
+        );
+    }
+
+    #[test]
+    fn dry_runs_change_nothing() {
+        let source = TempDir::new();
+        fs::write(source.path().join("pic.png"), png()).unwrap();
+        fs::write(source.path().join("a.md"), "#idea ![](pic.png)").unwrap();
+
+        let data = TempDir::new();
+        let mut conn = open_test_db();
+        let report = import(&mut conn, data.path(), source.path(), &colors(), true).unwrap();
+        assert_eq!(report.notes, 1);
+        assert_eq!(report.images, 1);
+        assert_eq!(report.new_tags, vec!["#idea"]);
+
+        assert_eq!(note_count(&conn), 0);
+        assert!(database::get_blob_paths(&mut &conn).unwrap().is_empty());
+        assert!(!data.path().join("res").exists());
+        let temp_files = fs::read_dir(data.path().join("tmp")).unwrap().count();
+        assert_eq!(temp_files, 0);
+    }
+
+    #[test]
+    fn failing_to_store_a_file_removes_only_the_added_files() {
+        let data = TempDir::new();
+        let out = TempDir::new();
+        let mut conn = open_test_db();
+        let files: Vec<FileAttachment> = [b"a", b"b", b"c"]
+            .iter()
+            .map(|contents| store_file(data.path(), *contents))
+            .collect();
+        for file in &files {
+            add_note(&mut conn, NoteType::File, &file.to_text(), 1);
+        }
+        export_to(&mut conn, data.path(), out.path());
+
+        // The first file is already stored, and the last can't be as a file is in its way
+        let target = TempDir::new();
+        let stored = store_file(target.path(), b"a");
+        let added = file_store::resource_file(target.path(), &files[1].path);
+        let blocked = file_store::resource_file(target.path(), &files[2].path);
+        fs::create_dir_all(blocked.parent().unwrap().parent().unwrap()).unwrap();
+        fs::write(blocked.parent().unwrap(), "").unwrap();
+
+        let mut imported = open_test_db();
+        assert!(import(&mut imported, target.path(), out.path(), &colors(), false).is_err());
+        assert_eq!(note_count(&imported), 0);
+        assert!(file_store::resource_file(target.path(), &stored.path).is_file());
+        assert!(!added.exists());
+        assert!(!blocked.exists());
+    }
+
+    #[test]
+    fn imports_markdown_with_the_images_inside_the_directory() {
+        let root = TempDir::new();
+        let source = root.path().join("notes");
+        fs::create_dir_all(source.join("day/img")).unwrap();
+        fs::write(source.join("day/img/pic.png"), png()).unwrap();
+        fs::write(root.path().join("outside.png"), png()).unwrap();
+        let text = "#idea ![a](img/pic.png) ![b](../../outside.png) ![c](https://x/y.png)";
+        fs::write(source.join("day/a.md"), text).unwrap();
+        fs::write(source.join("empty.txt"), " \n").unwrap();
+
+        let data = TempDir::new();
+        let mut conn = open_test_db();
+        let report = import(&mut conn, data.path(), &source, &colors(), false).unwrap();
+        assert_eq!(report.notes, 1);
+        assert_eq!(report.images, 1);
+        assert_eq!(report.skipped, vec!["empty.txt: empty"]);
+
+        let db = &mut &conn;
+        let stored = database::get_blob_paths(db).unwrap();
+        assert_eq!(stored.len(), 1);
+        assert!(file_store::resource_file(data.path(), &stored[0]).is_file());
+
+        let notes = database::get_all_notes(db).unwrap();
+        assert_eq!(
+            notes[0].text,
+            text.replace("img/pic.png", &stored[0]),
+            "only the image inside the directory is stored"
+        );
+        let maps = database::get_tag_map(db, "#idea", false, &Cursor::First, 10).unwrap();
+        assert_eq!(maps.items.len(), 1);
+    }
+}
@@ -3,38 +3,145 @@ extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+mod auth;
+mod backup;
+mod commands;
 mod database;
+mod db_pool;
+mod events;
+mod export;
+mod export_api;
+mod file_store;
+mod image_store;
+mod import;
+mod migrations;
+mod multipart;
 mod note_api;
+mod paging;
+mod search_api;
 mod tag_api;
+#[cfg(test)]
+mod testing;
+mod token_api;
 
 use actix_files as fs;
 use actix_service::Service;
-use actix_web::{http, web};
-use actix_web::{App, HttpResponse, HttpServer, Result as WebResult};
-use clap::Arg;
+use actix_web::dev::Factory;
+use actix_web::{guard, http, web};
+use actix_web::{App, FromRequest, HttpResponse, HttpServer, Result as WebResult};
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
+use db_pool::DbPool;
+use futures::Future;
+use lenote_common::api::{self, Endpoint};
+use serde::Serialize;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+// Read-only connections in the pool, next to the single writer
+const DB_READER_COUNT: usize = 4;
+// Images and files are uploaded separately, so JSON bodies only hold note texts
+const JSON_LIMIT: usize = 1024 * 1024 * 10;
+const RES_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
 #[derive(Clone)]
 pub struct AppConfig {
     ui: PathBuf,
     pages: PathBuf,
     data: PathBuf,
     slow: bool,
+    /// Address the HTTP server listens on, such as `0.0.0.0` for every interface
+    bind: String,
     port: String,
+    /// Only sends the session cookie over HTTPS, e.g. behind a TLS proxy
+    secure_cookies: bool,
+    tag_colors: Vec<String>,
+    /// Largest image or file upload accepted, in bytes
+    max_upload_size: u64,
 }
 
 #[derive(Clone)]
 pub struct AppState {
     config: AppConfig,
-    db: Arc<Mutex<rusqlite::Connection>>,
+    db: DbPool,
+    events: events::Broadcaster,
+}
+
+/// Why a request was turned down
+#[derive(Debug)]
+pub enum Rejection {
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    BadRequest(&'static str),
+    Conflict(&'static str),
+    TooLarge(&'static str),
+}
+
+impl From<Rejection> for HttpResponse {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Unauthorized(reason) => HttpResponse::Unauthorized().body(reason),
+            Rejection::Forbidden(reason) => HttpResponse::Forbidden().body(reason),
+            Rejection::NotFound(reason) => HttpResponse::NotFound().body(reason),
+            Rejection::BadRequest(reason) => HttpResponse::BadRequest().body(reason),
+            Rejection::Conflict(reason) => HttpResponse::Conflict().body(reason),
+            Rejection::TooLarge(reason) => HttpResponse::PayloadTooLarge().body(reason),
+        }
+    }
+}
+
+impl From<Rejection> for actix_web::Error {
+    fn from(rejection: Rejection) -> Self {
+        HttpResponse::from(rejection).into()
+    }
+}
+
+fn data_arg() -> Arg<'static, 'static> {
+    Arg::with_name("data")
+        .long("data")
+        .value_name("DIR")
+        .required(true)
+        .help("Path to the directory containing the database and resource files")
+        .takes_value(true)
+}
+
+fn tag_colors_arg() -> Arg<'static, 'static> {
+    Arg::with_name("tag-colors")
+        .long("tag-colors")
+        .value_name("COLORS")
+        .default_value(tag_api::DEFAULT_TAG_COLORS)
+        .help("Comma separated #rrggbb colors to pick default tag colors from")
+        .validator(|v| {
+            match v
+                .split(',')
+                .map(str::trim)
+                .find(|c| !tag_api::is_valid_color(c))
+            {
+                Some(c) => Err(format!("Invalid color: {}", c)),
+                None => Ok(()),
+            }
+        })
+        .takes_value(true)
+}
+
+fn username_arg() -> Arg<'static, 'static> {
+    Arg::with_name("username")
+        .value_name("USERNAME")
+        .required(true)
+        .help("Name to log in with")
+}
+
+fn password_stdin_arg() -> Arg<'static, 'static> {
+    Arg::with_name("password-stdin")
+        .long("password-stdin")
+        .help("Reads the password from the first line of stdin instead of asking for it")
 }
 
-fn get_config() -> AppConfig {
+fn get_matches() -> ArgMatches<'static> {
     let app = clap::App::new("lenote-server")
         .about("Lenote Server")
         .version("0.1.0")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("ui")
                 .long("ui")
@@ -51,12 +158,13 @@ fn get_config() -> AppConfig {
                 .help("Path to the directory containing HTML & static files")
                 .takes_value(true),
         )
+        .arg(data_arg())
         .arg(
-            Arg::with_name("data")
-                .long("data")
-                .value_name("DIR")
-                .required(true)
-                .help("Path to the directory containing the database and resource files")
+            Arg::with_name("bind")
+                .long("bind")
+                .value_name("ADDRESS")
+                .default_value("127.0.0.1")
+                .help("Address for the HTTP server to listen on, e.g. 0.0.0.0 for every interface")
                 .takes_value(true),
         )
         .arg(
@@ -67,20 +175,194 @@ fn get_config() -> AppConfig {
                 .help("Port for the HTTP server")
                 .takes_value(true),
         )
+        .arg(tag_colors_arg())
+        .arg(
+            Arg::with_name("max-upload-mb")
+                .long("max-upload-mb")
+                .value_name("MB")
+                .default_value("50")
+                .help("Largest image or file upload accepted, in megabytes")
+                .validator(|v| match v.parse::<u64>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => Err(format!("Invalid size: {}", v)),
+                })
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("secure-cookies")
+                .long("secure-cookies")
+                .help("Only sends the session cookie over HTTPS, for a server behind a TLS proxy"),
+        )
         .arg(
             Arg::with_name("slow")
                 .long("slow")
                 .help("Slow down each request. Used for development purpose"),
+        )
+        .subcommand(
+            SubCommand::with_name("user")
+                .about("Manages the accounts that can log in")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Adds an account, asking for its password")
+                        .arg(data_arg())
+                        .arg(username_arg())
+                        .arg(password_stdin_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("passwd")
+                        .about("Changes the password of an account, logging it out everywhere")
+                        .arg(data_arg())
+                        .arg(username_arg())
+                        .arg(password_stdin_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Removes an account")
+                        .arg(data_arg())
+                        .arg(username_arg()),
+                )
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Lists the accounts")
+                        .arg(data_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .about("Upgrades the database schema")
+                .arg(data_arg())
+                .arg(
+                    Arg::with_name("status")
+                        .long("status")
+                        .help("Lists the migrations and whether they are applied"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .conflicts_with("status")
+                        .help("Runs the pending migrations without keeping the changes"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Writes the database, images and files to an archive, also while the server runs")
+                .arg(data_arg())
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Path of the .tar.zst archive to write")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restores an archive written by backup. The server must be stopped")
+                .arg(data_arg())
+                .arg(
+                    Arg::with_name("archive")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Path of the .tar.zst archive to restore"),
+                )
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Replaces the existing database, after backing it up"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes all notes and tags as Markdown and JSON files")
+                .arg(data_arg())
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .value_name("DIR")
+                        .required(true)
+                        .help("Path of the directory to write to, which must be empty")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("by")
+                        .long("by")
+                        .value_name("GROUPING")
+                        .possible_values(&["day", "tag"])
+                        .default_value("day")
+                        .help("Whether to write a Markdown file per day or per tag")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Adds the notes of an export, or of a directory of Markdown and text files")
+                .arg(data_arg())
+                .arg(tag_colors_arg())
+                .arg(
+                    Arg::with_name("source")
+                        .value_name("DIR")
+                        .required(true)
+                        .help("Path of the export or of the directory to import"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Reports what would be imported without changing anything"),
+                ),
         );
 
-    let matches = app.get_matches();
-    return AppConfig {
+    app.get_matches()
+}
+
+fn get_config(matches: &ArgMatches) -> AppConfig {
+    AppConfig {
         ui: PathBuf::from(matches.value_of("ui").expect("Missing UI parameter")),
         pages: PathBuf::from(matches.value_of("pages").expect("Missing pages parameter")),
         data: PathBuf::from(matches.value_of("data").expect("Missing DB parameter")),
+        bind: matches.value_of("bind").unwrap_or("127.0.0.1").to_string(),
         port: matches.value_of("port").unwrap_or("8080").to_string(),
+        secure_cookies: matches.is_present("secure-cookies"),
         slow: matches.is_present("slow"),
-    };
+        tag_colors: get_tag_colors(matches),
+        max_upload_size: matches
+            .value_of("max-upload-mb")
+            .and_then(|v| v.parse::<u64>().ok())
+            .unwrap_or(50)
+            * 1024
+            * 1024,
+    }
+}
+
+fn get_tag_colors(matches: &ArgMatches) -> Vec<String> {
+    matches
+        .value_of("tag-colors")
+        .unwrap_or(tag_api::DEFAULT_TAG_COLORS)
+        .split(',')
+        .map(|c| c.trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// The resource of an endpoint at `E::ROUTE`, guarded by its method, so that requests
+/// with other methods go on to the next matching resource.
+/// The handler has to respond with `E::Response`, so the build breaks when they differ.
+fn endpoint<E, F, I, R>(handler: F) -> actix_web::Resource
+where
+    E: Endpoint,
+    E::Response: Serialize + 'static,
+    F: Factory<I, R, WebResult<web::Json<E::Response>>>,
+    I: FromRequest + 'static,
+    R: Future<Output = WebResult<web::Json<E::Response>>> + 'static,
+{
+    web::resource(E::ROUTE)
+        .guard(guard::Method(match E::METHOD {
+            api::Method::Get => http::Method::GET,
+            api::Method::Post => http::Method::POST,
+            api::Method::Put => http::Method::PUT,
+            api::Method::Delete => http::Method::DELETE,
+        }))
+        .to(handler)
 }
 
 async fn index() -> HttpResponse {
@@ -93,31 +375,80 @@ async fn app_page(state: web::Data<AppState>) -> WebResult<fs::NamedFile> {
     Ok(fs::NamedFile::open(state.config.pages.join("index.html"))?)
 }
 
+async fn login_page(state: web::Data<AppState>) -> WebResult<fs::NamedFile> {
+    Ok(fs::NamedFile::open(state.config.pages.join("login.html"))?)
+}
+
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
-    let config = get_config();
+    let matches = get_matches();
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        if let Err(e) = commands::run(name, sub_matches) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let config = get_config(&matches);
 
     std::fs::create_dir_all(config.data.join("res").join("images"))?;
+    std::fs::create_dir_all(config.data.join("res").join("files"))?;
 
     let db_path = config.data.join("lenote.db");
-    info!("Opening DB connection to {}", db_path.display());
-    let mut connection = rusqlite::Connection::open(&db_path).unwrap();
-    database::init(&mut connection).unwrap();
-    let db = Arc::new(Mutex::new(connection));
+    info!("Opening DB connections to {}", db_path.display());
+    let db = DbPool::open(&db_path, DB_READER_COUNT).unwrap();
 
-    let addr = format!("127.0.0.1:{}", config.port);
+    let data = config.data.clone();
+    match db
+        .write(move |conn| file_store::remove_unused(conn, &data))
+        .await
+    {
+        Ok(removed) => info!("Removed {} unused images and files", removed),
+        Err(e) => warn!("Failed to remove unused images and files: {}", e),
+    }
+
+    match db.read(database::get_blob_paths).await {
+        Ok(paths) => {
+            let data = config.data.clone();
+            std::thread::spawn(move || {
+                let made = image_store::make_all_missing_thumbnails(&data, &paths);
+                if made > 0 {
+                    info!("Made thumbnails for {} images", made);
+                }
+            });
+        }
+        Err(e) => warn!("Failed to list images: {}", e),
+    }
+
+    match db
+        .write(|conn| {
+            database::delete_expired_sessions(conn)?;
+            database::get_users(conn)
+        })
+        .await
+    {
+        Ok(users) if users.is_empty() => {
+            warn!("There are no accounts to log in with, add one with the user add command")
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to list accounts: {}", e),
+    }
+
+    let addr = format!("{}:{}", config.bind, config.port);
     info!("Listening on {}", addr);
+    let events = events::Broadcaster::new();
     HttpServer::new(move || {
         let app_state = AppState {
             config: config.clone(),
             db: db.clone(),
+            events: events.clone(),
         };
 
         let slow = config.slow;
         App::new()
-            .app_data(web::PayloadConfig::default().limit(1024 * 1024 * 500))
-            .app_data(web::JsonConfig::default().limit(1024 * 1024 * 500))
+            .app_data(web::JsonConfig::default().limit(JSON_LIMIT))
             .wrap_fn(move |req, srv| {
                 if slow {
                     std::thread::sleep(Duration::from_millis(100));
@@ -125,23 +456,102 @@ async fn main() -> std::io::Result<()> {
                 srv.call(req)
             })
             .data(app_state)
+            .wrap(auth::RequireLogin::new(db.clone()))
             .service(fs::Files::new("/ui", &config.ui))
-            .service(fs::Files::new("/static", &config.pages.join("static")))
-            .service(fs::Files::new("/res", &config.data.join("res")))
-            .route("/api/notes{_:/?}", web::post().to(note_api::http_save_note))
-            .route("/api/notes{_:/?}", web::get().to(note_api::http_get_notes))
-            .route("/api/tags{_:/?}", web::get().to(tag_api::http_get_tags))
-            .route(
-                "/api/tags/{tag}{_:/?}",
-                web::get().to(tag_api::http_get_tag_map),
-            )
-            .route(
-                "/api/tags/{tag}{_:/?}",
-                web::post().to(tag_api::http_save_tag_map),
+            .service(fs::Files::new("/static", config.pages.join("static")))
+            .service(
+                // Stored files never change, as their names are random or come from their content
+                web::scope("/res")
+                    .wrap_fn(|req, srv| {
+                        let response = srv.call(req);
+                        async {
+                            let mut response = response.await?;
+                            // Attached files are served as they were uploaded,
+                            // browsers must not guess they are pages or scripts
+                            response.headers_mut().insert(
+                                http::header::X_CONTENT_TYPE_OPTIONS,
+                                http::HeaderValue::from_static("nosniff"),
+                            );
+                            if response.status().is_success() {
+                                response.headers_mut().insert(
+                                    http::header::CACHE_CONTROL,
+                                    http::HeaderValue::from_static(RES_CACHE_CONTROL),
+                                );
+                            }
+                            Ok(response)
+                        }
+                    })
+                    .service(fs::Files::new("", config.data.join("res"))),
             )
+            .service(endpoint::<api::SaveNote, _, _, _>(note_api::http_save_note))
+            .service(endpoint::<api::GetNotes, _, _, _>(note_api::http_get_notes))
+            .service(endpoint::<api::UploadImage, _, _, _>(
+                note_api::http_upload_image,
+            ))
+            .service(endpoint::<api::UploadFile, _, _, _>(
+                note_api::http_upload_file,
+            ))
+            .service(endpoint::<api::UpdateNote, _, _, _>(
+                note_api::http_update_note,
+            ))
+            .service(endpoint::<api::GetNoteRevisions, _, _, _>(
+                note_api::http_get_note_revisions,
+            ))
+            .service(endpoint::<api::DeleteNote, _, _, _>(
+                note_api::http_delete_note,
+            ))
+            .service(endpoint::<api::GetTrash, _, _, _>(note_api::http_get_trash))
+            .service(endpoint::<api::EmptyTrash, _, _, _>(
+                note_api::http_empty_trash,
+            ))
+            .service(endpoint::<api::RestoreNote, _, _, _>(
+                note_api::http_restore_note,
+            ))
+            .service(endpoint::<api::PurgeNote, _, _, _>(
+                note_api::http_purge_note,
+            ))
+            .service(endpoint::<api::Search, _, _, _>(search_api::http_search))
+            .service(endpoint::<api::GetTags, _, _, _>(tag_api::http_get_tags))
+            .service(endpoint::<api::GetTagStats, _, _, _>(
+                tag_api::http_get_tag_stats,
+            ))
+            .service(endpoint::<api::GetTagPalette, _, _, _>(
+                tag_api::http_get_tag_palette,
+            ))
+            .service(endpoint::<api::GetTagColor, _, _, _>(
+                tag_api::http_get_tag_color,
+            ))
+            .service(endpoint::<api::SaveTagColor, _, _, _>(
+                tag_api::http_save_tag_color,
+            ))
+            .service(endpoint::<api::RenameTag, _, _, _>(
+                tag_api::http_rename_tag,
+            ))
+            .service(endpoint::<api::MergeTag, _, _, _>(tag_api::http_merge_tag))
+            .service(endpoint::<api::GetTagMap, _, _, _>(
+                tag_api::http_get_tag_map,
+            ))
+            .service(endpoint::<api::SaveTagMap, _, _, _>(
+                tag_api::http_save_tag_map,
+            ))
+            .service(endpoint::<api::GetMe, _, _, _>(auth::http_get_me))
+            .service(endpoint::<api::GetTokens, _, _, _>(
+                token_api::http_get_tokens,
+            ))
+            .service(endpoint::<api::CreateToken, _, _, _>(
+                token_api::http_create_token,
+            ))
+            .service(endpoint::<api::DeleteToken, _, _, _>(
+                token_api::http_delete_token,
+            ))
+            .route("/api/export{_:/?}", web::get().to(export_api::http_export))
+            .route(api::EVENTS_ROUTE, web::get().to(events::http_events))
+            .route("/login", web::get().to(login_page))
+            .route("/login", web::post().to(auth::http_login))
+            .route("/logout", web::post().to(auth::http_logout))
             .route("/", web::get().to(index))
             .route("/app{_:/?}", web::get().to(index))
-            .route("/app/{app:[a-zA-z0-9_\\-/]+}", web::get().to(app_page))
+            .route("/app/{app:.+}", web::get().to(app_page))
     })
     .bind(&addr)?
     .run()
@@ -149,3 +559,135 @@ async fn main() -> std::io::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_router::{Path, ResourceDef};
+    use lenote_common::models::*;
+
+    /// Checks that the server would route the path a client sends `endpoint` to
+    /// to the endpoint, with the values the path was made from
+    fn assert_routed<E: Endpoint>(endpoint: E, values: &[(&str, &str)]) {
+        let mut path = Path::new(endpoint.path());
+        assert!(
+            ResourceDef::new(E::ROUTE).match_path(&mut path),
+            "{} doesn't match {}",
+            endpoint.path(),
+            E::ROUTE
+        );
+        for (name, value) in values {
+            assert_eq!(path.get(name), Some(*value), "{} in {}", name, E::ROUTE);
+        }
+    }
+
+    fn note(id: i64) -> Note {
+        Note {
+            id,
+            client_id: String::new(),
+            text: String::new(),
+            timestamp: 0,
+            note_type: NoteType::Text,
+            tags: Default::default(),
+        }
+    }
+
+    #[test]
+    fn endpoint_paths_match_their_routes() {
+        // Child tags keep their `/`, other characters that would break the path are escaped
+        let tag = String::from("#a b/c");
+        let tag_value = [("tag", "a%20b/c")];
+        let id = [("id", "42")];
+
+        assert_routed(api::GetNotes(Default::default()), &[]);
+        assert_routed(api::SaveNote(note(0)), &[]);
+        assert_routed(api::UpdateNote(note(42)), &id);
+        assert_routed(api::DeleteNote { id: 42 }, &id);
+        assert_routed(api::GetNoteRevisions { id: 42 }, &id);
+        assert_routed(api::UploadImage, &[]);
+        assert_routed(api::UploadFile, &[]);
+        assert_routed(api::GetTrash, &[]);
+        assert_routed(api::EmptyTrash, &[]);
+        assert_routed(api::RestoreNote { id: 42 }, &id);
+        assert_routed(api::PurgeNote { id: 42 }, &id);
+        assert_routed(api::Search(Default::default()), &[]);
+        assert_routed(api::GetTags(Default::default()), &[]);
+        assert_routed(api::GetTagStats, &[]);
+        assert_routed(api::GetTagPalette, &[]);
+        assert_routed(
+            api::GetTagMap {
+                tag: tag.clone(),
+                filter: Default::default(),
+            },
+            &tag_value,
+        );
+        assert_routed(
+            api::SaveTagMap {
+                tag: tag.clone(),
+                tag_map: TagMap {
+                    tag: tag.clone(),
+                    note_id: 42,
+                    status: TagMapStatus::Active,
+                    timestamp: 0,
+                },
+            },
+            &tag_value,
+        );
+        assert_routed(api::GetTagColor { tag: tag.clone() }, &tag_value);
+        let color = TagColor {
+            color: String::from("#000000"),
+        };
+        assert_routed(
+            api::SaveTagColor {
+                tag: tag.clone(),
+                color,
+            },
+            &tag_value,
+        );
+        let target = TagTarget {
+            tag: String::from("#d"),
+        };
+        assert_routed(
+            api::RenameTag {
+                tag: tag.clone(),
+                target: target.clone(),
+            },
+            &tag_value,
+        );
+        assert_routed(api::MergeTag { tag, target }, &tag_value);
+
+        // Child tags named like a tag action are still read and changed as tags
+        let child = String::from("#project/color");
+        let child_map = api::GetTagMap {
+            tag: child.clone(),
+            filter: Default::default(),
+        };
+        assert!(!ResourceDef::new(api::GetTagColor::ROUTE).is_match(&child_map.path()));
+        assert_routed(child_map, &[("tag", "project/color")]);
+        assert_routed(
+            api::SaveTagMap {
+                tag: child.clone(),
+                tag_map: TagMap {
+                    tag: child,
+                    note_id: 42,
+                    status: TagMapStatus::Archived,
+                    timestamp: 0,
+                },
+            },
+            &[("tag", "project/color")],
+        );
+
+        assert_routed(api::GetMe, &[]);
+        assert_routed(api::GetTokens, &[]);
+        assert_routed(
+            api::CreateToken(NewApiToken {
+                name: String::from("cli"),
+                scope: TokenScope::Read,
+            }),
+            &[],
+        );
+        assert_routed(api::DeleteToken { id: 42 }, &id);
+
+        assert!(ResourceDef::new(api::EVENTS_ROUTE).is_match(api::EVENTS_PATH));
+    }
+}
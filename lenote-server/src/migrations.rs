@@ -0,0 +1,541 @@
+//! Schema migrations of the database.
+//!
+//! Each applied migration is recorded with a checksum of its SQL, and the server
+//! refuses to start if a recorded migration was changed or is unknown to it.
+//! Released migrations must never be edited, add a new one instead.
+
+use crate::database;
+use rusqlite::{params, Connection, DatabaseName, OptionalExtension, NO_PARAMS};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+pub struct Migration {
+    pub name: &'static str,
+    /// One or more statements, applied in a single transaction
+    pub sql: &'static str,
+}
+
+/// The version of a migration is its position in this list, starting from 1
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        name: "create_notes",
+        sql: "CREATE TABLE notes(
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            text VARCHAR NOT NULL,
+            timestamp BIGINT
+        )",
+    },
+    Migration {
+        name: "create_tags",
+        sql: "CREATE TABLE tags(
+            tag VARCHAR NOT NULL PRIMARY KEY,
+            color VARCHAR NOT NULL
+        )",
+    },
+    Migration {
+        name: "create_tag_map",
+        sql: "CREATE TABLE tag_map(
+            tag VARCHAR NOT NULL,
+            note_id BIGINT NOT NULL,
+            status INT NOT NULL,
+            PRIMARY KEY (tag, note_id),
+            FOREIGN KEY(note_id) REFERENCES notes(id),
+            FOREIGN KEY(tag) REFERENCES tags(tag)
+        )",
+    },
+    Migration {
+        name: "create_tag_map_history",
+        sql: "CREATE TABLE tag_map_history(
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            tag VARCHAR NOT NULL,
+            note_id BIGINT NOT NULL,
+            status INT NOT NULL,
+            timestamp BIGINT,
+            FOREIGN KEY(note_id) REFERENCES notes(id),
+            FOREIGN KEY(tag) REFERENCES tags(tag)
+        )",
+    },
+    Migration {
+        name: "add_notes_note_type",
+        sql: "ALTER TABLE notes ADD COLUMN note_type INTEGER NOT NULL DEFAULT 0",
+    },
+    Migration {
+        name: "create_notes_fts",
+        sql: "CREATE VIRTUAL TABLE notes_fts USING fts5(
+            text,
+            content='notes',
+            content_rowid='id'
+        )",
+    },
+    Migration {
+        name: "create_notes_fts_insert_trigger",
+        sql: "CREATE TRIGGER notes_fts_insert AFTER INSERT ON notes
+        WHEN new.note_type = 0
+        BEGIN
+            INSERT INTO notes_fts(rowid, text) VALUES (new.id, new.text);
+        END",
+    },
+    Migration {
+        name: "create_notes_fts_update_trigger",
+        sql: "CREATE TRIGGER notes_fts_update AFTER UPDATE OF text ON notes
+        WHEN new.note_type = 0
+        BEGIN
+            INSERT INTO notes_fts(notes_fts, rowid, text) VALUES ('delete', old.id, old.text);
+            INSERT INTO notes_fts(rowid, text) VALUES (new.id, new.text);
+        END",
+    },
+    Migration {
+        name: "create_notes_fts_delete_trigger",
+        sql: "CREATE TRIGGER notes_fts_delete AFTER DELETE ON notes
+        WHEN old.note_type = 0
+        BEGIN
+            INSERT INTO notes_fts(notes_fts, rowid, text) VALUES ('delete', old.id, old.text);
+        END",
+    },
+    Migration {
+        name: "fill_notes_fts",
+        sql: "INSERT INTO notes_fts(rowid, text) SELECT id, text FROM notes WHERE note_type = 0",
+    },
+    Migration {
+        name: "create_note_revisions",
+        sql: "CREATE TABLE note_revisions(
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            note_id BIGINT NOT NULL,
+            text VARCHAR NOT NULL,
+            timestamp BIGINT,
+            FOREIGN KEY(note_id) REFERENCES notes(id)
+        )",
+    },
+    Migration {
+        name: "add_notes_deleted_at",
+        sql: "ALTER TABLE notes ADD COLUMN deleted_at BIGINT",
+    },
+    Migration {
+        name: "add_tags_display",
+        sql: "ALTER TABLE tags ADD COLUMN display VARCHAR",
+    },
+    Migration {
+        name: "create_images",
+        sql: "CREATE TABLE images(
+            path VARCHAR NOT NULL PRIMARY KEY,
+            ref_count INTEGER NOT NULL,
+            created_at BIGINT
+        );
+        INSERT INTO images(path, ref_count, created_at)
+            SELECT text, COUNT(*), MIN(timestamp) FROM notes
+            WHERE note_type = 1
+            GROUP BY text",
+    },
+    Migration {
+        name: "rename_images_to_blobs",
+        sql: "ALTER TABLE images RENAME TO blobs",
+    },
+    Migration {
+        name: "create_users",
+        sql: "CREATE TABLE users(
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            username VARCHAR NOT NULL UNIQUE,
+            password_hash VARCHAR NOT NULL,
+            created_at BIGINT
+        )",
+    },
+    Migration {
+        name: "create_sessions",
+        sql: "CREATE TABLE sessions(
+            token_hash VARCHAR NOT NULL PRIMARY KEY,
+            user_id BIGINT NOT NULL,
+            created_at BIGINT,
+            expires_at BIGINT NOT NULL,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+    },
+    Migration {
+        name: "create_api_tokens",
+        sql: "CREATE TABLE api_tokens(
+            id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+            user_id BIGINT NOT NULL,
+            name VARCHAR NOT NULL,
+            token_hash VARCHAR NOT NULL UNIQUE,
+            scope INTEGER NOT NULL,
+            created_at BIGINT NOT NULL,
+            last_used_at BIGINT,
+            FOREIGN KEY(user_id) REFERENCES users(id)
+        )",
+    },
+];
+
+/// A migration recorded in the database
+struct AppliedMigration {
+    version: usize,
+    name: String,
+    checksum: String,
+    // None for migrations recorded by the old `db_version` table
+    applied_at: Option<i64>,
+}
+
+pub enum MigrationState {
+    Applied(Option<i64>),
+    Pending,
+    Changed,
+}
+
+fn checksum(sql: &str) -> String {
+    hex::encode(Sha256::digest(sql.as_bytes()))
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool, failure::Error> {
+    let found = conn
+        .query_row(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![table],
+            |row| row.get::<_, String>(0),
+        )
+        .optional()?;
+
+    Ok(found.is_some())
+}
+
+/// Reads the applied migrations, without changing the database.
+/// Databases from before the migrations table only have a version number,
+/// in which case the migrations up to that version are taken as they are now.
+fn get_applied(conn: &Connection) -> Result<Vec<AppliedMigration>, failure::Error> {
+    if table_exists(conn, "schema_migrations")? {
+        let mut stmt = conn.prepare(
+            "SELECT version, name, checksum, applied_at FROM schema_migrations ORDER BY version",
+        )?;
+        let iter = stmt.query_map(NO_PARAMS, |row| {
+            Ok(AppliedMigration {
+                version: row.get::<_, i64>(0)? as usize,
+                name: row.get(1)?,
+                checksum: row.get(2)?,
+                applied_at: row.get(3)?,
+            })
+        })?;
+
+        let mut result = vec![];
+        for applied in iter {
+            result.push(applied?);
+        }
+
+        return Ok(result);
+    }
+
+    if table_exists(conn, "db_version")? {
+        let version: i64 =
+            conn.query_row("SELECT v FROM db_version", NO_PARAMS, |row| row.get(0))?;
+        return Ok(MIGRATIONS
+            .iter()
+            .take(version as usize)
+            .enumerate()
+            .map(|(i, m)| AppliedMigration {
+                version: i + 1,
+                name: m.name.to_string(),
+                checksum: checksum(m.sql),
+                applied_at: None,
+            })
+            .collect());
+    }
+
+    Ok(vec![])
+}
+
+/// The schema version this server brings databases up to
+pub fn latest_version() -> usize {
+    MIGRATIONS.len()
+}
+
+/// The number of migrations applied to the database
+pub fn schema_version(conn: &Connection) -> Result<usize, failure::Error> {
+    Ok(get_applied(conn)?.len())
+}
+
+/// Fails if an applied migration was changed or is not known to this build
+fn verify(applied: &[AppliedMigration]) -> Result<(), failure::Error> {
+    for a in applied {
+        match MIGRATIONS.get(a.version - 1) {
+            Some(m) if checksum(m.sql) == a.checksum => {}
+            Some(_) => {
+                return Err(failure::format_err!(
+                    "Migration {} ({}) was changed after it was applied",
+                    a.version,
+                    a.name
+                ))
+            }
+            None => {
+                return Err(failure::format_err!(
+                    "The database has migration {} ({}), which is newer than this server",
+                    a.version,
+                    a.name
+                ))
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns every known migration along with its state in the database
+pub fn get_status(
+    conn: &Connection,
+) -> Result<Vec<(usize, &'static Migration, MigrationState)>, failure::Error> {
+    let applied = get_applied(conn)?;
+
+    Ok(MIGRATIONS
+        .iter()
+        .enumerate()
+        .map(|(i, m)| {
+            let state = match applied.iter().find(|a| a.version == i + 1) {
+                Some(a) if a.checksum == checksum(m.sql) => MigrationState::Applied(a.applied_at),
+                Some(_) => MigrationState::Changed,
+                None => MigrationState::Pending,
+            };
+            (i + 1, m, state)
+        })
+        .collect())
+}
+
+/// Creates the migrations table, moving over the version of an older database
+fn init_migrations_table(
+    tx: &rusqlite::Transaction,
+    applied: &[AppliedMigration],
+) -> Result<(), failure::Error> {
+    if table_exists(tx, "schema_migrations")? {
+        return Ok(());
+    }
+
+    tx.execute(
+        "CREATE TABLE schema_migrations(
+            version INTEGER NOT NULL PRIMARY KEY,
+            name VARCHAR NOT NULL,
+            checksum VARCHAR NOT NULL,
+            applied_at BIGINT
+        )",
+        NO_PARAMS,
+    )?;
+
+    for a in applied {
+        tx.execute(
+            "INSERT INTO schema_migrations(version, name, checksum, applied_at)
+            VALUES(?1, ?2, ?3, NULL)",
+            params![a.version as i64, &a.name, &a.checksum],
+        )?;
+    }
+
+    tx.execute("DROP TABLE IF EXISTS db_version", NO_PARAMS)?;
+    Ok(())
+}
+
+fn apply(tx: &rusqlite::Transaction, version: usize, m: &Migration) -> Result<(), failure::Error> {
+    tx.execute_batch(m.sql)
+        .map_err(|e| failure::format_err!("Migration {} ({}) failed: {}", version, m.name, e))?;
+    tx.execute(
+        "INSERT INTO schema_migrations(version, name, checksum, applied_at)
+        VALUES(?1, ?2, ?3, ?4)",
+        params![version as i64, m.name, checksum(m.sql), database::now()],
+    )?;
+
+    Ok(())
+}
+
+fn pending(applied: &[AppliedMigration]) -> Vec<(usize, &'static Migration)> {
+    MIGRATIONS
+        .iter()
+        .enumerate()
+        .skip(applied.len())
+        .map(|(i, m)| (i + 1, m))
+        .collect()
+}
+
+/// Copies the database with SQLite's online backup, e.g. before migrating it
+pub fn backup_db(conn: &Connection, backup_path: &Path) -> Result<(), failure::Error> {
+    if let Some(dir) = backup_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    conn.backup(DatabaseName::Main, backup_path, None)?;
+    Ok(())
+}
+
+/// Brings the database up to date.
+/// An existing database is first copied into `backup_dir`, and each migration
+/// runs in its own transaction, so a failed one leaves the database at the previous version.
+pub fn migrate(conn: &mut Connection, backup_dir: &Path) -> Result<(), failure::Error> {
+    let applied = get_applied(conn)?;
+    verify(&applied)?;
+
+    let pending = pending(&applied);
+    if !pending.is_empty() && !applied.is_empty() {
+        let backup_path =
+            backup_dir.join(format!("lenote-v{}-{}.db", applied.len(), database::now()));
+        info!("Backing up the database to {}", backup_path.display());
+        backup_db(conn, &backup_path)?;
+    }
+
+    let tx = conn.transaction()?;
+    init_migrations_table(&tx, &applied)?;
+    tx.commit()?;
+
+    for (version, m) in pending {
+        let tx = conn.transaction()?;
+        apply(&tx, version, m)?;
+        tx.commit()?;
+        info!("Applied migration {} ({})", version, m.name);
+    }
+
+    Ok(())
+}
+
+/// Applies the pending migrations and rolls them all back,
+/// returning the ones that would be applied.
+pub fn dry_run(conn: &mut Connection) -> Result<Vec<(usize, &'static Migration)>, failure::Error> {
+    let applied = get_applied(conn)?;
+    verify(&applied)?;
+
+    let pending = pending(&applied);
+    let tx = conn.transaction()?;
+    init_migrations_table(&tx, &applied)?;
+    for (version, m) in &pending {
+        apply(&tx, *version, m)?;
+    }
+    tx.rollback()?;
+
+    Ok(pending)
+}
+
+/// Where the backups made before migrating the database at `db_path` go
+pub fn backup_dir(db_path: &Path) -> PathBuf {
+    db_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join("backups")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A database with only the first `count` migrations applied
+    fn migrated_to(count: usize) -> Connection {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let tx = conn.transaction().unwrap();
+        init_migrations_table(&tx, &[]).unwrap();
+        for (i, m) in MIGRATIONS.iter().take(count).enumerate() {
+            apply(&tx, i + 1, m).unwrap();
+        }
+        tx.commit().unwrap();
+        conn
+    }
+
+    /// A database from before the migrations table, at version `count`
+    fn legacy_at(count: usize) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        for m in MIGRATIONS.iter().take(count) {
+            conn.execute_batch(m.sql).unwrap();
+        }
+        conn.execute_batch(&format!(
+            "CREATE TABLE db_version(v INT); INSERT INTO db_version(v) VALUES({});",
+            count
+        ))
+        .unwrap();
+        conn
+    }
+
+    #[test]
+    fn migrates_a_new_database() {
+        let dir = TempDir::new();
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn, dir.path()).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        assert!(get_status(&conn)
+            .unwrap()
+            .iter()
+            .all(|(_, _, state)| matches!(state, MigrationState::Applied(Some(_)))));
+        // Nothing to back up yet
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // Migrating again does nothing
+        migrate(&mut conn, dir.path()).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn backs_up_a_database_before_migrating_it() {
+        let dir = TempDir::new();
+        let mut conn = migrated_to(3);
+        migrate(&mut conn, dir.path()).unwrap();
+
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+        let backups: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(backups.len(), 1);
+
+        let backup = Connection::open(backups[0].as_ref().unwrap().path()).unwrap();
+        assert_eq!(schema_version(&backup).unwrap(), 3);
+    }
+
+    #[test]
+    fn refuses_changed_migrations() {
+        let dir = TempDir::new();
+        let mut conn = migrated_to(2);
+        conn.execute(
+            "UPDATE schema_migrations SET checksum = 'changed' WHERE version = 2",
+            NO_PARAMS,
+        )
+        .unwrap();
+
+        let status = get_status(&conn).unwrap();
+        assert!(matches!(status[1].2, MigrationState::Changed));
+        assert!(matches!(status[2].2, MigrationState::Pending));
+
+        let e = migrate(&mut conn, dir.path()).unwrap_err();
+        assert!(e.to_string().contains("was changed"), "{}", e);
+        assert!(dry_run(&mut conn).is_err());
+        assert_eq!(schema_version(&conn).unwrap(), 2);
+    }
+
+    #[test]
+    fn refuses_migrations_newer_than_the_server() {
+        let dir = TempDir::new();
+        let mut conn = migrated_to(latest_version());
+        conn.execute(
+            "INSERT INTO schema_migrations(version, name, checksum) VALUES(?1, 'future', 'x')",
+            params![latest_version() as i64 + 1],
+        )
+        .unwrap();
+
+        let e = migrate(&mut conn, dir.path()).unwrap_err();
+        assert!(e.to_string().contains("newer than this server"), "{}", e);
+    }
+
+    #[test]
+    fn imports_the_legacy_version_table() {
+        let dir = TempDir::new();
+        let mut conn = legacy_at(5);
+        assert_eq!(schema_version(&conn).unwrap(), 5);
+
+        migrate(&mut conn, dir.path()).unwrap();
+        assert!(!table_exists(&conn, "db_version").unwrap());
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        let status = get_status(&conn).unwrap();
+        assert!(matches!(status[4].2, MigrationState::Applied(None)));
+        assert!(matches!(status[5].2, MigrationState::Applied(Some(_))));
+    }
+
+    #[test]
+    fn dry_run_rolls_everything_back() {
+        let mut conn = legacy_at(5);
+        let pending = dry_run(&mut conn).unwrap();
+
+        assert_eq!(pending.len(), latest_version() - 5);
+        assert_eq!(pending[0].0, 6);
+        // Still the legacy database it was
+        assert!(table_exists(&conn, "db_version").unwrap());
+        assert!(!table_exists(&conn, "schema_migrations").unwrap());
+        assert!(!table_exists(&conn, "note_revisions").unwrap());
+
+        let mut conn = migrated_to(latest_version());
+        assert!(dry_run(&mut conn).unwrap().is_empty());
+    }
+}
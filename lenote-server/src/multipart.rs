@@ -0,0 +1,396 @@
+//! A small streaming reader of `multipart/form-data` request bodies.
+//! The content of each part is handed out as it arrives,
+//! so that uploaded files never have to be held in memory.
+
+use crate::Rejection;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{HeaderMap, CONTENT_TYPE};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+
+// Longest preamble or block of part headers we are willing to buffer
+const MAX_HEADERS_SIZE: usize = 16 * 1024;
+
+#[derive(PartialEq)]
+enum State {
+    /// Before the first boundary
+    Preamble,
+    /// Right after a boundary, which is followed by either headers or the end
+    Boundary,
+    Headers,
+    Content,
+    Done,
+}
+
+/// Headers of a part
+pub struct Part {
+    pub name: String,
+    /// The name of the uploaded file, as the client sent it
+    pub filename: Option<String>,
+}
+
+pub struct Multipart<S> {
+    stream: S,
+    buf: BytesMut,
+    /// `\r\n--` followed by the boundary, which ends the content of a part
+    delimiter: Vec<u8>,
+    state: State,
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Finds the value of a parameter like `boundary=...` in a header value
+fn header_param<'a>(value: &'a str, name: &str) -> Option<&'a str> {
+    value.split(';').skip(1).find_map(|param| {
+        let mut pair = param.trim().splitn(2, '=');
+        if pair.next()?.trim().eq_ignore_ascii_case(name) {
+            Some(pair.next()?.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+fn parse_part(headers: &str) -> Result<Part, Rejection> {
+    let mut disposition = None;
+    for line in headers.split("\r\n") {
+        let mut pair = line.splitn(2, ':');
+        let name = pair.next().unwrap_or_default().trim();
+        let value = pair.next().unwrap_or_default().trim();
+        if name.eq_ignore_ascii_case("content-disposition") {
+            disposition = Some(value);
+        }
+    }
+
+    let disposition = disposition.ok_or(Rejection::BadRequest("Missing part disposition"))?;
+    let name =
+        header_param(disposition, "name").ok_or(Rejection::BadRequest("Missing part name"))?;
+
+    Ok(Part {
+        name: name.to_string(),
+        filename: header_param(disposition, "filename").map(String::from),
+    })
+}
+
+impl<S> Multipart<S>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    pub fn new(headers: &HeaderMap, stream: S) -> Result<Self, Rejection> {
+        let content_type = headers
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        if !content_type.starts_with("multipart/form-data") {
+            return Err(Rejection::BadRequest(
+                "Expected a multipart/form-data request",
+            ));
+        }
+
+        let boundary = header_param(content_type, "boundary")
+            .filter(|b| !b.is_empty())
+            .ok_or(Rejection::BadRequest("Missing multipart boundary"))?;
+
+        // The first boundary isn't preceded by a line break,
+        // one is added so that all boundaries can be found the same way
+        let mut buf = BytesMut::new();
+        buf.extend_from_slice(b"\r\n");
+
+        Ok(Self {
+            stream,
+            buf,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            state: State::Preamble,
+        })
+    }
+
+    /// Reads more of the body into the buffer. Returns false at the end of the body.
+    async fn fill(&mut self) -> Result<bool, Rejection> {
+        match self.stream.next().await {
+            Some(Ok(bytes)) => {
+                self.buf.extend_from_slice(&bytes);
+                Ok(true)
+            }
+            Some(Err(e)) => {
+                warn!("Failed to read the request body: {}", e);
+                Err(Rejection::BadRequest("Failed to read the request body"))
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn fill_or_fail(&mut self) -> Result<(), Rejection> {
+        if self.buf.len() > MAX_HEADERS_SIZE + self.delimiter.len() {
+            return Err(Rejection::BadRequest("Multipart headers are too large"));
+        }
+
+        if self.fill().await? {
+            Ok(())
+        } else {
+            Err(Rejection::BadRequest(
+                "Unexpected end of the multipart body",
+            ))
+        }
+    }
+
+    /// Moves on to the next part, skipping whatever is left of the current one.
+    /// Returns None after the last part.
+    pub async fn next_part(&mut self) -> Result<Option<Part>, Rejection> {
+        loop {
+            match self.state {
+                State::Preamble => match find(&self.buf, &self.delimiter) {
+                    Some(pos) => {
+                        self.buf.advance(pos + self.delimiter.len());
+                        self.state = State::Boundary;
+                    }
+                    None => self.fill_or_fail().await?,
+                },
+                State::Boundary => {
+                    if self.buf.len() < 2 {
+                        self.fill_or_fail().await?;
+                    } else if self.buf.starts_with(b"--") {
+                        self.state = State::Done;
+                    } else if self.buf.starts_with(b"\r\n") {
+                        self.buf.advance(2);
+                        self.state = State::Headers;
+                    } else {
+                        return Err(Rejection::BadRequest("Invalid multipart boundary"));
+                    }
+                }
+                State::Headers => match find(&self.buf, b"\r\n\r\n") {
+                    Some(pos) => {
+                        let headers = self.buf.split_to(pos + 4);
+                        let headers = std::str::from_utf8(&headers[..pos])
+                            .map_err(|_| Rejection::BadRequest("Invalid multipart headers"))?;
+                        let part = parse_part(headers)?;
+                        self.state = State::Content;
+                        return Ok(Some(part));
+                    }
+                    None => self.fill_or_fail().await?,
+                },
+                State::Content => while self.next_chunk().await?.is_some() {},
+                State::Done => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads the next piece of the content of the current part.
+    /// Returns None at the end of the part.
+    pub async fn next_chunk(&mut self) -> Result<Option<Bytes>, Rejection> {
+        if self.state != State::Content {
+            return Ok(None);
+        }
+
+        loop {
+            match find(&self.buf, &self.delimiter) {
+                Some(0) => {
+                    self.buf.advance(self.delimiter.len());
+                    self.state = State::Boundary;
+                    return Ok(None);
+                }
+                Some(pos) => return Ok(Some(self.buf.split_to(pos).freeze())),
+                None => {
+                    // The end of the buffer may be the start of the delimiter
+                    let safe = self.buf.len().saturating_sub(self.delimiter.len() - 1);
+                    if safe > 0 {
+                        return Ok(Some(self.buf.split_to(safe).freeze()));
+                    }
+
+                    if !self.fill().await? {
+                        return Err(Rejection::BadRequest(
+                            "Unexpected end of the multipart body",
+                        ));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::HeaderValue;
+
+    const BODY: &[u8] = b"preamble\r\n--XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        first\r\n--XyZ\r\n\
+        content-type: text/plain\r\n\
+        content-disposition: form-data; name=\"file\"; filename=\"a b.txt\"\r\n\r\n\
+        line\r\n--Xy\r\n\r\nmore\r\n--XyZ--\r\n";
+
+    type Chunks = futures::stream::Iter<std::vec::IntoIter<Result<Bytes, PayloadError>>>;
+
+    /// Reads `body` in chunks of `size` bytes
+    fn multipart(body: &[u8], size: usize) -> Multipart<Chunks> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("multipart/form-data; boundary=\"XyZ\""),
+        );
+        let chunks: Vec<_> = body
+            .chunks(size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        Multipart::new(&headers, futures::stream::iter(chunks)).unwrap()
+    }
+
+    /// The name, file name and content of every part
+    async fn read_all(
+        mut multipart: Multipart<Chunks>,
+    ) -> Result<Vec<(String, Option<String>, Vec<u8>)>, Rejection> {
+        let mut parts = vec![];
+        while let Some(part) = multipart.next_part().await? {
+            let mut content = vec![];
+            while let Some(chunk) = multipart.next_chunk().await? {
+                assert!(!chunk.is_empty());
+                content.extend_from_slice(&chunk);
+            }
+            parts.push((part.name, part.filename, content));
+        }
+        Ok(parts)
+    }
+
+    #[actix_rt::test]
+    async fn reads_parts_split_anywhere() {
+        // Every size splits the boundaries and line breaks at another place
+        for size in 1..=BODY.len() {
+            let parts = read_all(multipart(BODY, size)).await.unwrap();
+            assert_eq!(
+                parts,
+                vec![
+                    ("title".to_string(), None, b"first".to_vec()),
+                    (
+                        "file".to_string(),
+                        Some("a b.txt".to_string()),
+                        b"line\r\n--Xy\r\n\r\nmore".to_vec()
+                    ),
+                ],
+                "chunks of {} bytes",
+                size
+            );
+        }
+    }
+
+    #[actix_rt::test]
+    async fn skips_unread_parts() {
+        let mut multipart = multipart(BODY, 7);
+        assert_eq!(multipart.next_part().await.unwrap().unwrap().name, "title");
+        assert_eq!(multipart.next_part().await.unwrap().unwrap().name, "file");
+        assert_eq!(
+            multipart.next_chunk().await.unwrap().unwrap(),
+            Bytes::from_static(b"line")
+        );
+        assert!(multipart.next_part().await.unwrap().is_none());
+        assert!(multipart.next_chunk().await.unwrap().is_none());
+    }
+
+    #[actix_rt::test]
+    async fn reads_an_empty_part() {
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n\r\n--XyZ--";
+        for size in 1..=body.len() {
+            let parts = read_all(multipart(body, size)).await.unwrap();
+            assert_eq!(parts, vec![("a".to_string(), None, vec![])]);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fails_without_the_closing_boundary() {
+        let cut_bodies: &[&[u8]] = &[
+            // In the content
+            &BODY[..BODY.len() - 14],
+            // Right after the last delimiter
+            &BODY[..BODY.len() - 4],
+            // In the headers
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n",
+            // Before any boundary
+            b"preamble",
+        ];
+        for body in cut_bodies {
+            for size in 1..=body.len() {
+                let e = read_all(multipart(body, size)).await.unwrap_err();
+                assert!(
+                    matches!(
+                        e,
+                        Rejection::BadRequest("Unexpected end of the multipart body")
+                    ),
+                    "{:?}",
+                    e
+                );
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn fails_on_parts_without_a_disposition() {
+        let body = b"--XyZ\r\nContent-Type: text/plain\r\n\r\nfirst\r\n--XyZ--";
+        let e = read_all(multipart(body, 5)).await.unwrap_err();
+        assert!(
+            matches!(e, Rejection::BadRequest("Missing part disposition")),
+            "{:?}",
+            e
+        );
+
+        let body = b"--XyZ\r\nContent-Disposition: form-data\r\n\r\nfirst\r\n--XyZ--";
+        let e = read_all(multipart(body, 5)).await.unwrap_err();
+        assert!(
+            matches!(e, Rejection::BadRequest("Missing part name")),
+            "{:?}",
+            e
+        );
+    }
+
+    #[actix_rt::test]
+    async fn fails_on_text_after_a_boundary() {
+        let body =
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\nfirst\r\n--XyZ!!\r\n";
+        let e = read_all(multipart(body, 3)).await.unwrap_err();
+        assert!(
+            matches!(e, Rejection::BadRequest("Invalid multipart boundary")),
+            "{:?}",
+            e
+        );
+    }
+
+    #[actix_rt::test]
+    async fn limits_the_size_of_headers() {
+        let mut body = b"--XyZ\r\nContent-Disposition: form-data; name=\"a\"\r\n".to_vec();
+        body.extend(std::iter::repeat_n(b'x', MAX_HEADERS_SIZE * 2));
+        body.extend_from_slice(b"\r\n\r\nfirst\r\n--XyZ--");
+
+        let e = read_all(multipart(&body, 1024)).await.unwrap_err();
+        assert!(
+            matches!(e, Rejection::BadRequest("Multipart headers are too large")),
+            "{:?}",
+            e
+        );
+    }
+
+    #[test]
+    fn needs_a_boundary() {
+        let stream = || futures::stream::iter(Vec::<Result<Bytes, PayloadError>>::new());
+        let new = |content_type: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+            Multipart::new(&headers, stream()).err()
+        };
+
+        assert!(new("multipart/form-data; boundary=a").is_none());
+        assert!(matches!(
+            new("multipart/form-data"),
+            Some(Rejection::BadRequest("Missing multipart boundary"))
+        ));
+        assert!(matches!(
+            new("multipart/form-data; boundary=\"\""),
+            Some(Rejection::BadRequest("Missing multipart boundary"))
+        ));
+        assert!(matches!(
+            new("application/json"),
+            Some(Rejection::BadRequest(
+                "Expected a multipart/form-data request"
+            ))
+        ));
+    }
+}
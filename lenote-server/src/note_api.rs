@@ -1,105 +1,630 @@
 use crate::database;
+use crate::file_store::{self, BlobWriter};
+use crate::image_store;
+use crate::multipart::Multipart;
+use crate::paging::{page_limit, Cursor, MAX_PAGE_LIMIT};
 use crate::tag_api;
-use crate::AppState;
-use actix_web::{web, HttpResponse, Result as WebResult};
+use crate::{AppState, Rejection};
+use actix_web::error::PayloadError;
+use actix_web::{web, HttpRequest, Result as WebResult};
+use bytes::Bytes;
+use futures::Stream;
+use lenote_common::api::GetNotesFilter;
+use lenote_common::files::{clean_file_name, FileAttachment};
 use lenote_common::models::*;
-use regex::Regex;
-use serde::Deserialize;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
-use tokio::fs::File;
-use tokio::prelude::*;
 
-const FILE_NAME_LENGTH: usize = 32;
-const FILE_NAME_CHARS: &'static [char] = &[
-    'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's',
-    't', 'u', 'v', 'w', 'x', 'y', 'z', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9',
-];
+/// Streams the content of the current part to `writer`, up to `max_size` bytes
+async fn write_part<S>(
+    multipart: &mut Multipart<S>,
+    writer: &mut BlobWriter,
+    max_size: u64,
+) -> WebResult<()>
+where
+    S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+{
+    while let Some(chunk) = multipart.next_chunk().await? {
+        if writer.size() + chunk.len() as u64 > max_size {
+            return Err(Rejection::TooLarge("The file is too large").into());
+        }
+        writer.write(&chunk).await?;
+    }
 
-#[derive(Deserialize)]
-pub struct GetNotesFilter {
-    pub min_id: Option<i64>,
-    pub max_id: Option<i64>,
+    Ok(())
 }
 
-fn gen_file_name() -> String {
-    let mut ret = String::with_capacity(FILE_NAME_LENGTH);
-    for _ in 0..ret.capacity() {
-        ret.push(FILE_NAME_CHARS[rand::random::<usize>() % FILE_NAME_CHARS.len()]);
-    }
-
-    ret
+/// Maps the resource path stored in an image note back to its file under `res/images`
+pub fn img_file_path(data: &Path, note: &Note) -> Option<PathBuf> {
+    image_store::file_path(data, &note.text)
 }
 
-async fn save_img_file(ctx: &AppState, note: &mut Note) -> Result<(), failure::Error> {
-    lazy_static! {
-        static ref DATA_URL_PREFIX: Regex = Regex::new(r"data:image/(?P<ext>.+);base64").unwrap();
+/// The stored image or file a note refers to, if any
+fn blob_path(note: &Note) -> Option<String> {
+    match note.note_type {
+        NoteType::Image => Some(note.text.clone()),
+        NoteType::File => FileAttachment::from_text(&note.text).map(|file| file.path),
+        NoteType::Text => None,
     }
+}
 
-    if let Some(pos) = note.text.find(",") {
-        if let Some(caps) = DATA_URL_PREFIX.captures(&note.text[..pos]) {
-            let ext = caps.name("ext").unwrap().as_str();
-            let file_name = gen_file_name();
-            let file_path = ctx
-                .config
-                .data
-                .join("res")
-                .join("images")
-                .join(&file_name)
-                .with_extension(ext);
+/// Releases the images and files of purged notes, and returns the ones no note refers to anymore
+fn release_blobs<'a, Conn: Deref<Target = rusqlite::Connection>>(
+    conn: &mut Conn,
+    notes: impl Iterator<Item = &'a Note>,
+) -> Result<Vec<String>, failure::Error> {
+    let mut unused = vec![];
+    for path in notes.filter_map(blob_path) {
+        if database::release_blob(conn, &path)? {
+            unused.push(path);
+        }
+    }
 
-            let bin = base64::decode(&note.text[pos + 1..])?;
-            info!(
-                "Saving image ({} bytes) to path: {}",
-                bin.len(),
-                file_path.display()
-            );
-            let mut file = File::create(&file_path).await?;
-            file.write_all(&bin).await?;
+    Ok(unused)
+}
 
-            note.text = format!("/res/images/{}.{}", file_name, ext);
-        }
+/// Checks the attachment of a new file note against the store,
+/// keeping only the name from the client
+fn check_attachment(data: &Path, text: &str) -> Option<FileAttachment> {
+    let file = FileAttachment::from_text(text)?;
+    let size = std::fs::metadata(file_store::file_path(data, &file.path)?)
+        .ok()?
+        .len();
 
-        Ok(())
-    } else {
-        Ok(())
-    }
+    Some(FileAttachment {
+        name: clean_file_name(&file.name),
+        size,
+        path: file.path,
+    })
 }
 
 pub async fn http_save_note(
     ctx: web::Data<AppState>,
     mut req: web::Json<Note>,
-) -> WebResult<HttpResponse> {
+) -> WebResult<web::Json<Note>> {
     req.timestamp = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs() as i64;
 
     let mut note = req.into_inner();
-    let mut conn = ctx.db.lock().unwrap();
-    let mut tx = database::tx(&mut conn)?;
+    let colors = ctx.config.tag_colors.clone();
+    let data = ctx.config.data.clone();
+    let saved = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+            let is_image = image_store::file_path(&data, &note.text).is_some();
+            if note.note_type == NoteType::Image
+                && !(is_image && database::add_blob_ref(&mut tx, &note.text)?)
+            {
+                return Ok(Err(Rejection::BadRequest(
+                    "Image notes must refer to an uploaded image",
+                )));
+            }
+
+            if note.note_type == NoteType::File {
+                let file = match check_attachment(&data, &note.text) {
+                    Some(file) if database::add_blob_ref(&mut tx, &file.path)? => file,
+                    _ => {
+                        return Ok(Err(Rejection::BadRequest(
+                            "File notes must refer to an uploaded file",
+                        )))
+                    }
+                };
+                note.text = file.to_text();
+            }
 
-    if note.note_type == NoteType::Image {
-        save_img_file(&ctx, &mut note).await?;
+            note.id = database::save_note(&note, &mut tx)?;
+            let saved = tag_api::save_tags_for_note(note, &colors, &mut tx)?;
+            database::commit(tx)?;
+            Ok(Ok(saved))
+        })
+        .await?;
+
+    match saved {
+        Ok((note, created_tags)) => {
+            ctx.events.note_saved(&note, created_tags);
+            Ok(web::Json(note))
+        }
+        Err(rejection) => Err(rejection.into()),
     }
+}
 
-    note.id = database::save_note(&note, &mut tx)?;
-    note = tag_api::save_tags_for_note(note, &mut tx)?;
+/// Stores the `file` part of a multipart upload, unless the same image is already stored,
+/// and responds with the path to refer to it with in an image note.
+/// The format of the image is told from its content, whatever type the client declared.
+pub async fn http_upload_image(
+    ctx: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> WebResult<web::Json<UploadedFile>> {
+    let mut multipart = Multipart::new(req.headers(), payload)?;
+    while let Some(part) = multipart.next_part().await? {
+        if part.name != "file" {
+            continue;
+        }
+
+        let mut writer = BlobWriter::create(&ctx.config.data).await?;
+        if let Err(e) = write_part(&mut multipart, &mut writer, ctx.config.max_upload_size).await {
+            writer.discard().await;
+            return Err(e);
+        }
 
-    database::commit(tx)?;
+        let size = writer.size();
+        let temp_path = writer.finish().await?;
+        let data = ctx.config.data.clone();
+        let prepared = web::block(move || image_store::prepare_upload(&data, &temp_path)).await?;
+        let prepared = match prepared {
+            Some(prepared) => prepared,
+            None => return Err(Rejection::BadRequest("Only images can be uploaded").into()),
+        };
+
+        let path = prepared.blob.url_path();
+        let data = ctx.config.data.clone();
+        let saved_path = path.clone();
+        // Files are placed on the writer, so that one is never removed from under a new note
+        ctx.db
+            .write(move |conn| {
+                prepared.place(&data)?;
+                database::save_blob(conn, &saved_path)
+            })
+            .await?;
+
+        info!("Stored image ({} bytes uploaded) as {}", size, path);
+        return Ok(web::Json(UploadedFile { path }));
+    }
 
-    return Ok(HttpResponse::Ok().json(note));
+    Err(Rejection::BadRequest("Missing file").into())
+}
+
+/// Stores the `file` part of a multipart upload as it is, unless the same file is already stored,
+/// and responds with the attachment to save as the text of a file note
+pub async fn http_upload_file(
+    ctx: web::Data<AppState>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> WebResult<web::Json<FileAttachment>> {
+    let mut multipart = Multipart::new(req.headers(), payload)?;
+    while let Some(part) = multipart.next_part().await? {
+        if part.name != "file" {
+            continue;
+        }
+
+        let name = clean_file_name(part.filename.as_deref().unwrap_or_default());
+        let mut writer = BlobWriter::create(&ctx.config.data).await?;
+        if let Err(e) = write_part(&mut multipart, &mut writer, ctx.config.max_upload_size).await {
+            writer.discard().await;
+            return Err(e);
+        }
+
+        let temp_path = writer.finish().await?;
+        let prepared = web::block(move || file_store::prepare_upload(temp_path)).await?;
+
+        let file = FileAttachment {
+            path: prepared.url_path(),
+            name,
+            size: prepared.size,
+        };
+        let data = ctx.config.data.clone();
+        let saved_path = file.path.clone();
+        ctx.db
+            .write(move |conn| {
+                prepared.place(&data)?;
+                database::save_blob(conn, &saved_path)
+            })
+            .await?;
+
+        info!(
+            "Stored file {} ({} bytes) as {}",
+            file.name, file.size, file.path
+        );
+        return Ok(web::Json(file));
+    }
+
+    Err(Rejection::BadRequest("Missing file").into())
 }
 
 pub async fn http_get_notes(
     ctx: web::Data<AppState>,
     filter: web::Query<GetNotesFilter>,
-) -> WebResult<HttpResponse> {
-    let mut conn = ctx.db.lock().unwrap();
-    let notes = database::get_notes(
-        &mut conn,
-        filter.min_id.unwrap_or(1),
-        filter.max_id.unwrap_or(std::i64::MAX - 1),
-    )?;
-
-    return Ok(HttpResponse::Ok().json(notes));
+) -> WebResult<web::Json<Page<Note>>> {
+    let filter = filter.into_inner();
+    if let Some(ids) = filter.ids {
+        if ids.0.len() as i64 > MAX_PAGE_LIMIT {
+            return Err(Rejection::BadRequest("Too many note IDs").into());
+        }
+
+        let notes = ctx
+            .db
+            .read(move |conn| database::get_notes_by_ids(conn, &ids.0))
+            .await?;
+        return Ok(web::Json(Page {
+            items: notes,
+            next_cursor: None,
+        }));
+    }
+
+    let notes = ctx
+        .db
+        .read(move |conn| {
+            database::get_notes(
+                conn,
+                filter.min_id.unwrap_or(1),
+                filter.max_id.unwrap_or(i64::MAX),
+                &Cursor::from_query(filter.before, filter.after),
+                page_limit(filter.limit),
+            )
+        })
+        .await?;
+
+    Ok(web::Json(notes))
+}
+
+pub async fn http_update_note(
+    ctx: web::Data<AppState>,
+    ex: (web::Path<(i64,)>, web::Json<Note>),
+) -> WebResult<web::Json<Note>> {
+    let note_id = (ex.0).0;
+    let text = ex.1.into_inner().text;
+    let colors = ctx.config.tag_colors.clone();
+
+    let updated = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+
+            let mut note = match database::get_note(&mut tx, note_id)? {
+                Some(note) => note,
+                None => return Ok(Err(Rejection::NotFound("Note not found"))),
+            };
+
+            if note.note_type != NoteType::Text {
+                return Ok(Err(Rejection::BadRequest("Only text notes can be edited")));
+            }
+
+            let mut created_tags = None;
+            if note.text != text {
+                database::save_note_revision(&mut tx, note.id, &note.text)?;
+                database::update_note_text(&mut tx, note_id, &text)?;
+                note.text = text;
+                let (saved, created) = tag_api::update_tags_for_note(note, &colors, &mut tx)?;
+                note = saved;
+                created_tags = Some(created);
+            }
+
+            database::commit(tx)?;
+            Ok(Ok((note, created_tags)))
+        })
+        .await?;
+
+    match updated {
+        Ok((note, created_tags)) => {
+            // Other clients already have the note when its text didn't change
+            if let Some(created_tags) = created_tags {
+                ctx.events.note_saved(&note, created_tags);
+            }
+            Ok(web::Json(note))
+        }
+        Err(rejection) => Err(rejection.into()),
+    }
+}
+
+pub async fn http_get_note_revisions(
+    ctx: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> WebResult<web::Json<Vec<NoteRevision>>> {
+    let note_id = path.0;
+    let revisions = ctx
+        .db
+        .read(move |conn| database::get_note_revisions(conn, note_id))
+        .await?;
+
+    Ok(web::Json(revisions))
+}
+
+pub async fn http_delete_note(
+    ctx: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> WebResult<web::Json<()>> {
+    let note_id = path.0;
+    let trashed = ctx
+        .db
+        .write(move |conn| database::trash_note(conn, note_id))
+        .await?;
+    if !trashed {
+        return Err(Rejection::NotFound("Note not found").into());
+    }
+
+    Ok(web::Json(()))
+}
+
+pub async fn http_get_trash(ctx: web::Data<AppState>) -> WebResult<web::Json<Vec<TrashedNote>>> {
+    let notes = ctx.db.read(database::get_trashed_notes).await?;
+
+    Ok(web::Json(notes))
+}
+
+pub async fn http_restore_note(
+    ctx: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> WebResult<web::Json<Note>> {
+    let note_id = path.0;
+    let restored = ctx
+        .db
+        .write(move |conn| {
+            if !database::restore_note(conn, note_id)? {
+                return Ok(None);
+            }
+
+            database::get_note(conn, note_id)
+        })
+        .await?;
+
+    match restored {
+        Some(note) => Ok(web::Json(note)),
+        None => Err(Rejection::NotFound("Note not found in trash").into()),
+    }
+}
+
+pub async fn http_purge_note(
+    ctx: web::Data<AppState>,
+    path: web::Path<(i64,)>,
+) -> WebResult<web::Json<()>> {
+    let note_id = path.0;
+    let data = ctx.config.data.clone();
+    let purged = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+            let purged = database::purge_note(&mut tx, note_id)?;
+            let unused = release_blobs(&mut tx, purged.iter())?;
+            database::commit(tx)?;
+
+            for url_path in &unused {
+                file_store::remove_stored(&data, url_path);
+            }
+            Ok(purged.is_some())
+        })
+        .await?;
+
+    if !purged {
+        return Err(Rejection::NotFound("Note not found in trash").into());
+    }
+
+    Ok(web::Json(()))
+}
+
+pub async fn http_empty_trash(ctx: web::Data<AppState>) -> WebResult<web::Json<()>> {
+    let data = ctx.config.data.clone();
+    let purged = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+            let mut purged = vec![];
+            for note_id in database::get_trashed_note_ids(&mut tx)? {
+                if let Some(note) = database::purge_note(&mut tx, note_id)? {
+                    purged.push(note);
+                }
+            }
+
+            let unused = release_blobs(&mut tx, purged.iter())?;
+            database::commit(tx)?;
+
+            for url_path in &unused {
+                file_store::remove_stored(&data, url_path);
+            }
+            Ok(purged.len())
+        })
+        .await?;
+
+    info!("Purged {} notes from trash", purged);
+
+    Ok(web::Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_pool::open_test_db;
+    use crate::testing::{app_state, TempDir};
+    use actix_web::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+
+    /// A single `file` part of `size` bytes, read in chunks of 7 bytes
+    fn upload(size: usize) -> Multipart<impl Stream<Item = Result<Bytes, PayloadError>> + Unpin> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("multipart/form-data; boundary=XyZ"),
+        );
+        let mut body =
+            b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a\"\r\n\r\n"
+                .to_vec();
+        body.extend(std::iter::repeat_n(b'x', size));
+        body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+        let chunks: Vec<_> = body
+            .chunks(7)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        Multipart::new(&headers, futures::stream::iter(chunks)).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn stops_uploads_at_the_size_limit() {
+        let dir = TempDir::new();
+
+        let mut multipart = upload(100);
+        multipart.next_part().await.unwrap().unwrap();
+        let mut writer = BlobWriter::create(dir.path()).await.unwrap();
+        let e = write_part(&mut multipart, &mut writer, 50)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.as_response_error().error_response().status(),
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE
+        );
+        // Stopped in the middle of the part, without writing past the limit
+        assert!(
+            writer.size() > 0 && writer.size() <= 50,
+            "{}",
+            writer.size()
+        );
+
+        let mut multipart = upload(50);
+        multipart.next_part().await.unwrap().unwrap();
+        let mut writer = BlobWriter::create(dir.path()).await.unwrap();
+        write_part(&mut multipart, &mut writer, 50).await.unwrap();
+        assert_eq!(writer.size(), 50);
+        assert!(multipart.next_part().await.unwrap().is_none());
+    }
+
+    /// A trashed note of `note_type` referring to the stored file `path`
+    fn trashed_note<Conn: Deref<Target = rusqlite::Connection>>(
+        conn: &mut Conn,
+        note_type: NoteType,
+        text: &str,
+        path: &str,
+    ) -> i64 {
+        let note = Note {
+            id: 0,
+            client_id: String::new(),
+            text: text.to_string(),
+            timestamp: 1,
+            note_type,
+            tags: Default::default(),
+        };
+        let note_id = database::save_note(&note, conn).unwrap();
+        assert!(database::add_blob_ref(conn, path).unwrap());
+        assert!(database::trash_note(conn, note_id).unwrap());
+        note_id
+    }
+
+    fn purge(conn: &mut rusqlite::Transaction, note_id: i64) -> Vec<String> {
+        let purged = database::purge_note(conn, note_id).unwrap();
+        release_blobs(conn, purged.iter()).unwrap()
+    }
+
+    #[test]
+    fn purging_releases_stored_files() {
+        let mut conn = open_test_db();
+        let mut tx = database::tx(&mut conn).unwrap();
+
+        let image = "/res/images/ab/abcd.png";
+        let file = FileAttachment {
+            path: "/res/files/cd/cdef".to_string(),
+            name: "a.txt".to_string(),
+            size: 1,
+        };
+        database::save_blob(&mut tx, image).unwrap();
+        database::save_blob(&mut tx, &file.path).unwrap();
+
+        let first = trashed_note(&mut tx, NoteType::Image, image, image);
+        let second = trashed_note(&mut tx, NoteType::Image, image, image);
+        let attached = trashed_note(&mut tx, NoteType::File, &file.to_text(), &file.path);
+
+        // Still used by the other note
+        assert!(purge(&mut tx, first).is_empty());
+        assert_eq!(
+            database::get_blob_paths(&mut tx).unwrap(),
+            vec![file.path.clone(), image.to_string()]
+        );
+
+        assert_eq!(purge(&mut tx, second), vec![image.to_string()]);
+        assert_eq!(purge(&mut tx, attached), vec![file.path.clone()]);
+        assert!(database::get_blob_paths(&mut tx).unwrap().is_empty());
+
+        // Purging again releases nothing more
+        assert!(purge(&mut tx, second).is_empty());
+    }
+
+    #[test]
+    fn notes_outside_the_trash_keep_their_files() {
+        let mut conn = open_test_db();
+        let mut tx = database::tx(&mut conn).unwrap();
+
+        let image = "/res/images/ab/abcd.png";
+        database::save_blob(&mut tx, image).unwrap();
+        let note_id = trashed_note(&mut tx, NoteType::Image, image, image);
+        assert!(database::restore_note(&mut tx, note_id).unwrap());
+
+        assert!(purge(&mut tx, note_id).is_empty());
+        assert_eq!(database::get_blob_paths(&mut tx).unwrap(), vec![image]);
+    }
+
+    fn status<Conn: Deref<Target = rusqlite::Connection>>(
+        conn: &mut Conn,
+        tag: &str,
+    ) -> Vec<(i64, TagMapStatus)> {
+        let maps = database::get_tag_map(conn, tag, false, &Cursor::First, 10).unwrap();
+        maps.items.iter().map(|m| (m.note_id, m.status)).collect()
+    }
+
+    #[actix_rt::test]
+    async fn edits_keep_a_revision_and_follow_the_tags() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let colors = ctx.config.tag_colors.clone();
+        let note_id = ctx
+            .db
+            .write(move |conn| {
+                let mut tx = database::tx(conn)?;
+                let mut note = Note {
+                    id: 0,
+                    client_id: String::new(),
+                    text: "#keep and #drop".to_string(),
+                    timestamp: 1,
+                    note_type: NoteType::Text,
+                    tags: Default::default(),
+                };
+                note.id = database::save_note(&note, &mut tx)?;
+                let (note, _) = tag_api::save_tags_for_note(note, &colors, &mut tx)?;
+                let archived = TagMap {
+                    tag: "#keep".to_string(),
+                    note_id: note.id,
+                    status: TagMapStatus::Archived,
+                    timestamp: 0,
+                };
+                assert!(database::save_tag_map(&mut tx, "#keep", &archived)?);
+                database::commit(tx)?;
+                Ok(note.id)
+            })
+            .await
+            .unwrap();
+
+        let edit = Note {
+            id: note_id,
+            client_id: String::new(),
+            text: "#keep and #new".to_string(),
+            timestamp: 2,
+            note_type: NoteType::Text,
+            tags: Default::default(),
+        };
+        let note = http_update_note(ctx.clone(), (web::Path::from((note_id,)), web::Json(edit)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(note.text, "#keep and #new");
+        let mut tags: Vec<_> = note.tags.into_iter().collect();
+        tags.sort();
+        assert_eq!(tags, vec!["#keep", "#new"]);
+
+        ctx.db
+            .read(move |conn| {
+                let revisions = database::get_note_revisions(conn, note_id)?;
+                assert_eq!(revisions.len(), 1);
+                assert_eq!(revisions[0].text, "#keep and #drop");
+
+                assert_eq!(
+                    status(conn, "#keep"),
+                    vec![(note_id, TagMapStatus::Archived)]
+                );
+                assert_eq!(status(conn, "#new"), vec![(note_id, TagMapStatus::Active)]);
+                assert!(status(conn, "#drop").is_empty());
+                Ok(())
+            })
+            .await
+            .unwrap();
+    }
 }
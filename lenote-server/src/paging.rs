@@ -0,0 +1,48 @@
+const DEFAULT_PAGE_LIMIT: i64 = 100;
+pub const MAX_PAGE_LIMIT: i64 = 500;
+
+/// Where a page starts.
+/// `First` is the first page in the listing's natural order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cursor<C> {
+    First,
+    Before(C),
+    After(C),
+}
+
+impl<C> Cursor<C> {
+    /// `after` wins when both cursors are given
+    pub fn from_query(before: Option<C>, after: Option<C>) -> Self {
+        match (before, after) {
+            (_, Some(after)) => Cursor::After(after),
+            (Some(before), None) => Cursor::Before(before),
+            (None, None) => Cursor::First,
+        }
+    }
+}
+
+pub fn page_limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursor_from_query() {
+        assert_eq!(Cursor::<i64>::from_query(None, None), Cursor::First);
+        assert_eq!(Cursor::from_query(Some(5), None), Cursor::Before(5));
+        assert_eq!(Cursor::from_query(None, Some(7)), Cursor::After(7));
+        assert_eq!(Cursor::from_query(Some(5), Some(7)), Cursor::After(7));
+    }
+
+    #[test]
+    fn page_limit_is_clamped() {
+        assert_eq!(page_limit(None), DEFAULT_PAGE_LIMIT);
+        assert_eq!(page_limit(Some(20)), 20);
+        assert_eq!(page_limit(Some(0)), 1);
+        assert_eq!(page_limit(Some(-3)), 1);
+        assert_eq!(page_limit(Some(MAX_PAGE_LIMIT + 1)), MAX_PAGE_LIMIT);
+    }
+}
@@ -0,0 +1,78 @@
+use crate::database;
+use crate::{AppState, Rejection};
+use actix_web::{web, Result as WebResult};
+use lenote_common::api::SearchFilter;
+use lenote_common::models::SearchResult;
+
+const DEFAULT_SEARCH_LIMIT: i64 = 50;
+const MAX_SEARCH_LIMIT: i64 = 500;
+
+/// Turns free-form user input into an FTS5 query.
+/// Each word is quoted so that characters such as `#`, `-` or `:` are not
+/// interpreted as FTS5 operators. The last word is matched as a prefix.
+fn to_fts_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q
+        .split_whitespace()
+        .map(|t| format!("\"{}\"", t.replace('"', "\"\"")))
+        .collect();
+
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(format!("{}*", terms.join(" ")))
+}
+
+pub async fn http_search(
+    ctx: web::Data<AppState>,
+    filter: web::Query<SearchFilter>,
+) -> WebResult<web::Json<Vec<SearchResult>>> {
+    let query = match to_fts_query(&filter.q) {
+        Some(query) => query,
+        None => return Err(Rejection::BadRequest("Missing search query").into()),
+    };
+
+    let limit = filter
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    let results = ctx
+        .db
+        .read(move |conn| database::search_notes(conn, &query, limit))
+        .await?;
+
+    Ok(web::Json(results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fts_query_matches_the_last_word_as_a_prefix() {
+        assert_eq!(to_fts_query("hello"), Some(String::from("\"hello\"*")));
+        assert_eq!(
+            to_fts_query("  hello   wor "),
+            Some(String::from("\"hello\" \"wor\"*"))
+        );
+    }
+
+    #[test]
+    fn fts_query_quotes_operators() {
+        assert_eq!(
+            to_fts_query("#work -draft a:b"),
+            Some(String::from("\"#work\" \"-draft\" \"a:b\"*"))
+        );
+        assert_eq!(
+            to_fts_query("say \"hi\""),
+            Some(String::from("\"say\" \"\"\"hi\"\"\"*"))
+        );
+    }
+
+    #[test]
+    fn fts_query_needs_a_word() {
+        assert_eq!(to_fts_query(""), None);
+        assert_eq!(to_fts_query(" \t\n"), None);
+    }
+}
@@ -1,86 +1,593 @@
 use crate::database;
-use crate::AppState;
-use actix_web::{web, HttpResponse, Result as WebResult};
+use crate::paging::{page_limit, Cursor};
+use crate::{AppState, Rejection};
+use actix_web::{web, Result as WebResult};
+use lenote_common::api::{GetTagMapFilter, GetTagsFilter, LiveEvent};
 use lenote_common::models::*;
+use lenote_common::tags::{find_tags, parse_tag, parse_tags, tag_key};
 use regex::Regex;
 use std::collections::HashSet;
 use std::ops::Deref;
 
-const TAG_COLORS: [&'static str; 6] = [
-    "#34495e", "#8e44ad", "#27ae60", "#3498db", "#c0392b", "#f1c40f",
-];
+/// Comma separated palette used when `--tag-colors` is not given
+pub const DEFAULT_TAG_COLORS: &str = "#34495e,#8e44ad,#27ae60,#3498db,#c0392b,#f1c40f";
 
-fn parse_tags(text: &str) -> HashSet<String> {
-    lazy_static! {
-        static ref TAGS_RE: Regex = Regex::new(r"#[a-zA-Z0-9\-_]+").unwrap();
+lazy_static! {
+    static ref COLOR_RE: Regex = Regex::new(r"^#[0-9a-fA-F]{6}$").unwrap();
+}
+
+/// Replaces every occurrence of the tag `from`, in any case, with `to`
+fn replace_tag(text: &str, from: &str, to: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for token in find_tags(text) {
+        if tag_key(token.text) == from {
+            result.push_str(&text[last..token.range.start]);
+            result.push_str(to);
+            last = token.range.end;
+        }
     }
 
-    let mut result = HashSet::new();
+    result.push_str(&text[last..]);
+    result
+}
 
-    for captures in TAGS_RE.captures_iter(text) {
-        for c in captures.iter() {
-            if let Some(match_) = c {
-                result.insert(match_.as_str().to_ascii_lowercase());
-            }
-        }
+/// Turns the tag part of a request path, which may be nested like `project/alpha/`, into a tag
+fn tag_from_path(path: &str) -> String {
+    tag_key(&format!("#{}", path.trim_end_matches('/')))
+}
+
+pub fn is_valid_color(color: &str) -> bool {
+    COLOR_RE.is_match(color)
+}
+
+/// Picks a color from the palette based on the tag name,
+/// so that a tag always gets the same default color.
+fn default_tag_color(tag: &str, colors: &[String]) -> String {
+    // 64-bit FNV-1a, which unlike `DefaultHasher` is stable across Rust versions
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in tag.as_bytes() {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
     }
 
-    return result;
+    colors[(hash % colors.len() as u64) as usize].clone()
+}
+
+fn new_tag_for_note(tag: &str, display: &str, note: &Note, colors: &[String]) -> Tag {
+    Tag {
+        tag: tag.to_string(),
+        display: display.to_string(),
+        color: default_tag_color(tag, colors),
+        maps: vec![TagMap {
+            tag: tag.to_string(),
+            note_id: note.id,
+            status: TagMapStatus::Active,
+            timestamp: note.timestamp,
+        }],
+    }
 }
 
+/// Saves the tags of a new note, and returns it with the tags it used for the first time
 pub fn save_tags_for_note<Conn: Deref<Target = rusqlite::Connection>>(
     mut note: Note,
+    colors: &[String],
     db: &mut Conn,
-) -> Result<Note, failure::Error> {
-    note.tags = parse_tags(&note.text);
+) -> Result<(Note, Vec<String>), failure::Error> {
+    let tags = parse_tags(&note.text);
+    note.tags = tags.keys().cloned().collect();
 
-    let tag_objs: Vec<Tag> = note
-        .tags
+    let tag_objs: Vec<Tag> = tags
         .iter()
-        .map(|t| Tag {
-            tag: t.clone(),
-            color: TAG_COLORS[rand::random::<usize>() % TAG_COLORS.len()].to_string(),
-            maps: vec![TagMap {
-                note_id: note.id,
-                status: TagMapStatus::Active,
-                timestamp: note.timestamp,
-            }],
-        })
+        .map(|(t, display)| new_tag_for_note(t, display, &note, colors))
+        .collect();
+
+    let created = database::save_tags(&tag_objs, db)?;
+    Ok((note, created))
+}
+
+/// Re-parses the tags of an edited note.
+/// `note.tags` must hold the tags saved for the note before the edit.
+/// Tag maps are added for new tags and removed for tags no longer in the text.
+/// Returns the note with the tags it used for the first time.
+pub fn update_tags_for_note<Conn: Deref<Target = rusqlite::Connection>>(
+    mut note: Note,
+    colors: &[String],
+    db: &mut Conn,
+) -> Result<(Note, Vec<String>), failure::Error> {
+    let parsed = parse_tags(&note.text);
+    let new_tags: HashSet<String> = parsed.keys().cloned().collect();
+
+    let added: Vec<Tag> = new_tags
+        .difference(&note.tags)
+        .map(|t| new_tag_for_note(t, &parsed[t], &note, colors))
         .collect();
+    let removed: Vec<String> = note.tags.difference(&new_tags).cloned().collect();
+
+    let created = database::save_tags(&added, db)?;
+    database::delete_tag_maps_for_note(db, note.id, &removed)?;
 
-    database::save_tags(&tag_objs, db)?;
-    Ok(note)
+    note.tags = new_tags;
+    Ok((note, created))
 }
 
-pub async fn http_get_tags(ctx: web::Data<AppState>) -> WebResult<HttpResponse> {
-    let mut conn = ctx.db.lock().unwrap();
-    let tags = database::get_tags(&mut conn)?;
+pub async fn http_get_tags(
+    ctx: web::Data<AppState>,
+    filter: web::Query<GetTagsFilter>,
+) -> WebResult<web::Json<Page<Tag, String>>> {
+    let filter = filter.into_inner();
+    let tags = ctx
+        .db
+        .read(move |conn| {
+            database::get_tags(
+                conn,
+                &Cursor::from_query(filter.before, filter.after),
+                page_limit(filter.limit),
+            )
+        })
+        .await?;
 
-    return Ok(HttpResponse::Ok().json(tags));
+    Ok(web::Json(tags))
+}
+
+pub async fn http_get_tag_stats(ctx: web::Data<AppState>) -> WebResult<web::Json<Vec<TagStats>>> {
+    let stats = ctx.db.read(database::get_tag_stats).await?;
+
+    Ok(web::Json(stats))
 }
 
 pub async fn http_get_tag_map(
     ctx: web::Data<AppState>,
-    path: web::Path<(String,)>,
-) -> WebResult<HttpResponse> {
-    let tag = format!("#{}", path.0);
-    let mut conn = ctx.db.lock().unwrap();
-    let tag_map = database::get_tag_map(&mut conn, &tag)?;
-    Ok(HttpResponse::Ok().json(tag_map))
+    ex: (web::Path<(String,)>, web::Query<GetTagMapFilter>),
+) -> WebResult<web::Json<Page<TagMap>>> {
+    let tag = tag_from_path(&(ex.0).0);
+    let filter = ex.1.into_inner();
+    let tag_map = ctx
+        .db
+        .read(move |conn| {
+            database::get_tag_map(
+                conn,
+                &tag,
+                filter.children.unwrap_or(false),
+                &Cursor::from_query(filter.before, filter.after),
+                page_limit(filter.limit),
+            )
+        })
+        .await?;
+    Ok(web::Json(tag_map))
 }
 
 pub async fn http_save_tag_map(
     ctx: web::Data<AppState>,
     ex: (web::Path<(String,)>, web::Json<TagMap>),
-) -> WebResult<HttpResponse> {
+) -> WebResult<web::Json<TagMap>> {
     let path = ex.0;
-    let tag_map = ex.1;
-    let tag = format!("#{}", path.0);
-    let mut conn = ctx.db.lock().unwrap();
+    let tag_map = ex.1.into_inner();
+    let tag = tag_from_path(&path.0);
+
+    let saved = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+            if !database::save_tag_map(&mut tx, &tag, &tag_map)? {
+                return Ok(Err(Rejection::NotFound("The note is not in the tag")));
+            }
+            // Only the status comes from the client, the rest is what was saved
+            let note = database::get_note(&mut tx, tag_map.note_id)?;
+            database::commit(tx)?;
+            Ok(Ok(TagMap {
+                tag,
+                note_id: tag_map.note_id,
+                status: tag_map.status,
+                timestamp: note.map_or(tag_map.timestamp, |note| note.timestamp),
+            }))
+        })
+        .await?;
+
+    match saved {
+        Ok(tag_map) => {
+            ctx.events.send(&LiveEvent::TagMapChanged(tag_map.clone()));
+            Ok(web::Json(tag_map))
+        }
+        Err(rejection) => Err(rejection.into()),
+    }
+}
+
+/// Rewrites `from` into `to_display` in all note texts, keeping revisions of the old texts.
+/// Returns the ids of the notes rewritten.
+fn rewrite_notes<Conn: Deref<Target = rusqlite::Connection>>(
+    db: &mut Conn,
+    from: &str,
+    to_display: &str,
+) -> Result<Vec<i64>, failure::Error> {
+    let mut rewritten = vec![];
+    for (note_id, text) in database::get_note_texts_for_tag(db, from)? {
+        let new_text = replace_tag(&text, from, to_display);
+        if new_text != text {
+            database::save_note_revision(db, note_id, &text)?;
+            database::update_note_text(db, note_id, &new_text)?;
+            rewritten.push(note_id);
+        }
+    }
+
+    Ok(rewritten)
+}
+
+/// A tag renamed or merged, with the notes rewritten for it
+struct Retagged {
+    target: TagTarget,
+    note_ids: Vec<i64>,
+}
+
+/// Renames or merges the tag `from` into `to_display` in every note.
+/// Renaming to the same tag written differently, like `#meeting` to `#Meeting`,
+/// only changes how it's written in the notes and the tag list.
+fn retag_in_db<Conn: Deref<Target = rusqlite::Connection>>(
+    db: &mut Conn,
+    from: &str,
+    to_display: &str,
+    merge: bool,
+) -> Result<Result<Retagged, Rejection>, failure::Error> {
+    let to = tag_key(to_display);
+    if from == to && merge {
+        return Ok(Err(Rejection::BadRequest("Target tag is the same tag")));
+    }
+
+    if !database::tag_exists(db, from)? {
+        return Ok(Err(Rejection::NotFound("Tag not found")));
+    }
+
+    if from == to {
+        info!("Writing tag {} as {}", from, to_display);
+        let note_ids = rewrite_notes(db, from, to_display)?;
+        database::set_tag_display(db, &to, to_display)?;
+        return Ok(Ok(Retagged {
+            target: TagTarget { tag: to },
+            note_ids,
+        }));
+    }
+
+    let target_exists = database::tag_exists(db, &to)?;
+    if merge && !target_exists {
+        return Ok(Err(Rejection::NotFound("Target tag not found")));
+    }
+    if !merge && target_exists {
+        return Ok(Err(Rejection::Conflict(
+            "Target tag already exists, merge instead",
+        )));
+    }
+
+    info!("Moving tag {} to {}", from, to);
+    let note_ids = rewrite_notes(db, from, to_display)?;
+    database::move_tag(db, from, &to, to_display)?;
+    Ok(Ok(Retagged {
+        target: TagTarget { tag: to },
+        note_ids,
+    }))
+}
+
+async fn retag(
+    ctx: web::Data<AppState>,
+    naked_tag: &str,
+    target: &str,
+    merge: bool,
+) -> WebResult<web::Json<TagTarget>> {
+    let from = tag_from_path(naked_tag);
+    let to_display = match parse_tag(target) {
+        Some(to_display) => to_display,
+        None => return Err(Rejection::BadRequest("Invalid target tag").into()),
+    };
+
+    let moved = ctx
+        .db
+        .write(move |conn| {
+            let mut tx = database::tx(conn)?;
+            let moved = match retag_in_db(&mut tx, &from, &to_display, merge)? {
+                Ok(moved) => moved,
+                Err(rejection) => return Ok(Err(rejection)),
+            };
+            let notes = database::get_notes_by_ids(&mut tx, &moved.note_ids)?;
+            database::commit(tx)?;
+            Ok(Ok((from, moved.target, notes)))
+        })
+        .await?;
+
+    match moved {
+        Ok((from, target, notes)) => {
+            // Other apps drop the old tag, then pick up the rewritten texts
+            ctx.events.send(&LiveEvent::TagRenamed {
+                from,
+                to: target.tag.clone(),
+            });
+            for note in notes {
+                ctx.events.send(&LiveEvent::NoteSaved(note));
+            }
+            Ok(web::Json(target))
+        }
+        Err(rejection) => Err(rejection.into()),
+    }
+}
+
+pub async fn http_rename_tag(
+    ctx: web::Data<AppState>,
+    ex: (web::Path<(String,)>, web::Json<TagTarget>),
+) -> WebResult<web::Json<TagTarget>> {
+    retag(ctx, &(ex.0).0, &ex.1.tag, false).await
+}
 
-    let mut tx = database::tx(&mut conn)?;
-    database::save_tag_map(&mut tx, &tag, &tag_map)?;
-    database::commit(tx)?;
+pub async fn http_merge_tag(
+    ctx: web::Data<AppState>,
+    ex: (web::Path<(String,)>, web::Json<TagTarget>),
+) -> WebResult<web::Json<TagTarget>> {
+    retag(ctx, &(ex.0).0, &ex.1.tag, true).await
+}
+
+pub async fn http_get_tag_palette(ctx: web::Data<AppState>) -> WebResult<web::Json<Vec<String>>> {
+    Ok(web::Json(ctx.config.tag_colors.clone()))
+}
+
+pub async fn http_get_tag_color(
+    ctx: web::Data<AppState>,
+    path: web::Path<(String,)>,
+) -> WebResult<web::Json<TagColor>> {
+    let tag = tag_from_path(&path.0);
+    let color = ctx
+        .db
+        .read(move |conn| database::get_tag_color(conn, &tag))
+        .await?;
+
+    match color {
+        Some(color) => Ok(web::Json(TagColor { color })),
+        None => Err(Rejection::NotFound("Tag not found").into()),
+    }
+}
+
+pub async fn http_save_tag_color(
+    ctx: web::Data<AppState>,
+    ex: (web::Path<(String,)>, web::Json<TagColor>),
+) -> WebResult<web::Json<TagColor>> {
+    let tag = tag_from_path(&(ex.0).0);
+    let color = ex.1.color.to_ascii_lowercase();
+    if !is_valid_color(&color) {
+        return Err(Rejection::BadRequest("Color must look like #rrggbb").into());
+    }
+
+    let saved = ctx
+        .db
+        .write(move |conn| {
+            let saved = database::save_tag_color(conn, &tag, &color)?;
+            Ok(if saved { Some(color) } else { None })
+        })
+        .await?;
+
+    match saved {
+        Some(color) => Ok(web::Json(TagColor { color })),
+        None => Err(Rejection::NotFound("Tag not found").into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db_pool::open_test_db;
+    use crate::testing::{app_state, TempDir};
+    use actix_web::http::StatusCode;
+    use rusqlite::{params, NO_PARAMS};
+
+    fn add_note<Conn: Deref<Target = rusqlite::Connection>>(db: &mut Conn, text: &str) -> i64 {
+        let mut note = Note {
+            id: 0,
+            client_id: String::new(),
+            text: text.to_string(),
+            timestamp: 1,
+            note_type: NoteType::Text,
+            tags: HashSet::new(),
+        };
+        note.id = database::save_note(&note, db).unwrap();
+        let (note, _) = save_tags_for_note(note, &[String::from("#000000")], db).unwrap();
+        note.id
+    }
+
+    fn display<Conn: Deref<Target = rusqlite::Connection>>(db: &mut Conn, tag: &str) -> String {
+        db.query_row(
+            "SELECT display FROM tags WHERE tag = ?1",
+            params![tag],
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    fn text<Conn: Deref<Target = rusqlite::Connection>>(db: &mut Conn, note_id: i64) -> String {
+        database::get_note(db, note_id).unwrap().unwrap().text
+    }
+
+    #[test]
+    fn replaces_tags_by_key() {
+        assert_eq!(
+            replace_tag("#Meeting and #meeting/sub, `#meeting`", "#meeting", "#Talk"),
+            "#Talk and #meeting/sub, `#meeting`"
+        );
+    }
+
+    #[test]
+    fn renaming_to_another_case_rewrites_the_tag() {
+        let mut conn = open_test_db();
+        let mut tx = database::tx(&mut conn).unwrap();
+        let first = add_note(&mut tx, "#meeting at 9");
+        let second = add_note(&mut tx, "#MEETING again");
+
+        let moved = retag_in_db(&mut tx, "#meeting", "#Meeting", false).unwrap();
+        assert_eq!(moved.ok().unwrap().target.tag, "#meeting");
+
+        assert_eq!(display(&mut tx, "#meeting"), "#Meeting");
+        assert_eq!(text(&mut tx, first), "#Meeting at 9");
+        assert_eq!(text(&mut tx, second), "#Meeting again");
+        // The maps stay on the tag, and the old texts are kept as revisions
+        assert!(database::get_note(&mut tx, first)
+            .unwrap()
+            .unwrap()
+            .tags
+            .contains("#meeting"));
+        assert_eq!(
+            database::get_note_revisions(&mut tx, first).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn merging_into_the_same_tag_is_rejected() {
+        let mut conn = open_test_db();
+        let mut tx = database::tx(&mut conn).unwrap();
+        add_note(&mut tx, "#meeting");
+
+        match retag_in_db(&mut tx, "#meeting", "#Meeting", true).unwrap() {
+            Err(Rejection::BadRequest(_)) => {}
+            _ => panic!("Merging a tag into itself should be rejected"),
+        }
+    }
+
+    #[test]
+    fn renaming_moves_the_tag() {
+        let mut conn = open_test_db();
+        let mut tx = database::tx(&mut conn).unwrap();
+        let note_id = add_note(&mut tx, "#meetings today");
+
+        let moved = retag_in_db(&mut tx, "#meetings", "#Meeting", false).unwrap();
+        assert_eq!(moved.ok().unwrap().target.tag, "#meeting");
+        assert!(!database::tag_exists(&mut tx, "#meetings").unwrap());
+        assert_eq!(display(&mut tx, "#meeting"), "#Meeting");
+        assert_eq!(text(&mut tx, note_id), "#Meeting today");
+
+        match retag_in_db(&mut tx, "#meetings", "#other", false).unwrap() {
+            Err(Rejection::NotFound(_)) => {}
+            _ => panic!("Renaming a missing tag should be rejected"),
+        }
+    }
+
+    fn history_len<Conn: Deref<Target = rusqlite::Connection>>(db: &mut Conn) -> i64 {
+        db.query_row("SELECT COUNT(*) FROM tag_map_history", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .unwrap()
+    }
+
+    fn archived(note_id: i64) -> TagMap {
+        TagMap {
+            tag: String::new(),
+            note_id,
+            status: TagMapStatus::Archived,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn saves_tag_maps_only_for_notes_in_the_tag() {
+        let mut conn = open_test_db();
+        let mut db = database::tx(&mut conn).unwrap();
+        let note_id = add_note(&mut db, "a #job");
+        let history = history_len(&mut db);
+
+        assert!(!database::save_tag_map(&mut db, "#other", &archived(note_id)).unwrap());
+        assert!(!database::save_tag_map(&mut db, "#job", &archived(note_id + 1)).unwrap());
+        assert_eq!(history_len(&mut db), history);
+
+        assert!(database::save_tag_map(&mut db, "#job", &archived(note_id)).unwrap());
+        assert_eq!(history_len(&mut db), history + 1);
+    }
+
+    #[actix_rt::test]
+    async fn answers_not_found_for_notes_outside_the_tag() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let note_id = ctx.db.write(|db| Ok(add_note(db, "a #job"))).await.unwrap();
 
-    Ok(HttpResponse::Ok().json(tag_map.into_inner()))
+        let save = |tag: &str| {
+            http_save_tag_map(
+                ctx.clone(),
+                (
+                    web::Path::from((tag.to_string(),)),
+                    web::Json(archived(note_id)),
+                ),
+            )
+        };
+        let e = save("other").await.unwrap_err();
+        assert_eq!(
+            e.as_response_error().error_response().status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(save("job").await.unwrap().status, TagMapStatus::Archived);
+    }
+
+    fn next_event(
+        events: &mut futures::channel::mpsc::UnboundedReceiver<bytes::Bytes>,
+    ) -> LiveEvent {
+        let message = events.try_recv().unwrap();
+        let message = std::str::from_utf8(&message).unwrap();
+        serde_json::from_str(message.trim_start_matches("data: ")).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn tag_map_events_use_the_tag_saved() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let note_id = ctx.db.write(|db| Ok(add_note(db, "a #job"))).await.unwrap();
+        let mut events = ctx.events.subscribe();
+        events.try_recv().unwrap();
+
+        let mut tag_map = archived(note_id);
+        tag_map.tag = String::from("#other");
+        let saved = http_save_tag_map(
+            ctx.clone(),
+            (web::Path::from((String::from("Job"),)), web::Json(tag_map)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(saved.tag, "#job");
+        assert_eq!(saved.timestamp, 1);
+
+        match next_event(&mut events) {
+            LiveEvent::TagMapChanged(changed) => {
+                assert_eq!(changed.tag, "#job");
+                assert_eq!(changed.note_id, note_id);
+                assert_eq!(changed.status, TagMapStatus::Archived);
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn retagging_sends_the_new_tag_and_the_rewritten_notes() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let note_id = ctx.db.write(|db| Ok(add_note(db, "a #job"))).await.unwrap();
+        let mut events = ctx.events.subscribe();
+        events.try_recv().unwrap();
+
+        let target = TagTarget {
+            tag: String::from("#Work"),
+        };
+        let moved = http_rename_tag(
+            ctx.clone(),
+            (web::Path::from((String::from("job"),)), web::Json(target)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(moved.tag, "#work");
+
+        match next_event(&mut events) {
+            LiveEvent::TagRenamed { from, to } => {
+                assert_eq!(from, "#job");
+                assert_eq!(to, "#work");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        match next_event(&mut events) {
+            LiveEvent::NoteSaved(note) => {
+                assert_eq!(note.id, note_id);
+                assert_eq!(note.text, "a #Work");
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+        assert!(events.try_recv().is_err());
+    }
 }
@@ -0,0 +1,57 @@
+//! Helpers shared by the tests
+
+use crate::db_pool::DbPool;
+use crate::events::Broadcaster;
+use crate::{AppConfig, AppState};
+use actix_web::web;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A new directory under the system's temporary directory, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "lenote-test-{}-{}",
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The state of an app storing its data in `dir`
+pub fn app_state(dir: &TempDir) -> web::Data<AppState> {
+    let config = AppConfig {
+        ui: dir.path().join("ui"),
+        pages: dir.path().join("pages"),
+        data: dir.path().to_path_buf(),
+        slow: false,
+        bind: String::from("127.0.0.1"),
+        port: String::from("0"),
+        secure_cookies: false,
+        tag_colors: vec![String::from("#000000")],
+        max_upload_size: 1024,
+    };
+    let db = DbPool::open(&dir.path().join("lenote.db"), 1).unwrap();
+
+    web::Data::new(AppState {
+        config,
+        db,
+        events: Broadcaster::default(),
+    })
+}
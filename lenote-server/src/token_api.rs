@@ -0,0 +1,190 @@
+//! Management of the API tokens of the user logged in.
+//! These routes only take a session, an API token can't be used to make more tokens.
+
+use crate::auth;
+use crate::database;
+use crate::{AppState, Rejection};
+use actix_web::{web, HttpRequest, Result as WebResult};
+use lenote_common::models::*;
+
+const MAX_TOKEN_NAME_LENGTH: usize = 100;
+
+fn user_id(req: &HttpRequest) -> WebResult<i64> {
+    match req.extensions().get::<User>() {
+        Some(user) => Ok(user.id),
+        None => Err(Rejection::Unauthorized("Not logged in").into()),
+    }
+}
+
+pub async fn http_get_tokens(
+    ctx: web::Data<AppState>,
+    req: HttpRequest,
+) -> WebResult<web::Json<Vec<ApiToken>>> {
+    let user_id = user_id(&req)?;
+    let tokens = ctx
+        .db
+        .read(move |conn| database::get_api_tokens(conn, user_id))
+        .await?;
+
+    Ok(web::Json(tokens))
+}
+
+/// Creates a token, responding with its secret this one time
+pub async fn http_create_token(
+    ctx: web::Data<AppState>,
+    req: HttpRequest,
+    new_token: web::Json<NewApiToken>,
+) -> WebResult<web::Json<CreatedApiToken>> {
+    let user_id = user_id(&req)?;
+    let NewApiToken { name, scope } = new_token.into_inner();
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err(Rejection::BadRequest("A token needs a name").into());
+    }
+    if name.chars().count() > MAX_TOKEN_NAME_LENGTH {
+        return Err(Rejection::BadRequest("The name of the token is too long").into());
+    }
+
+    let secret = auth::new_api_token();
+    let token_hash = auth::hash_token(&secret);
+    let token = ctx
+        .db
+        .write(move |conn| database::create_api_token(conn, user_id, &name, &token_hash, scope))
+        .await?;
+
+    info!("Created API token {} ({:?})", token.name, token.scope);
+    Ok(web::Json(CreatedApiToken { token, secret }))
+}
+
+pub async fn http_delete_token(
+    ctx: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<(i64,)>,
+) -> WebResult<web::Json<()>> {
+    let user_id = user_id(&req)?;
+    let token_id = path.0;
+    let deleted = ctx
+        .db
+        .write(move |conn| database::delete_api_token(conn, user_id, token_id))
+        .await?;
+
+    if !deleted {
+        return Err(Rejection::NotFound("Token not found").into());
+    }
+
+    info!("Revoked API token {}", token_id);
+    Ok(web::Json(()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{app_state, TempDir};
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use rusqlite::params;
+
+    /// A request of a user added to the database
+    async fn logged_in(ctx: &web::Data<AppState>, username: &'static str) -> HttpRequest {
+        let user_id = ctx
+            .db
+            .write(move |conn| Ok(database::create_user(conn, username, "hash")?.unwrap()))
+            .await
+            .unwrap();
+
+        let req = test::TestRequest::default().to_http_request();
+        req.extensions_mut().insert(User {
+            id: user_id,
+            username: username.to_string(),
+            created_at: 0,
+        });
+        req
+    }
+
+    fn new_token(name: &str) -> web::Json<NewApiToken> {
+        web::Json(NewApiToken {
+            name: name.to_string(),
+            scope: TokenScope::Read,
+        })
+    }
+
+    async fn token_names(ctx: &web::Data<AppState>, req: &HttpRequest) -> Vec<String> {
+        let tokens = http_get_tokens(ctx.clone(), req.clone()).await.unwrap();
+        tokens.iter().map(|t| t.name.clone()).collect()
+    }
+
+    #[actix_rt::test]
+    async fn creates_lists_and_deletes_tokens() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let ann = logged_in(&ctx, "ann").await;
+        let bob = logged_in(&ctx, "bob").await;
+
+        let created = http_create_token(ctx.clone(), ann.clone(), new_token(" cli "))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(created.token.name, "cli");
+        assert_eq!(created.token.scope, TokenScope::Read);
+        assert!(created.secret.starts_with("lenote_"));
+        http_create_token(ctx.clone(), ann.clone(), new_token("backup"))
+            .await
+            .unwrap();
+
+        assert_eq!(token_names(&ctx, &ann).await, vec!["cli", "backup"]);
+        assert!(token_names(&ctx, &bob).await.is_empty());
+
+        // Only the hash of the secret is stored
+        let secret = created.secret.clone();
+        let stored: Vec<String> = ctx
+            .db
+            .read(|conn| {
+                let mut stmt = conn.prepare("SELECT token_hash FROM api_tokens")?;
+                let hashes = stmt.query_map(params![], |row| row.get(0))?;
+                Ok(hashes.collect::<Result<_, _>>()?)
+            })
+            .await
+            .unwrap();
+        assert!(stored.contains(&auth::hash_token(&secret)));
+        assert!(stored.iter().all(|hash| !hash.contains(&secret)));
+
+        // Tokens of other users can't be deleted
+        let id = created.token.id;
+        let e = http_delete_token(ctx.clone(), bob.clone(), web::Path::from((id,)))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            e.as_response_error().error_response().status(),
+            StatusCode::NOT_FOUND
+        );
+
+        http_delete_token(ctx.clone(), ann.clone(), web::Path::from((id,)))
+            .await
+            .unwrap();
+        assert_eq!(token_names(&ctx, &ann).await, vec!["backup"]);
+        assert!(
+            http_delete_token(ctx.clone(), ann.clone(), web::Path::from((id,)))
+                .await
+                .is_err()
+        );
+    }
+
+    #[actix_rt::test]
+    async fn tokens_need_a_name() {
+        let dir = TempDir::new();
+        let ctx = app_state(&dir);
+        let ann = logged_in(&ctx, "ann").await;
+
+        let long_name = "x".repeat(MAX_TOKEN_NAME_LENGTH + 1);
+        for name in &["  ", long_name.as_str()] {
+            let e = http_create_token(ctx.clone(), ann.clone(), new_token(name))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                e.as_response_error().error_response().status(),
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert!(token_names(&ctx, &ann).await.is_empty());
+    }
+}
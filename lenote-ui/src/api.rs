@@ -0,0 +1,73 @@
+//! Requests of the components to the server, made of the endpoints in `lenote_common::api`
+
+use lenote_common::api::{Endpoint, Method};
+use std::fmt;
+use yew::callback::Callback;
+use yew::format::{Json, Nothing, Text};
+use yew::services::fetch::{self, FetchService, FetchTask, StatusCode};
+use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
+
+/// A request that failed, or whose response couldn't be read
+pub struct Error {
+    pub status: StatusCode,
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+fn fetch_method(method: Method) -> fetch::Method {
+    match method {
+        Method::Get => fetch::Method::GET,
+        Method::Post => fetch::Method::POST,
+        Method::Put => fetch::Method::PUT,
+        Method::Delete => fetch::Method::DELETE,
+    }
+}
+
+/// Sends a request to an endpoint, with its JSON body if it has one.
+/// `callback` gets the response, or what went wrong.
+pub fn request<E: Endpoint>(
+    fetch: &mut FetchService,
+    endpoint: &E,
+    callback: Callback<Result<E::Response, Error>>,
+) -> anyhow::Result<FetchTask>
+where
+    E::Response: 'static,
+{
+    let handler = Callback::from(move |response: FetchResponse<Text>| {
+        let (meta, body) = response.into_parts();
+        let result = match body {
+            Ok(body) if meta.status.is_success() => {
+                // Endpoints without a response send an empty body
+                let json: &str = if body.is_empty() { "null" } else { &body };
+                serde_json::from_str(json).map_err(|e| Error {
+                    status: meta.status,
+                    message: e.to_string(),
+                })
+            }
+            body => Err(Error {
+                status: meta.status,
+                message: format!("META: {:?}, {:?}", meta, body),
+            }),
+        };
+        callback.emit(result);
+    });
+
+    let builder = FetchRequest::builder()
+        .method(fetch_method(E::METHOD))
+        .uri(endpoint.url());
+    match endpoint.body() {
+        Some(body) => fetch.fetch(
+            builder
+                .header("Content-Type", "application/json")
+                .body(Json(body))
+                .unwrap(),
+            handler,
+        ),
+        None => fetch.fetch(builder.body(Nothing).unwrap(), handler),
+    }
+}
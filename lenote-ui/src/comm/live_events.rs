@@ -0,0 +1,70 @@
+use super::{NoteEvent, NoteEventBus, TagEvent, TagEventBus};
+use crate::js_util::decode_uri;
+use lenote_common::api::{LiveEvent, EVENTS_PATH};
+use wasm_bindgen::closure::Closure;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{EventSource, MessageEvent};
+use yew::agent::Dispatched;
+use yew::services::ConsoleService;
+
+/// Listens to the changes the server pushes, including the ones made in other tabs
+/// and devices, and sends them to the event buses. The browser reconnects by itself.
+pub struct LiveEvents {
+    source: EventSource,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl LiveEvents {
+    pub fn connect() -> Result<Self, JsValue> {
+        let source = EventSource::new(EVENTS_PATH)?;
+
+        let mut console = ConsoleService::new();
+        let mut note_events = NoteEventBus::dispatcher();
+        let mut tag_events = TagEventBus::dispatcher();
+        let on_message = Closure::wrap(Box::new(move |e: MessageEvent| {
+            let data = e.data().as_string().unwrap_or_default();
+            let event = match serde_json::from_str(&data) {
+                Ok(event) => event,
+                Err(err) => {
+                    console.error(&format!("Invalid event {}: {}", data, err));
+                    return;
+                }
+            };
+
+            match event {
+                LiveEvent::NoteSaved(note) => {
+                    if !note.tags.is_empty() {
+                        tag_events.send(TagEvent::TagsChanged);
+                    }
+                    note_events.send(NoteEvent::NoteSaved(note));
+                }
+                LiveEvent::TagMapChanged(_) | LiveEvent::TagCreated(_) => {
+                    tag_events.send(TagEvent::TagsChanged)
+                }
+                LiveEvent::TagRenamed { from, to } => {
+                    tag_events.send(TagEvent::TagsChanged);
+                    // The page of the old tag would stay empty, so move over like the app that retagged
+                    let location = yew::utils::window().location();
+                    let path = location.pathname().unwrap_or_default();
+                    let on_old_tag = decode_uri(&path).trim_end_matches('/')
+                        == format!("/app/tag/{}", from.get(1..).unwrap());
+                    if on_old_tag && from != to {
+                        let _ = location.set_href(&format!("/app/tag/{}", to.get(1..).unwrap()));
+                    }
+                }
+            }
+        }) as Box<dyn FnMut(MessageEvent)>);
+        source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Self {
+            source,
+            _on_message: on_message,
+        })
+    }
+}
+
+impl Drop for LiveEvents {
+    fn drop(&mut self) {
+        self.source.close();
+    }
+}
@@ -1,4 +1,5 @@
 mod event_bus;
+mod live_events;
 
 use lenote_common::models::Note;
 use serde::{Deserialize, Serialize};
@@ -7,6 +8,8 @@ use serde::{Deserialize, Serialize};
 pub enum NoteEvent {
     NoteSubmitted(Note),
     NoteSaved(Note),
+    NoteUpdated(Note),
+    NoteDeleted(i64),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -16,3 +19,5 @@ pub enum TagEvent {
 
 pub type NoteEventBus = event_bus::EventBus<NoteEvent>;
 pub type TagEventBus = event_bus::EventBus<TagEvent>;
+
+pub use live_events::LiveEvents;
@@ -3,12 +3,19 @@ use super::note_input::NoteInput;
 use super::tag_summary::TagSummary;
 use super::tag_viewer::TagViewer;
 use super::AppRoute;
+use super::SearchPage;
+use super::TokenSettings;
+use super::TrashViewer;
+use crate::comm::LiveEvents;
+use crate::js_util::decode_uri;
 
+use yew::services::ConsoleService;
 use yew::{html, Component, ComponentLink, Html, ShouldRender};
 use yew_router::{service::RouteService, Switch};
 
 pub struct Composer {
     route_service: RouteService<()>,
+    _live_events: Option<LiveEvents>,
 }
 
 impl Component for Composer {
@@ -16,8 +23,17 @@ impl Component for Composer {
     type Properties = ();
 
     fn create(_: Self::Properties, _: ComponentLink<Self>) -> Self {
+        let live_events = match LiveEvents::connect() {
+            Ok(live_events) => Some(live_events),
+            Err(e) => {
+                ConsoleService::new().error(&format!("Failed to listen to changes: {:?}", e));
+                None
+            }
+        };
+
         Self {
             route_service: RouteService::new(),
+            _live_events: live_events,
         }
     }
 
@@ -28,7 +44,15 @@ impl Component for Composer {
     fn view(&self) -> Html {
         html! {
             <div class="full-height">
-                <div class="page-title"><a href="/">{ "Lenote" }</a></div>
+                <div class="page-title">
+                    <a href="/">{ "Lenote" }</a>
+                    <a class="page-title-link" href="/app/search">{ "Search" }</a>
+                    <a class="page-title-link" href="/app/trash">{ "Trash" }</a>
+                    <a class="page-title-link" href="/app/settings">{ "Settings" }</a>
+                    <form class="logout-form" method="post" action="/logout">
+                        <button type="submit">{ "Log out" }</button>
+                    </form>
+                </div>
                 <div class="composer full-height">
                     <div class="side-pane full-height">
                         <TagSummary />
@@ -56,7 +80,22 @@ impl Composer {
             },
             Some(AppRoute::Tag(tag)) => html! {
                 <>
-                    <TagViewer naked_tag={ tag } />
+                    <TagViewer naked_tag={ decode_uri(&tag) } />
+                </>
+            },
+            Some(AppRoute::Search) => html! {
+                <>
+                    <SearchPage />
+                </>
+            },
+            Some(AppRoute::Trash) => html! {
+                <>
+                    <TrashViewer />
+                </>
+            },
+            Some(AppRoute::Settings) => html! {
+                <>
+                    <TokenSettings />
                 </>
             },
             _ => html! {
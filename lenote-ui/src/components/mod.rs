@@ -2,9 +2,12 @@ mod composer;
 mod note_canvas;
 mod note_input;
 mod note_viewer;
+mod search_page;
 mod tag_map_viewer;
 mod tag_summary;
 mod tag_viewer;
+mod token_settings;
+mod trash_viewer;
 
 use yew_router::Switch;
 
@@ -12,12 +15,21 @@ use yew_router::Switch;
 pub enum AppRoute {
     #[to = "/app/main"]
     Main,
-    #[to = "/app/tag/{anything}"]
+    #[to = "/app/tag/{*}"]
     Tag(String),
+    #[to = "/app/search"]
+    Search,
+    #[to = "/app/trash"]
+    Trash,
+    #[to = "/app/settings"]
+    Settings,
 }
 
 pub use composer::Composer;
 pub use note_viewer::NoteViewer;
+pub use search_page::SearchPage;
 pub use tag_map_viewer::TagMapViewer;
 pub use tag_summary::TagSummary;
 pub use tag_viewer::TagViewer;
+pub use token_settings::TokenSettings;
+pub use trash_viewer::TrashViewer;
@@ -1,30 +1,41 @@
 use super::NoteViewer;
+use crate::api;
 use crate::comm::{NoteEvent, NoteEventBus};
-use lenote_common::models::Note;
+use lenote_common::api::{GetNotes, GetNotesFilter};
+use lenote_common::models::{Note, Page};
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::time::Duration;
 use web_sys::Element;
 use yew::agent::{Bridge, Bridged};
-use yew::format::{Json, Nothing};
 use yew::html::NodeRef;
 use yew::services::fetch::{FetchService, FetchTask};
-use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
 use yew::services::{dialog::DialogService, ConsoleService};
 use yew::services::{timeout::TimeoutTask, TimeoutService};
 use yew::{html, Component, ComponentLink, Html, ShouldRender};
 
 const SHOW_NOTE_TIME_MESSAGE_GAP: i64 = 1800;
+// Start loading older notes when scrolled this close to the top
+const LOAD_OLDER_SCROLL_THRESHOLD: i32 = 100;
 
 struct State {
     notes: Vec<Rc<Note>>,
     pending_notes: HashMap<String, usize>,
+    // Cursor to the page of notes older than the oldest one shown.
+    // None once the first note has been loaded.
+    older_cursor: Option<i64>,
+    loading_older: bool,
+    // Distance from the bottom of the canvas to keep while older notes are prepended
+    scroll_anchor: Option<i32>,
 }
 
 pub enum Msg {
     NewNoteEvent(NoteEvent),
-    NotesLoaded(Vec<Rc<Note>>),
+    NotesLoaded(Vec<Rc<Note>>, Option<i64>),
+    OlderNotesLoaded(Vec<Rc<Note>>, Option<i64>),
+    Scrolled,
     ScrollBottom,
+    RestoreScroll,
     Error(String),
 }
 
@@ -37,6 +48,7 @@ pub struct NoteCanvas {
     timeout: TimeoutService,
     timeout_task: Option<TimeoutTask>,
     fetch_task: Option<anyhow::Result<FetchTask>>,
+    older_fetch_task: Option<anyhow::Result<FetchTask>>,
     canvas_div_ref: NodeRef,
     _note_event_producer: Box<dyn Bridge<NoteEventBus>>,
 }
@@ -51,6 +63,9 @@ impl Component for NoteCanvas {
             state: State {
                 notes: vec![],
                 pending_notes: HashMap::new(),
+                older_cursor: None,
+                loading_older: false,
+                scroll_anchor: None,
             },
             link,
             console: ConsoleService::new(),
@@ -59,6 +74,7 @@ impl Component for NoteCanvas {
             timeout: TimeoutService::new(),
             timeout_task: None,
             fetch_task: None,
+            older_fetch_task: None,
             canvas_div_ref: NodeRef::default(),
             // Need to keep a reference of this so that it won't
             // disconnect from the event bus when going out of scope
@@ -67,7 +83,7 @@ impl Component for NoteCanvas {
     }
 
     fn mounted(&mut self) -> ShouldRender {
-        self.fetch_notes();
+        self.fetch_notes(None);
         false
     }
 
@@ -75,9 +91,15 @@ impl Component for NoteCanvas {
         match msg {
             Msg::NewNoteEvent(e) => self.handle_note_event(e),
             Msg::ScrollBottom => self.scroll_to_bottom(),
-            Msg::NotesLoaded(notes) => {
+            Msg::Scrolled => {
+                self.maybe_load_older();
+                false
+            }
+            Msg::RestoreScroll => self.restore_scroll(),
+            Msg::NotesLoaded(notes, older_cursor) => {
                 self.console.log(&format!("Found {} notes", notes.len()));
                 self.state.notes = notes;
+                self.state.older_cursor = older_cursor;
 
                 // We will send a delayed message
                 // for all the messages to finish rendering
@@ -88,7 +110,9 @@ impl Component for NoteCanvas {
 
                 true
             }
+            Msg::OlderNotesLoaded(notes, older_cursor) => self.prepend_notes(notes, older_cursor),
             Msg::Error(e) => {
+                self.state.loading_older = false;
                 self.dialog.alert(&e);
                 false
             }
@@ -100,7 +124,9 @@ impl Component for NoteCanvas {
             <div
                 ref=self.canvas_div_ref.clone()
                 class="note-canvas"
+                onscroll=self.link.callback(|_| Msg::Scrolled)
             >
+                { self.view_history_status() }
                 {
                     for self.state.notes.iter().enumerate()
                         .map(|(i, note)| self.view_note(note.clone(), i))
@@ -121,11 +147,81 @@ impl NoteCanvas {
         }
     }
 
-    fn scroll_to_bottom(&self) -> ShouldRender {
+    fn view_history_status(&self) -> Html {
+        if self.state.loading_older {
+            html! {
+                <div class="note-canvas-status unselectable">{ "Loading older notes..." }</div>
+            }
+        } else if self.state.older_cursor.is_none() && !self.state.notes.is_empty() {
+            html! {
+                <div class="note-canvas-status unselectable">{ "Beginning of notes" }</div>
+            }
+        } else {
+            html! {}
+        }
+    }
+
+    fn scroll_to_bottom(&mut self) -> ShouldRender {
         self.canvas_div_ref
             .cast::<Element>()
             .unwrap()
             .set_scroll_top(std::i32::MAX - 1);
+
+        // The first page may not be tall enough to scroll
+        self.maybe_load_older();
+        false
+    }
+
+    fn maybe_load_older(&mut self) {
+        if self.state.loading_older {
+            return;
+        }
+
+        let before = match self.state.older_cursor {
+            Some(before) => before,
+            None => return,
+        };
+
+        let canvas = match self.canvas_div_ref.cast::<Element>() {
+            Some(canvas) => canvas,
+            None => return,
+        };
+
+        if canvas.scroll_top() > LOAD_OLDER_SCROLL_THRESHOLD {
+            return;
+        }
+
+        self.state.loading_older = true;
+        self.state.scroll_anchor = Some(canvas.scroll_height() - canvas.scroll_top());
+        self.fetch_notes(Some(before));
+    }
+
+    fn prepend_notes(&mut self, notes: Vec<Rc<Note>>, older_cursor: Option<i64>) -> ShouldRender {
+        self.console
+            .log(&format!("Found {} older notes", notes.len()));
+        self.state.loading_older = false;
+        self.state.older_cursor = older_cursor;
+
+        let count = notes.len();
+        self.state.notes.splice(0..0, notes);
+        for index in self.state.pending_notes.values_mut() {
+            *index += count;
+        }
+
+        // This message will kick in after the older notes are rendered
+        self.link.send_message(Msg::RestoreScroll);
+        true
+    }
+
+    fn restore_scroll(&mut self) -> ShouldRender {
+        if let (Some(anchor), Some(canvas)) = (
+            self.state.scroll_anchor.take(),
+            self.canvas_div_ref.cast::<Element>(),
+        ) {
+            canvas.set_scroll_top(canvas.scroll_height() - anchor);
+        }
+
+        self.maybe_load_older();
         false
     }
 
@@ -150,38 +246,73 @@ impl NoteCanvas {
                     .log(&format!("Marking note {} as saved", note.client_id));
                 if let Some(index) = self.state.pending_notes.remove(&note.client_id) {
                     self.state.notes[index] = Rc::new(note);
+                    return true;
+                }
+
+                // Pushed by the server, either a note we have already or one from elsewhere
+                if let Some(index) = self.state.notes.iter().position(|n| n.id == note.id) {
+                    self.state.notes[index] = Rc::new(note);
+                    true
+                } else if self.state.notes.iter().all(|n| n.id < note.id) {
+                    self.state.notes.push(Rc::new(note));
+                    self.link.send_message(Msg::ScrollBottom);
+                    true
+                } else {
+                    false
+                }
+            }
+            NoteEvent::NoteUpdated(note) => {
+                match self.state.notes.iter().position(|n| n.id == note.id) {
+                    Some(index) => {
+                        self.state.notes[index] = Rc::new(note);
+                        true
+                    }
+                    None => false,
+                }
+            }
+            NoteEvent::NoteDeleted(note_id) => {
+                match self.state.notes.iter().position(|n| n.id == note_id) {
+                    Some(index) => {
+                        self.state.notes.remove(index);
+                        for pending_index in self.state.pending_notes.values_mut() {
+                            if *pending_index > index {
+                                *pending_index -= 1;
+                            }
+                        }
+                        true
+                    }
+                    None => false,
                 }
-                true
             }
         }
     }
 
-    fn fetch_notes(&mut self) {
+    fn fetch_notes(&mut self, before: Option<i64>) {
         self.console.log("Fetching notes");
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<Result<Vec<Note>, anyhow::Error>>>| {
-                let (meta, Json(n)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match n {
-                        Ok(mut notes) => {
-                            let mut note_ptrs = Vec::with_capacity(notes.len());
-                            while let Some(note) = notes.pop() {
-                                note_ptrs.push(Rc::new(note));
-                            }
-
-                            note_ptrs.reverse();
-                            Msg::NotesLoaded(note_ptrs)
+        let is_older = before.is_some();
+        let callback =
+            self.link
+                .callback(move |result: Result<Page<Note>, api::Error>| match result {
+                    Ok(page) => {
+                        let note_ptrs = page.items.into_iter().map(Rc::new).collect();
+                        if is_older {
+                            Msg::OlderNotesLoaded(note_ptrs, page.next_cursor)
+                        } else {
+                            Msg::NotesLoaded(note_ptrs, page.next_cursor)
                         }
-                        Err(e) => Msg::Error(e.to_string()),
                     }
-                } else {
-                    Msg::Error(format!("META: {:?}, {:?}", meta, n))
-                }
-            },
-        );
-        let request = FetchRequest::get("/api/notes").body(Nothing).unwrap();
+                    Err(e) => Msg::Error(e.to_string()),
+                });
+        let endpoint = GetNotes(GetNotesFilter {
+            before,
+            ..Default::default()
+        });
 
-        self.fetch_task = Some(self.fetch.fetch(request, callback));
+        let task = Some(api::request(&mut self.fetch, &endpoint, callback));
+        if is_older {
+            self.older_fetch_task = task;
+        } else {
+            self.fetch_task = task;
+        }
     }
 }
@@ -1,16 +1,17 @@
+use crate::api;
 use crate::comm::{NoteEvent, NoteEventBus, TagEvent, TagEventBus};
 use crate::js_util;
 use js_sys::Math::random;
+use lenote_common::api::{Endpoint, SaveNote, UploadFile, UploadImage};
 use lenote_common::models::*;
 use std::collections::{HashMap, HashSet};
 use wasm_bindgen::closure::Closure;
 use wasm_bindgen::prelude::wasm_bindgen;
+use wasm_bindgen::JsValue;
 use yew::agent::{Dispatched, Dispatcher};
-use yew::events::{InputData, KeyboardEvent};
-use yew::format::Json;
+use yew::events::{DragEvent, InputData, KeyboardEvent};
 use yew::html::NodeRef;
 use yew::services::fetch::{FetchService, FetchTask};
-use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
 use yew::services::ConsoleService;
 use yew::{html, Component, ComponentLink, Html, ShouldRender};
 
@@ -34,10 +35,14 @@ pub enum Msg {
     Submit,
     NoteSaved(Note),
     NoteSaveFailed(String),
-    ImagePasted(String),
+    ImagePasted(JsValue),
+    ImageUploaded(String),
+    FilesDropped(Vec<web_sys::File>),
+    FileUploaded(String),
 }
 
 pub struct NoteInput {
+    /// Random, so that the client IDs of notes from other tabs and devices can't match ours
     id: String,
     state: State,
     link: ComponentLink<Self>,
@@ -47,7 +52,10 @@ pub struct NoteInput {
     fetch: FetchService,
     fetch_tasks: HashMap<String, anyhow::Result<FetchTask>>,
     input_node: NodeRef,
-    paste_callback_interop: Closure<dyn FnMut(String)>,
+    paste_callback_interop: Closure<dyn FnMut(JsValue)>,
+    uploaded_callback_interop: Closure<dyn FnMut(String)>,
+    file_uploaded_callback_interop: Closure<dyn FnMut(String)>,
+    upload_failed_callback_interop: Closure<dyn FnMut(String)>,
 }
 
 impl Component for NoteInput {
@@ -55,7 +63,10 @@ impl Component for NoteInput {
     type Properties = ();
 
     fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
-        let paste_callback = link.callback(|s: String| Msg::ImagePasted(s));
+        let paste_callback = link.callback(|blob: JsValue| Msg::ImagePasted(blob));
+        let uploaded_callback = link.callback(|path: String| Msg::ImageUploaded(path));
+        let file_uploaded_callback = link.callback(|file: String| Msg::FileUploaded(file));
+        let upload_failed_callback = link.callback(|e: String| Msg::NoteSaveFailed(e));
         let mut id = String::with_capacity(16);
         for _ in 0..id.capacity() {
             id.push(CHARS[(random() * CHARS.len() as f64) as usize]);
@@ -75,8 +86,17 @@ impl Component for NoteInput {
             fetch: FetchService::new(),
             fetch_tasks: HashMap::new(),
             input_node: NodeRef::default(),
-            paste_callback_interop: Closure::wrap(Box::new(move |s: String| {
-                paste_callback.emit(s);
+            paste_callback_interop: Closure::wrap(Box::new(move |blob: JsValue| {
+                paste_callback.emit(blob);
+            }) as Box<dyn FnMut(JsValue)>),
+            uploaded_callback_interop: Closure::wrap(Box::new(move |path: String| {
+                uploaded_callback.emit(path);
+            }) as Box<dyn FnMut(String)>),
+            file_uploaded_callback_interop: Closure::wrap(Box::new(move |file: String| {
+                file_uploaded_callback.emit(file);
+            }) as Box<dyn FnMut(String)>),
+            upload_failed_callback_interop: Closure::wrap(Box::new(move |e: String| {
+                upload_failed_callback.emit(e);
             }) as Box<dyn FnMut(String)>),
         }
     }
@@ -86,7 +106,7 @@ impl Component for NoteInput {
             let _ = input.focus().unwrap_or_default();
         }
 
-        set_img_paste_callback('main-input', &self.paste_callback_interop);
+        set_img_paste_callback("main-input", &self.paste_callback_interop);
 
         false
     }
@@ -116,8 +136,45 @@ impl Component for NoteInput {
                 self.state.error = Some(e);
                 true
             }
-            Msg::ImagePasted(img_data) => {
-                self.submit_image(img_data);
+            Msg::ImagePasted(blob) => {
+                self.console.log("Uploading pasted image");
+                upload_image(
+                    &UploadImage.url(),
+                    &blob,
+                    &self.uploaded_callback_interop,
+                    &self.upload_failed_callback_interop,
+                );
+                false
+            }
+            Msg::ImageUploaded(path) => {
+                self.submit_image(path);
+                true
+            }
+            Msg::FilesDropped(files) => {
+                for file in files {
+                    self.console
+                        .log(&format!("Uploading dropped file {}", file.name()));
+                    // Images are shown in the notes, anything else is attached
+                    if file.type_().starts_with("image/") {
+                        upload_image(
+                            &UploadImage.url(),
+                            &file.into(),
+                            &self.uploaded_callback_interop,
+                            &self.upload_failed_callback_interop,
+                        );
+                    } else {
+                        upload_file(
+                            &UploadFile.url(),
+                            &file.into(),
+                            &self.file_uploaded_callback_interop,
+                            &self.upload_failed_callback_interop,
+                        );
+                    }
+                }
+                false
+            }
+            Msg::FileUploaded(file) => {
+                self.submit_file(file);
                 true
             }
             Msg::None => false,
@@ -142,6 +199,22 @@ impl Component for NoteInput {
                         } else { Msg::None }
                     })
                     onpaste="handleInputPaste('main-input', event);"
+                    ondragover=self.link.callback(|e: DragEvent| {
+                        if has_files(&e) {
+                            e.prevent_default();
+                        }
+                        Msg::None
+                    })
+                    ondrop=self.link.callback(|e: DragEvent| {
+                        let files = dropped_files(&e);
+                        if files.is_empty() {
+                            // Leaves dropped text to the browser
+                            Msg::None
+                        } else {
+                            e.prevent_default();
+                            Msg::FilesDropped(files)
+                        }
+                    })
                 ></textarea>
             </div>
         }
@@ -157,7 +230,7 @@ impl NoteInput {
             self.state.last_client_note_id += 1;
             let note = Note {
                 id: 0,
-                client_id: format!("nn-{}", self.state.last_client_note_id),
+                client_id: format!("nn-{}-{}", self.id, self.state.last_client_note_id),
                 text: self.state.note.clone(),
                 timestamp: js_util::now(),
                 note_type: NoteType::Text,
@@ -173,14 +246,14 @@ impl NoteInput {
         }
     }
 
-    fn submit_image(&mut self, img_data: String) {
-        self.console
-            .log(&format!("Submitting image, len: {}", img_data.len()));
+    /// Submits a note for an image already uploaded to `path`
+    fn submit_image(&mut self, path: String) {
+        self.console.log(&format!("Submitting image {}", path));
         self.state.last_client_note_id += 1;
         let note = Note {
             id: 0,
-            client_id: format!("nn-{}", self.state.last_client_note_id),
-            text: img_data,
+            client_id: format!("nn-{}-{}", self.id, self.state.last_client_note_id),
+            text: path,
             timestamp: js_util::now(),
             note_type: NoteType::Image,
             tags: HashSet::new(),
@@ -192,27 +265,34 @@ impl NoteInput {
         self.note_events.send(NoteEvent::NoteSubmitted(note));
     }
 
+    /// Submits a note for a file already uploaded, given the attachment as JSON
+    fn submit_file(&mut self, text: String) {
+        self.console.log(&format!("Submitting file {}", text));
+        self.state.last_client_note_id += 1;
+        let note = Note {
+            id: 0,
+            client_id: format!("nn-{}-{}", self.id, self.state.last_client_note_id),
+            text,
+            timestamp: js_util::now(),
+            note_type: NoteType::File,
+            tags: HashSet::new(),
+        };
+
+        let fetch_task = self.fetch_submit_note(&note);
+        // Need to keep this task alive, otherwise it will go out of scope
+        self.fetch_tasks.insert(note.client_id.clone(), fetch_task);
+        self.note_events.send(NoteEvent::NoteSubmitted(note));
+    }
+
     fn fetch_submit_note(&mut self, note: &Note) -> anyhow::Result<FetchTask> {
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<Result<Note, anyhow::Error>>>| {
-                let (meta, Json(n)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match n {
-                        Ok(note) => Msg::NoteSaved(note),
-                        Err(e) => Msg::NoteSaveFailed(e.to_string()),
-                    }
-                } else {
-                    Msg::NoteSaveFailed(format!("META: {:?}, {:?}", meta, n))
-                }
-            },
-        );
-        let request = FetchRequest::post("/api/notes")
-            .header("Content-Type", "application/json")
-            .body(Json(note))
-            .unwrap();
+        let callback = self
+            .link
+            .callback(|result: Result<Note, api::Error>| match result {
+                Ok(note) => Msg::NoteSaved(note),
+                Err(e) => Msg::NoteSaveFailed(e.to_string()),
+            });
 
-        self.fetch.fetch(request, callback)
+        api::request(&mut self.fetch, &SaveNote(note.clone()), callback)
     }
 
     fn view_error(&self) -> Html {
@@ -228,8 +308,42 @@ impl NoteInput {
     }
 }
 
+/// Whether files, rather than text, are being dragged
+fn has_files(e: &DragEvent) -> bool {
+    e.data_transfer()
+        .map(|dt| dt.types().includes(&JsValue::from_str("Files"), 0))
+        .unwrap_or(false)
+}
+
+fn dropped_files(e: &DragEvent) -> Vec<web_sys::File> {
+    let files = match e.data_transfer().and_then(|dt| dt.files()) {
+        Some(files) => files,
+        None => return vec![],
+    };
+
+    (0..files.length()).filter_map(|i| files.get(i)).collect()
+}
+
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_name = "setImagePasteCallback")]
-    fn set_img_paste_callback(id: &str, cb: &Closure<dyn FnMut(String)>);
+    fn set_img_paste_callback(id: &str, cb: &Closure<dyn FnMut(JsValue)>);
+
+    /// Uploads an image as multipart form data to `url`, the one of `UploadImage`
+    #[wasm_bindgen(js_name = "uploadImage")]
+    fn upload_image(
+        url: &str,
+        blob: &JsValue,
+        on_uploaded: &Closure<dyn FnMut(String)>,
+        on_failed: &Closure<dyn FnMut(String)>,
+    );
+
+    /// Uploads an attached file as multipart form data to `url`, the one of `UploadFile`
+    #[wasm_bindgen(js_name = "uploadFile")]
+    fn upload_file(
+        url: &str,
+        blob: &JsValue,
+        on_uploaded: &Closure<dyn FnMut(String)>,
+        on_failed: &Closure<dyn FnMut(String)>,
+    );
 }
@@ -1,8 +1,20 @@
+use crate::api;
+use crate::comm::{NoteEvent, NoteEventBus, TagEvent, TagEventBus};
 use crate::js_util::get_js_date_string;
+use crate::markdown;
+use crate::text_diff::{diff_lines, DiffLine};
+use lenote_common::api::{DeleteNote, GetNoteRevisions, UpdateNote};
+use lenote_common::files::{format_size, FileAttachment};
+use lenote_common::images::{thumbnail_path, THUMBNAIL_SIZES};
 use lenote_common::models::*;
+use lenote_common::tags::{find_tags, tag_key, TagToken};
 use std::rc::Rc;
+use yew::agent::{Dispatched, Dispatcher};
+use yew::events::InputData;
+use yew::services::fetch::{FetchService, FetchTask};
+use yew::services::{ConsoleService, DialogService};
 use yew::virtual_dom::VNode;
-use yew::{html, Component, ComponentLink, Html, Properties};
+use yew::{html, Component, ComponentLink, Html, Properties, ShouldRender};
 
 #[derive(Properties, Clone)]
 pub struct Props {
@@ -13,30 +25,166 @@ pub struct Props {
     pub note_index: usize,
     #[prop_or_default]
     pub highlight: bool,
+    /// Hides the edit & delete actions, e.g. for notes in the trash
+    #[prop_or_default]
+    pub readonly: bool,
+}
+
+pub enum Msg {
+    StartEdit,
+    EditChanged(String),
+    CancelEdit,
+    SaveEdit,
+    Saved(Note),
+    ToggleRevisions,
+    TogglePlain,
+    RevisionsLoaded(Vec<NoteRevision>),
+    Delete,
+    Deleted,
+    Error(String),
+}
+
+struct State {
+    note: Rc<Note>,
+    /// The text of the note rendered from Markdown, None for other types of notes
+    rendered: Option<String>,
+    /// Shows the text as it was written, rather than rendered
+    plain: bool,
+    editing: Option<String>,
+    saving: bool,
+    revisions: Option<Vec<NoteRevision>>,
+    deleted: bool,
+    error: Option<String>,
 }
 
 pub struct NoteViewer {
+    state: State,
     props: Props,
+    link: ComponentLink<Self>,
+    note_events: Dispatcher<NoteEventBus>,
+    tag_events: Dispatcher<TagEventBus>,
+    console: ConsoleService,
+    dialog: DialogService,
+    fetch: FetchService,
+    fetch_task: Option<anyhow::Result<FetchTask>>,
 }
 
 impl Component for NoteViewer {
-    type Message = ();
+    type Message = Msg;
     type Properties = Props;
-    fn create(props: Self::Properties, _: ComponentLink<Self>) -> Self {
-        Self { props }
+    fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            state: State {
+                note: props.note.clone(),
+                rendered: render(&props.note),
+                plain: false,
+                editing: None,
+                saving: false,
+                revisions: None,
+                deleted: false,
+                error: None,
+            },
+            props,
+            link,
+            note_events: NoteEventBus::dispatcher(),
+            tag_events: TagEventBus::dispatcher(),
+            console: ConsoleService::new(),
+            dialog: DialogService::new(),
+            fetch: FetchService::new(),
+            fetch_task: None,
+        }
     }
 
-    fn update(&mut self, _: Self::Message) -> bool {
-        unimplemented!()
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::StartEdit => {
+                self.state.editing = Some(self.state.note.text.clone());
+                self.state.error = None;
+                true
+            }
+            Msg::EditChanged(text) => {
+                self.state.editing = Some(text);
+                true
+            }
+            Msg::CancelEdit => {
+                self.state.editing = None;
+                self.state.error = None;
+                true
+            }
+            Msg::SaveEdit => self.save_edit(),
+            Msg::Saved(note) => {
+                self.console.log(&format!("Note {} updated", note.id));
+                if note.tags != self.state.note.tags {
+                    self.tag_events.send(TagEvent::TagsChanged);
+                }
+
+                self.state.rendered = render(&note);
+                self.state.note = Rc::new(note.clone());
+                self.state.editing = None;
+                self.state.saving = false;
+                if self.state.revisions.is_some() {
+                    self.fetch_revisions();
+                }
+
+                self.note_events.send(NoteEvent::NoteUpdated(note));
+                true
+            }
+            Msg::ToggleRevisions => {
+                if self.state.revisions.is_some() {
+                    self.state.revisions = None;
+                    true
+                } else {
+                    self.fetch_revisions();
+                    false
+                }
+            }
+            Msg::TogglePlain => {
+                self.state.plain = !self.state.plain;
+                true
+            }
+            Msg::RevisionsLoaded(revisions) => {
+                self.state.revisions = Some(revisions);
+                true
+            }
+            Msg::Delete => self.delete(),
+            Msg::Deleted => {
+                self.console
+                    .log(&format!("Note {} moved to trash", self.state.note.id));
+                if !self.state.note.tags.is_empty() {
+                    self.tag_events.send(TagEvent::TagsChanged);
+                }
+
+                self.state.deleted = true;
+                self.note_events
+                    .send(NoteEvent::NoteDeleted(self.state.note.id));
+                true
+            }
+            Msg::Error(e) => {
+                self.console.error(&e);
+                self.state.saving = false;
+                self.state.error = Some(e);
+                true
+            }
+        }
     }
 
     fn change(&mut self, props: Props) -> bool {
+        // Keep the locally edited copy unless the parent passes down a different note
+        if !Rc::ptr_eq(&self.props.note, &props.note) {
+            self.state.note = props.note.clone();
+            self.state.rendered = render(&props.note);
+        }
+
         self.props = props;
         true
     }
 
     fn view(&self) -> Html {
-        let note = &self.props.note;
+        if self.state.deleted {
+            return html! {};
+        }
+
+        let note = &self.state.note;
         let time_str = get_js_date_string(note.timestamp);
         let title = format!("ID: {}. {}. Tags: {:?}", note.id, time_str, note.tags);
         let note_class = if self.props.highlight {
@@ -61,16 +209,28 @@ impl Component for NoteViewer {
                         html! {}
                     }
                 }
-                { match note.note_type {
-                    NoteType::Text => self.view_text(),
-                    NoteType::Image => self.view_image(),
-                    _ => html! {}
+                { self.view_actions() }
+                { match (note.note_type, &self.state.editing) {
+                    (NoteType::Text, Some(text)) => self.view_editor(text),
+                    (NoteType::Text, None) => self.view_text(),
+                    (NoteType::Image, _) => self.view_image(),
+                    (NoteType::File, _) => self.view_file(),
                 }}
+                { self.view_error() }
+                { self.view_revisions() }
             </div>
         }
     }
 }
 
+fn render(note: &Note) -> Option<String> {
+    match note.note_type {
+        NoteType::Text => Some(markdown::render(&note.text)),
+        _ => None,
+    }
+}
+
+/// Makes a node out of HTML, which must be sanitized if it comes from a note
 fn raw_node(element: &str, inner_html: &str) -> Html {
     let elem = yew::utils::document().create_element(element).unwrap();
     elem.set_inner_html(inner_html);
@@ -81,18 +241,46 @@ fn raw_node(element: &str, inner_html: &str) -> Html {
 
 impl NoteViewer {
     fn view_text(&self) -> Html {
-        let note = &self.props.note;
+        let note = &self.state.note;
+        match &self.state.rendered {
+            Some(rendered) if !self.state.plain => {
+                let class = if note.id > 0 {
+                    "note-markdown"
+                } else {
+                    "note-markdown note-line-pending"
+                };
+                html! {
+                    <div class={ class }>{ raw_node("div", rendered) }</div>
+                }
+            }
+            _ => self.view_plain_text(),
+        }
+    }
+
+    fn view_plain_text(&self) -> Html {
+        let note = &self.state.note;
+        // Tags are found in the whole text, as code spans can cover several lines
+        let tags = find_tags(&note.text);
+        let mut line_start = 0;
+
         html! {
             {
-                for note.text.lines().map(|l| {
+                for note.text.split_terminator('\n').map(|line| {
+                    let start = line_start;
+                    line_start += line.len() + 1;
+
+                    let l = line.trim_end_matches('\r');
                     if l.trim().is_empty() {
                         raw_node("div", "&nbsp;")
                     } else {
+                        let line_tags = tags
+                            .iter()
+                            .filter(|t| t.range.start >= start && t.range.end <= start + l.len());
                         html! {
                             <div
                                 class={ if note.id > 0 { "note-line" } else { "note-line-pending" }}
                             >
-                                { l }
+                                { view_line_with_tags(l, start, line_tags) }
                             </div>
                         }
                     }
@@ -101,10 +289,253 @@ impl NoteViewer {
         }
     }
 
+    /// Shows the thumbnail of an image, which opens the original when clicked
     fn view_image(&self) -> Html {
-        let note = &self.props.note;
+        let note = &self.state.note;
+        let thumbnails: Vec<String> = THUMBNAIL_SIZES
+            .iter()
+            .filter_map(|size| thumbnail_path(&note.text, *size))
+            .collect();
+        if thumbnails.len() != THUMBNAIL_SIZES.len() {
+            return html! {
+                <img class="image-note" src={&note.text} />
+            };
+        }
+
+        let srcset = thumbnails
+            .iter()
+            .enumerate()
+            .map(|(i, path)| format!("{} {}x", path, i + 1))
+            .collect::<Vec<String>>()
+            .join(", ");
+        html! {
+            <a href={note.text.as_str()} target="_blank" rel="noopener">
+                <img class="image-note" src={&thumbnails[0]} srcset={srcset} />
+            </a>
+        }
+    }
+
+    /// Shows the name and size of an attached file, with a link to download it
+    fn view_file(&self) -> Html {
+        let file = match FileAttachment::from_text(&self.state.note.text) {
+            Some(file) => file,
+            None => return html! { <div class="note-line">{ "Unreadable file note" }</div> },
+        };
+
         html! {
-            <img class="image-note" src={&note.text} />
+            <div class="file-note">
+                <a href={file.path.as_str()} download={file.name.as_str()}>{ &file.name }</a>
+                <span class="file-note-size">{ format_size(file.size) }</span>
+            </div>
         }
     }
+
+    fn view_actions(&self) -> Html {
+        let note = &self.state.note;
+        if note.id <= 0 || self.props.readonly || self.state.editing.is_some() {
+            return html! {};
+        }
+
+        let history_caption = if self.state.revisions.is_some() {
+            "Hide history"
+        } else {
+            "History"
+        };
+        let plain_caption = if self.state.plain {
+            "Formatted"
+        } else {
+            "Plain text"
+        };
+
+        html! {
+            <div class="note-actions unselectable">
+                {
+                    if note.note_type == NoteType::Text {
+                        html! {
+                            <>
+                                <a class="link-button" onclick=self.link.callback(|_| Msg::StartEdit)>
+                                    { "Edit" }
+                                </a>
+                                <a class="link-button" onclick=self.link.callback(|_| Msg::ToggleRevisions)>
+                                    { history_caption }
+                                </a>
+                                <a class="link-button" onclick=self.link.callback(|_| Msg::TogglePlain)>
+                                    { plain_caption }
+                                </a>
+                            </>
+                        }
+                    } else {
+                        html! {}
+                    }
+                }
+                <a class="link-button" onclick=self.link.callback(|_| Msg::Delete)>
+                    { "Delete" }
+                </a>
+            </div>
+        }
+    }
+
+    fn view_editor(&self, text: &str) -> Html {
+        html! {
+            <div class="note-editor">
+                <textarea
+                    class="note-input"
+                    rows="5"
+                    value=text
+                    disabled=self.state.saving
+                    oninput=self.link.callback(|e: InputData| Msg::EditChanged(e.value))
+                ></textarea>
+                <div>
+                    <button
+                        disabled=self.state.saving
+                        onclick=self.link.callback(|_| Msg::SaveEdit)
+                    >
+                        { if self.state.saving { "Saving..." } else { "Save" } }
+                    </button>
+                    <button
+                        style="margin-left: 5px;"
+                        disabled=self.state.saving
+                        onclick=self.link.callback(|_| Msg::CancelEdit)
+                    >
+                        { "Cancel" }
+                    </button>
+                </div>
+            </div>
+        }
+    }
+
+    fn view_error(&self) -> Html {
+        if let Some(e) = &self.state.error {
+            html! {
+                <div class="error">{ e }</div>
+            }
+        } else {
+            html! {}
+        }
+    }
+
+    fn view_revisions(&self) -> Html {
+        let revisions = match &self.state.revisions {
+            Some(revisions) => revisions,
+            None => return html! {},
+        };
+
+        if revisions.is_empty() {
+            return html! {
+                <div class="note-revisions">{ "No previous versions" }</div>
+            };
+        }
+
+        // Each revision is compared against the version that replaced it
+        html! {
+            <div class="note-revisions">
+                { for revisions.iter().enumerate().map(|(i, rev)| {
+                    let next_text = revisions
+                        .get(i + 1)
+                        .map(|r| r.text.as_str())
+                        .unwrap_or(&self.state.note.text);
+                    html! {
+                        <div class="note-revision">
+                            <div class="note-revision-time">
+                                { format!("Edited {}", get_js_date_string(rev.timestamp)) }
+                            </div>
+                            { for diff_lines(&rev.text, next_text).into_iter().map(view_diff_line) }
+                        </div>
+                    }
+                }) }
+            </div>
+        }
+    }
+
+    fn save_edit(&mut self) -> ShouldRender {
+        let text = match &self.state.editing {
+            Some(text) if !text.trim().is_empty() => text.clone(),
+            _ => return false,
+        };
+
+        if text == self.state.note.text {
+            self.state.editing = None;
+            return true;
+        }
+
+        self.state.saving = true;
+        let callback = self
+            .link
+            .callback(|result: Result<Note, api::Error>| match result {
+                Ok(note) => Msg::Saved(note),
+                Err(e) => Msg::Error(e.to_string()),
+            });
+
+        let mut note = (*self.state.note).clone();
+        note.text = text;
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &UpdateNote(note), callback));
+        true
+    }
+
+    fn fetch_revisions(&mut self) {
+        let callback =
+            self.link.callback(
+                |result: Result<Vec<NoteRevision>, api::Error>| match result {
+                    Ok(revisions) => Msg::RevisionsLoaded(revisions),
+                    Err(e) => Msg::Error(e.to_string()),
+                },
+            );
+        let endpoint = GetNoteRevisions {
+            id: self.state.note.id,
+        };
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+
+    fn delete(&mut self) -> ShouldRender {
+        if !self.dialog.confirm("Move this note to the trash?") {
+            return false;
+        }
+
+        let callback = self
+            .link
+            .callback(|result: Result<(), api::Error>| match result {
+                Ok(()) => Msg::Deleted,
+                Err(e) => Msg::Error(e.to_string()),
+            });
+        let endpoint = DeleteNote {
+            id: self.state.note.id,
+        };
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+        false
+    }
+}
+
+/// Shows a line of text with its tags linking to their pages.
+/// `start` is the offset of the line in the text the tags were found in.
+fn view_line_with_tags<'a>(
+    line: &str,
+    start: usize,
+    tags: impl Iterator<Item = &'a TagToken<'a>>,
+) -> Html {
+    let mut parts = vec![];
+    let mut last = 0;
+    for tag in tags {
+        let url = format!("/app/tag/{}", tag_key(tag.text).get(1..).unwrap());
+        parts.push(html! { { &line[last..tag.range.start - start] } });
+        parts.push(html! { <a class="note-tag" href={ url }>{ tag.text }</a> });
+        last = tag.range.end - start;
+    }
+    parts.push(html! { { &line[last..] } });
+
+    html! { { for parts } }
+}
+
+fn view_diff_line(line: DiffLine) -> Html {
+    let (class, prefix, text) = match line {
+        DiffLine::Same(l) => ("diff-line", "  ", l),
+        DiffLine::Added(l) => ("diff-line diff-added", "+ ", l),
+        DiffLine::Removed(l) => ("diff-line diff-removed", "- ", l),
+    };
+
+    html! {
+        <div class={ class }>{ format!("{}{}", prefix, text) }</div>
+    }
 }
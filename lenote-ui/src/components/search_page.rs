@@ -0,0 +1,175 @@
+use super::NoteViewer;
+use crate::api;
+use lenote_common::api::{Search, SearchFilter};
+use lenote_common::models::*;
+use std::rc::Rc;
+use yew::events::{InputData, KeyboardEvent};
+use yew::services::fetch::{FetchService, FetchTask};
+use yew::services::ConsoleService;
+use yew::{html, Component, ComponentLink, Html, ShouldRender};
+
+struct State {
+    query: String,
+    results: Vec<(Rc<Note>, String)>,
+    searched: bool,
+    error: Option<String>,
+}
+
+pub enum Msg {
+    None,
+    QueryChanged(String),
+    Search,
+    ResultsLoaded(Vec<SearchResult>),
+    Error(String),
+}
+
+pub struct SearchPage {
+    state: State,
+    link: ComponentLink<Self>,
+    console: ConsoleService,
+    fetch: FetchService,
+    fetch_task: Option<anyhow::Result<FetchTask>>,
+}
+
+impl Component for SearchPage {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            state: State {
+                query: String::from(""),
+                results: vec![],
+                searched: false,
+                error: None,
+            },
+            link,
+            console: ConsoleService::new(),
+            fetch: FetchService::new(),
+            fetch_task: None,
+        }
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::QueryChanged(query) => {
+                self.state.query = query;
+                true
+            }
+            Msg::Search => {
+                self.search();
+                false
+            }
+            Msg::ResultsLoaded(results) => {
+                self.console
+                    .log(&format!("Found {} search results", results.len()));
+                self.state.results = results
+                    .into_iter()
+                    .map(|r| (Rc::new(r.note), r.snippet))
+                    .collect();
+                self.state.searched = true;
+                self.state.error = None;
+                true
+            }
+            Msg::Error(e) => {
+                self.console.error(&e);
+                self.state.error = Some(e);
+                true
+            }
+            Msg::None => false,
+        }
+    }
+
+    fn view(&self) -> Html {
+        html! {
+            <>
+                <div style="margin-bottom: 30px;">
+                    <input
+                        type="text"
+                        class="search-input"
+                        placeholder="Search notes"
+                        value=&self.state.query
+                        oninput=self.link.callback(|e: InputData| Msg::QueryChanged(e.value))
+                        onkeypress=self.link.callback(|e: KeyboardEvent| {
+                            if e.key() == "Enter" { Msg::Search } else { Msg::None }
+                        })
+                    />
+                    <button onclick=self.link.callback(|_| Msg::Search)>{ "Search" }</button>
+                </div>
+                { self.view_error() }
+                { self.view_results() }
+            </>
+        }
+    }
+}
+
+impl SearchPage {
+    fn view_error(&self) -> Html {
+        if let Some(e) = &self.state.error {
+            html! {
+                <div class="error">{ e }</div>
+            }
+        } else {
+            html! {}
+        }
+    }
+
+    fn view_results(&self) -> Html {
+        if self.state.searched && self.state.results.is_empty() {
+            return html! {
+                <div>{ "No matching notes" }</div>
+            };
+        }
+
+        html! {
+            { for self.state.results.iter().map(|(note, snippet)| html! {
+                <div class="search-result">
+                    <div class="search-snippet">{ view_snippet(snippet) }</div>
+                    <NoteViewer note={ note.clone() } show_time=true />
+                </div>
+            }) }
+        }
+    }
+
+    fn search(&mut self) {
+        if self.state.query.trim().is_empty() {
+            return;
+        }
+
+        self.console
+            .log(&format!("Searching for {}", self.state.query));
+        let callback =
+            self.link.callback(
+                |result: Result<Vec<SearchResult>, api::Error>| match result {
+                    Ok(results) => Msg::ResultsLoaded(results),
+                    Err(e) => Msg::Error(e.to_string()),
+                },
+            );
+        let endpoint = Search(SearchFilter {
+            q: self.state.query.clone(),
+            ..Default::default()
+        });
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+}
+
+/// Renders a search snippet, wrapping the matched terms in `<mark>`.
+/// The snippet is rendered as text nodes, never as raw HTML.
+fn view_snippet(snippet: &str) -> Html {
+    let mut parts = vec![];
+    for (i, chunk) in snippet.split(SNIPPET_MATCH_START).enumerate() {
+        if i == 0 {
+            parts.push(html! { { chunk } });
+            continue;
+        }
+
+        let mut pieces = chunk.splitn(2, SNIPPET_MATCH_END);
+        let hit = pieces.next().unwrap_or("");
+        let rest = pieces.next().unwrap_or("");
+        parts.push(html! { <mark>{ hit }</mark> });
+        parts.push(html! { { rest } });
+    }
+
+    html! { <>{ for parts.into_iter() }</> }
+}
@@ -1,12 +1,12 @@
 use super::NoteViewer;
+use crate::api;
 use crate::comm::{TagEvent, TagEventBus};
 use crate::js_util::get_js_date_string;
+use lenote_common::api::{GetNotes, GetNotesFilter, SaveTagMap};
 use lenote_common::models::*;
 use std::rc::Rc;
 use yew::agent::{Dispatched, Dispatcher};
-use yew::format::{Json, Nothing};
 use yew::services::fetch::{FetchService, FetchTask};
-use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
 use yew::services::ConsoleService;
 use yew::services::DialogService;
 use yew::Callback;
@@ -132,6 +132,15 @@ impl Component for TagMapViewer {
         html! {
             <>
                 <div class={ status_class }>{ status_str }</div>
+                {
+                    if self.naked_tag() != self.props.naked_tag {
+                        html! {
+                            <div class="tag-map-tag">{ format!("#{}", self.naked_tag()) }</div>
+                        }
+                    } else {
+                        html! {}
+                    }
+                }
                 <div>
                     <span style="margin-left: 5px;">
                         { self.update_status_button(TagMapStatus::Active, "Activate")}
@@ -166,6 +175,14 @@ impl Component for TagMapViewer {
 }
 
 impl TagMapViewer {
+    /// The tag of this map, which can be a child of the viewed tag
+    fn naked_tag(&self) -> &str {
+        match self.props.tag_map.tag.get(1..) {
+            Some(tag) if !tag.is_empty() => tag,
+            _ => &self.props.naked_tag,
+        }
+    }
+
     fn update_status_button(&self, target_status: TagMapStatus, caption: &str) -> Html {
         html! {
             <button
@@ -179,36 +196,22 @@ impl TagMapViewer {
 
     fn fetch_notes(&mut self) {
         self.console.log("Fetching notes");
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<anyhow::Result<Vec<Note>>>>| {
-                let (meta, Json(notes)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match notes {
-                        Ok(mut notes) => {
-                            let mut note_ptrs = Vec::with_capacity(notes.len());
-                            while let Some(note) = notes.pop() {
-                                note_ptrs.push(Rc::new(note));
-                            }
-
-                            note_ptrs.reverse();
-                            Msg::NotesLoaded(note_ptrs)
-                        }
-                        Err(e) => Msg::Error(e.to_string()),
-                    }
-                } else {
-                    Msg::Error(format!("META: {:?}, {:?}", meta, notes))
+        let callback = self
+            .link
+            .callback(|result: Result<Page<Note>, api::Error>| match result {
+                Ok(page) => {
+                    let note_ptrs = page.items.into_iter().map(Rc::new).collect();
+                    Msg::NotesLoaded(note_ptrs)
                 }
-            },
-        );
-        let request = FetchRequest::get(format!(
-            "/api/notes?min_id={}&max_id={}",
-            self.state.min_note_id, self.state.max_note_id,
-        ))
-        .body(Nothing)
-        .unwrap();
-
-        self.fetch_task = Some(self.fetch.fetch(request, callback));
+                Err(e) => Msg::Error(e.to_string()),
+            });
+        let endpoint = GetNotes(GetNotesFilter {
+            min_id: Some(self.state.min_note_id),
+            max_id: Some(self.state.max_note_id),
+            ..Default::default()
+        });
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
     }
 
     fn update_status(&mut self, status: TagMapStatus) -> ShouldRender {
@@ -219,33 +222,24 @@ impl TagMapViewer {
 
         self.state.status_updating_to = Some(status);
 
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<anyhow::Result<TagMap>>>| {
-                let (meta, Json(t)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match t {
-                        Ok(t) => Msg::Updated(t),
-                        Err(e) => Msg::Error(e.to_string()),
-                    }
-                } else {
-                    Msg::Error(format!("META: {:?}, {:?}", meta, t))
-                }
+        let callback = self
+            .link
+            .callback(|result: Result<TagMap, api::Error>| match result {
+                Ok(t) => Msg::Updated(t),
+                Err(e) => Msg::Error(e.to_string()),
+            });
+
+        let endpoint = SaveTagMap {
+            tag: self.naked_tag().to_string(),
+            tag_map: TagMap {
+                tag: self.props.tag_map.tag.clone(),
+                note_id: self.props.tag_map.note_id,
+                status,
+                timestamp: self.props.tag_map.timestamp,
             },
-        );
-
-        let payload = TagMap {
-            note_id: self.props.tag_map.note_id,
-            status: status,
-            timestamp: self.props.tag_map.timestamp,
         };
 
-        let request = FetchRequest::post(format!("/api/tags/{}", self.props.naked_tag))
-            .header("Content-Type", "application/json")
-            .body(Json(&payload))
-            .unwrap();
-
-        self.fetch_task = Some(self.fetch.fetch(request, callback));
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
         true
     }
 }
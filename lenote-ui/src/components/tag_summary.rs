@@ -1,20 +1,80 @@
+use crate::api;
 use crate::comm::{TagEvent, TagEventBus};
+use crate::js_util::get_js_date_string;
+use lenote_common::api::GetTagStats;
 use lenote_common::models::*;
+use std::collections::HashSet;
 use yew::agent::{Bridge, Bridged};
-use yew::format::{Json, Nothing};
 use yew::services::fetch::{FetchService, FetchTask};
-use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
 use yew::services::ConsoleService;
 use yew::{html, Component, ComponentLink, Html, ShouldRender};
 
+/// A tag in the tree of `/` separated tag paths, with counts rolled up from its children
+struct TagNode {
+    // Last segment of the path, as written
+    name: String,
+    tag: String,
+    // None if the tag is only used as a parent, e.g. #project for #project/alpha
+    stats: Option<TagStats>,
+    active_count: i64,
+    archived_count: i64,
+    children: Vec<TagNode>,
+}
+
+fn build_tag_tree(tags: Vec<TagStats>) -> Vec<TagNode> {
+    let mut roots: Vec<TagNode> = vec![];
+
+    for stats in tags {
+        let mut nodes = &mut roots;
+        let mut tag = String::new();
+
+        let keys = stats.tag.trim_start_matches('#').split('/');
+        let names = stats.display.trim_start_matches('#').split('/');
+        for (key, name) in keys.zip(names) {
+            tag = if tag.is_empty() {
+                format!("#{}", key)
+            } else {
+                format!("{}/{}", tag, key)
+            };
+
+            let index = match nodes.iter().position(|n| n.tag == tag) {
+                Some(index) => index,
+                None => {
+                    nodes.push(TagNode {
+                        name: name.to_string(),
+                        tag: tag.clone(),
+                        stats: None,
+                        active_count: 0,
+                        archived_count: 0,
+                        children: vec![],
+                    });
+                    nodes.len() - 1
+                }
+            };
+
+            let node = &mut nodes[index];
+            node.active_count += stats.active_count;
+            node.archived_count += stats.archived_count;
+            if node.tag == stats.tag {
+                node.stats = Some(stats.clone());
+            }
+            nodes = &mut node.children;
+        }
+    }
+
+    roots
+}
+
 struct State {
-    tags: Vec<Tag>,
+    tags: Vec<TagNode>,
+    expanded: HashSet<String>,
     error: Option<String>,
 }
 
 #[derive(Debug)]
 pub enum Msg {
-    TagsLoaded(Vec<Tag>),
+    TagsLoaded(Vec<TagStats>),
+    ToggleExpanded(String),
     NewTagEvent(TagEvent),
     Error(String),
 }
@@ -37,6 +97,7 @@ impl Component for TagSummary {
         Self {
             state: State {
                 tags: vec![],
+                expanded: HashSet::new(),
                 error: None,
             },
             link,
@@ -55,7 +116,13 @@ impl Component for TagSummary {
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::TagsLoaded(tags) => {
-                self.state.tags = tags;
+                self.state.tags = build_tag_tree(tags);
+                true
+            }
+            Msg::ToggleExpanded(tag) => {
+                if !self.state.expanded.remove(&tag) {
+                    self.state.expanded.insert(tag);
+                }
                 true
             }
             Msg::NewTagEvent(e) => self.handle_tag_event(e),
@@ -77,55 +144,97 @@ impl Component for TagSummary {
         html! {
             <>
                 <div class="tag-category">{ "Active Tags" }</div>
-                {
-                    for self.state.tags.iter().map(|tag| {
-                        let actives = tag.maps.iter().filter(|m| m.status == TagMapStatus::Active).count();
-                        if actives == 0 {
-                            html!{}
-                        } else {
-                            let tag_text = format!("{} ({})", tag.tag, actives);
-                            let url = format!("/app/tag/{}", tag.tag.get(1..).unwrap());
-                            html! {
-                                <div class="tag"><a href={url}>{ tag_text }</a></div>
-                            }
-                        }
-                    })
-                }
+                { for self.state.tags.iter().map(|node| self.view_tag(node, true)) }
                 <div class="tag-category">{ "All Tags" }</div>
+                { for self.state.tags.iter().map(|node| self.view_tag(node, false)) }
+            </>
+        }
+    }
+}
+
+impl TagSummary {
+    fn view_tag(&self, node: &TagNode, active_only: bool) -> Html {
+        let count = if active_only {
+            node.active_count
+        } else {
+            node.active_count + node.archived_count
+        };
+        if count == 0 {
+            return html! {};
+        }
+
+        // Only the top level shows the whole tag, children show their last path segment
+        let label = if node.tag.contains('/') {
+            node.name.clone()
+        } else {
+            format!("#{}", node.name)
+        };
+        let tag_text = format!("{} ({})", label, count);
+        let url = format!("/app/tag/{}", node.tag.get(1..).unwrap());
+        let mut title = format!(
+            "{} active, {} archived",
+            node.active_count, node.archived_count
+        );
+        if let Some(stats) = &node.stats {
+            title.push_str(&format!(
+                ". Last used {}",
+                get_js_date_string(stats.last_used)
+            ));
+        }
+
+        let expanded = self.state.expanded.contains(&node.tag);
+        let toggle = if node.children.is_empty() {
+            html! { <span class="tag-toggle"></span> }
+        } else {
+            let tag = node.tag.clone();
+            html! {
+                <span
+                    class="tag-toggle unselectable"
+                    onclick=self.link.callback(move |_| Msg::ToggleExpanded(tag.clone()))
+                >
+                    { if expanded { "\u{25be}" } else { "\u{25b8}" } }
+                </span>
+            }
+        };
+        let swatch = match &node.stats {
+            Some(stats) => html! {
+                <span class="tag-color" style={ format!("background-color: {};", stats.color) }></span>
+            },
+            None => html! {},
+        };
+
+        html! {
+            <>
+                <div class="tag" title={ title }>
+                    { toggle }
+                    { swatch }
+                    <a href={url}>{ tag_text }</a>
+                </div>
                 {
-                    for self.state.tags.iter().map(|tag| {
-                        let tag_text = format!("{} ({})", tag.tag, tag.maps.len());
-                        let url = format!("/app/tag/{}", tag.tag.get(1..).unwrap());
+                    if expanded {
                         html! {
-                            <div class="tag"><a href={url}>{ tag_text }</a></div>
+                            <div class="tag-children">
+                                { for node.children.iter().map(|child| self.view_tag(child, active_only)) }
+                            </div>
                         }
-                    })
+                    } else {
+                        html! {}
+                    }
                 }
             </>
         }
     }
-}
 
-impl TagSummary {
     fn fetch_tags(&mut self) {
         self.console.log("Fetching tags");
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<Result<Vec<Tag>, anyhow::Error>>>| {
-                let (meta, Json(tags)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match tags {
-                        Ok(tags) => Msg::TagsLoaded(tags),
-                        Err(e) => Msg::Error(e.to_string()),
-                    }
-                } else {
-                    Msg::Error(format!("META: {:?}, {:?}", meta, tags))
-                }
-            },
-        );
-        let request = FetchRequest::get("/api/tags").body(Nothing).unwrap();
+        let callback =
+            self.link
+                .callback(|result: Result<Vec<TagStats>, api::Error>| match result {
+                    Ok(tags) => Msg::TagsLoaded(tags),
+                    Err(e) => Msg::Error(e.to_string()),
+                });
 
-        self.fetch_task = Some(self.fetch.fetch(request, callback));
+        self.fetch_task = Some(api::request(&mut self.fetch, &GetTagStats, callback));
     }
 
     fn handle_tag_event(&mut self, e: TagEvent) -> ShouldRender {
@@ -1,9 +1,12 @@
 use super::TagMapViewer;
+use crate::api;
+use crate::comm::{TagEvent, TagEventBus};
+use lenote_common::api::*;
 use lenote_common::models::*;
-use yew::format::{Json, Nothing};
+use yew::agent::{Dispatched, Dispatcher};
+use yew::events::{ChangeData, InputData};
 use yew::services::fetch::{FetchService, FetchTask};
-use yew::services::fetch::{Request as FetchRequest, Response as FetchResponse};
-use yew::services::ConsoleService;
+use yew::services::{ConsoleService, DialogService};
 use yew::{html, Component, ComponentLink, Html, Properties};
 
 #[derive(Properties, Clone)]
@@ -12,27 +15,51 @@ pub struct Props {
 }
 
 pub enum Msg {
-    TagMapLoaded(Vec<TagMap>),
+    TagMapLoaded(Page<TagMap>),
+    LoadMore,
     ToggleShowActives,
     ToggleShowArchived,
+    ToggleIncludeChildren,
     TagMapUpdated((usize, TagMap)),
+    RetagTargetChanged(String),
+    Retag(bool),
+    Retagged(TagTarget),
+    RetagFailed(String),
+    PaletteLoaded(Vec<String>),
+    ColorLoaded(TagColor),
+    // The tag is only a parent of other tags, e.g. #project for #project/alpha
+    TagNotFound,
+    SetColor(String),
     Error(String),
 }
 
 struct State {
     tag_map: Vec<TagMap>,
+    next_cursor: Option<i64>,
     error: Option<String>,
     show_actives: bool,
     show_archived: bool,
+    include_children: bool,
+    retag_target: String,
+    retagging: bool,
+    retag_error: Option<String>,
+    color: Option<String>,
+    tag_exists: bool,
+    palette: Vec<String>,
 }
 
 pub struct TagViewer {
     state: State,
     props: Props,
     link: ComponentLink<Self>,
+    tag_events: Dispatcher<TagEventBus>,
     console: ConsoleService,
+    dialog: DialogService,
     fetch: FetchService,
     fetch_task: Option<anyhow::Result<FetchTask>>,
+    retag_task: Option<anyhow::Result<FetchTask>>,
+    color_task: Option<anyhow::Result<FetchTask>>,
+    palette_task: Option<anyhow::Result<FetchTask>>,
 }
 
 impl Component for TagViewer {
@@ -42,30 +69,50 @@ impl Component for TagViewer {
         Self {
             state: State {
                 tag_map: vec![],
+                next_cursor: None,
                 error: None,
                 show_actives: true,
                 show_archived: false,
+                include_children: true,
+                retag_target: String::from(""),
+                retagging: false,
+                retag_error: None,
+                color: None,
+                tag_exists: true,
+                palette: vec![],
             },
             props,
             link,
+            tag_events: TagEventBus::dispatcher(),
             console: ConsoleService::new(),
+            dialog: DialogService::new(),
             fetch: FetchService::new(),
             fetch_task: None,
+            retag_task: None,
+            color_task: None,
+            palette_task: None,
         }
     }
 
     fn mounted(&mut self) -> bool {
         self.fetch_tag_map();
+        self.fetch_color();
+        self.fetch_palette();
         false
     }
 
     fn update(&mut self, msg: Self::Message) -> bool {
         match msg {
-            Msg::TagMapLoaded(tag_map) => {
+            Msg::TagMapLoaded(page) => {
                 self.console.log("Tag map loaded");
-                self.state.tag_map = tag_map;
+                self.state.tag_map.extend(page.items);
+                self.state.next_cursor = page.next_cursor;
                 true
             }
+            Msg::LoadMore => {
+                self.fetch_tag_map();
+                false
+            }
             Msg::ToggleShowActives => {
                 self.state.show_actives = !self.state.show_actives;
                 true
@@ -74,10 +121,55 @@ impl Component for TagViewer {
                 self.state.show_archived = !self.state.show_archived;
                 true
             }
+            Msg::ToggleIncludeChildren => {
+                self.state.include_children = !self.state.include_children;
+                self.state.tag_map.clear();
+                self.state.next_cursor = None;
+                self.fetch_tag_map();
+                true
+            }
             Msg::TagMapUpdated((i, tag_map)) => {
                 self.state.tag_map[i] = tag_map;
                 true
             }
+            Msg::RetagTargetChanged(target) => {
+                self.state.retag_target = target;
+                true
+            }
+            Msg::Retag(merge) => self.retag(merge),
+            Msg::Retagged(target) => {
+                // The tag is gone, so move over to the page of the resulting tag
+                let url = format!("/app/tag/{}", target.tag.get(1..).unwrap());
+                let _ = yew::utils::window().location().set_href(&url);
+                false
+            }
+            Msg::RetagFailed(e) => {
+                self.console.error(&e);
+                self.state.retagging = false;
+                self.state.retag_error = Some(e);
+                true
+            }
+            Msg::PaletteLoaded(palette) => {
+                self.state.palette = palette;
+                true
+            }
+            Msg::ColorLoaded(tag_color) => {
+                if self.state.color.is_some() {
+                    // The color was changed, the sidebar should pick it up
+                    self.tag_events.send(TagEvent::TagsChanged);
+                }
+
+                self.state.color = Some(tag_color.color);
+                true
+            }
+            Msg::TagNotFound => {
+                self.state.tag_exists = false;
+                true
+            }
+            Msg::SetColor(color) => {
+                self.save_color(color);
+                false
+            }
             Msg::Error(e) => {
                 self.console.error(&e);
                 self.state.error = Some(e);
@@ -97,6 +189,7 @@ impl Component for TagViewer {
             <>
                 <div style="margin-bottom: 30px;">
                     <div class="tag-headline">{ format!("#{}", self.props.naked_tag) }</div>
+                    { self.view_color_picker() }
                     <div>
                         <input
                             type="checkbox"
@@ -116,7 +209,24 @@ impl Component for TagViewer {
                             onclick=self.link.callback(|_| Msg::ToggleShowArchived)
                         />
                         <label for="show_archive">{ "Show Archived Items" }</label>
+
+                        <input
+                            type="checkbox"
+                            name="include_children"
+                            id="include_children"
+                            style="margin-left: 30px"
+                            checked=self.state.include_children
+                            onclick=self.link.callback(|_| Msg::ToggleIncludeChildren)
+                        />
+                        <label for="include_children">{ "Include Sub-tags" }</label>
                     </div>
+                    {
+                        if self.state.tag_exists {
+                            self.view_retag()
+                        } else {
+                            html! {}
+                        }
+                    }
                 </div>
                 { for self.state.tag_map.iter().enumerate().map(|(i, t)|
                     if self.should_show(t) {
@@ -132,6 +242,19 @@ impl Component for TagViewer {
                         html! {}
                     }
                 ) }
+                {
+                    if self.state.next_cursor.is_some() {
+                        html! {
+                            <div class="tag-notes-more">
+                                <a class="link-button" onclick=self.link.callback(|_| Msg::LoadMore)>
+                                    { "Load older items..." }
+                                </a>
+                            </div>
+                        }
+                    } else {
+                        html! {}
+                    }
+                }
             </>
         }
     }
@@ -140,25 +263,169 @@ impl Component for TagViewer {
 impl TagViewer {
     fn fetch_tag_map(&mut self) {
         self.console.log("Fetching tags");
-        let callback = self.link.callback(
-            move |response: FetchResponse<Json<Result<Vec<TagMap>, anyhow::Error>>>| {
-                let (meta, Json(tags)) = response.into_parts();
-
-                if meta.status.is_success() {
-                    match tags {
-                        Ok(tags) => Msg::TagMapLoaded(tags),
-                        Err(e) => Msg::Error(e.to_string()),
+        let callback =
+            self.link
+                .callback(|result: Result<Page<TagMap>, api::Error>| match result {
+                    Ok(tags) => Msg::TagMapLoaded(tags),
+                    Err(e) => Msg::Error(e.to_string()),
+                });
+        let endpoint = GetTagMap {
+            tag: self.props.naked_tag.clone(),
+            filter: GetTagMapFilter {
+                children: Some(self.state.include_children),
+                before: self.state.next_cursor,
+                ..Default::default()
+            },
+        };
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+
+    fn view_color_picker(&self) -> Html {
+        let current = match &self.state.color {
+            Some(color) => color.clone(),
+            None => return html! {},
+        };
+
+        html! {
+            <div class="tag-tools">
+                { for self.state.palette.iter().map(|c| {
+                    let color = c.clone();
+                    let class = if *c == current { "color-swatch selected" } else { "color-swatch" };
+                    html! {
+                        <span
+                            class={ class }
+                            title={ c }
+                            style={ format!("background-color: {};", c) }
+                            onclick=self.link.callback(move |_| Msg::SetColor(color.clone()))
+                        ></span>
+                    }
+                }) }
+                <input
+                    type="color"
+                    title="Custom color"
+                    value=&current
+                    onchange=self.link.callback(|e: ChangeData| match e {
+                        ChangeData::Value(color) => Msg::SetColor(color),
+                        _ => Msg::Error(String::from("Unexpected color input")),
+                    })
+                />
+            </div>
+        }
+    }
+
+    fn fetch_color(&mut self) {
+        let callback = self
+            .link
+            .callback(|result: Result<TagColor, api::Error>| match result {
+                Ok(c) => Msg::ColorLoaded(c),
+                Err(e) if e.status == 404 => Msg::TagNotFound,
+                Err(e) => Msg::Error(e.to_string()),
+            });
+        let endpoint = GetTagColor {
+            tag: self.props.naked_tag.clone(),
+        };
+
+        self.color_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+
+    fn save_color(&mut self, color: String) {
+        let callback = self
+            .link
+            .callback(|result: Result<TagColor, api::Error>| match result {
+                Ok(c) => Msg::ColorLoaded(c),
+                Err(e) => Msg::Error(e.to_string()),
+            });
+        let endpoint = SaveTagColor {
+            tag: self.props.naked_tag.clone(),
+            color: TagColor { color },
+        };
+
+        self.color_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+
+    fn fetch_palette(&mut self) {
+        let callback = self
+            .link
+            .callback(|result: Result<Vec<String>, api::Error>| match result {
+                Ok(p) => Msg::PaletteLoaded(p),
+                Err(e) => Msg::Error(e.to_string()),
+            });
+
+        self.palette_task = Some(api::request(&mut self.fetch, &GetTagPalette, callback));
+    }
+
+    fn view_retag(&self) -> Html {
+        let disabled = self.state.retagging || self.state.retag_target.trim().is_empty();
+        html! {
+            <div class="tag-tools">
+                <input
+                    type="text"
+                    placeholder="#other-tag"
+                    value=&self.state.retag_target
+                    disabled=self.state.retagging
+                    oninput=self.link.callback(|e: InputData| Msg::RetagTargetChanged(e.value))
+                />
+                <button
+                    style="margin-left: 5px;"
+                    disabled=disabled
+                    onclick=self.link.callback(|_| Msg::Retag(false))
+                >
+                    { "Rename" }
+                </button>
+                <button
+                    style="margin-left: 5px;"
+                    disabled=disabled
+                    onclick=self.link.callback(|_| Msg::Retag(true))
+                >
+                    { "Merge Into" }
+                </button>
+                {
+                    if let Some(e) = &self.state.retag_error {
+                        html! { <div class="error">{ e }</div> }
+                    } else {
+                        html! {}
                     }
-                } else {
-                    Msg::Error(format!("META: {:?}, {:?}", meta, tags))
                 }
-            },
-        );
-        let request = FetchRequest::get(format!("/api/tags/{}", self.props.naked_tag))
-            .body(Nothing)
-            .unwrap();
+            </div>
+        }
+    }
+
+    fn retag(&mut self, merge: bool) -> bool {
+        let target = self.state.retag_target.trim().to_string();
+        let question = if merge {
+            format!(
+                "Merge #{} into {}? This rewrites every note using it.",
+                self.props.naked_tag, target
+            )
+        } else {
+            format!(
+                "Rename #{} to {}? This rewrites every note using it.",
+                self.props.naked_tag, target
+            )
+        };
+
+        if !self.dialog.confirm(&question) {
+            return false;
+        }
+
+        self.state.retagging = true;
+        self.state.retag_error = None;
+        let callback = self
+            .link
+            .callback(|result: Result<TagTarget, api::Error>| match result {
+                Ok(t) => Msg::Retagged(t),
+                Err(e) => Msg::RetagFailed(e.to_string()),
+            });
 
-        self.fetch_task = Some(self.fetch.fetch(request, callback));
+        let tag = self.props.naked_tag.clone();
+        let target = TagTarget { tag: target };
+        self.retag_task = Some(if merge {
+            api::request(&mut self.fetch, &MergeTag { tag, target }, callback)
+        } else {
+            api::request(&mut self.fetch, &RenameTag { tag, target }, callback)
+        });
+        true
     }
 
     fn should_show(&self, tag_map: &TagMap) -> bool {
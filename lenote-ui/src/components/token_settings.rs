@@ -0,0 +1,253 @@
+use crate::api;
+use crate::js_util::get_js_date_string;
+use lenote_common::api::{CreateToken, DeleteToken, GetTokens};
+use lenote_common::models::*;
+use yew::events::{InputData, KeyboardEvent};
+use yew::services::fetch::{FetchService, FetchTask};
+use yew::services::{ConsoleService, DialogService};
+use yew::{html, Component, ComponentLink, Html, ShouldRender};
+
+pub enum Msg {
+    TokensLoaded(Vec<ApiToken>),
+    NameChanged(String),
+    ScopeChanged(TokenScope),
+    Create,
+    Created(CreatedApiToken),
+    Revoke(i64),
+    Revoked,
+    Error(String),
+    None,
+}
+
+struct State {
+    tokens: Vec<ApiToken>,
+    loaded: bool,
+    name: String,
+    scope: TokenScope,
+    /// The token created last, whose secret is shown until the page is left
+    created: Option<CreatedApiToken>,
+    error: Option<String>,
+}
+
+pub struct TokenSettings {
+    state: State,
+    link: ComponentLink<Self>,
+    console: ConsoleService,
+    dialog: DialogService,
+    fetch: FetchService,
+    fetch_task: Option<anyhow::Result<FetchTask>>,
+}
+
+impl Component for TokenSettings {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            state: State {
+                tokens: vec![],
+                loaded: false,
+                name: String::new(),
+                scope: TokenScope::Read,
+                created: None,
+                error: None,
+            },
+            link,
+            console: ConsoleService::new(),
+            dialog: DialogService::new(),
+            fetch: FetchService::new(),
+            fetch_task: None,
+        }
+    }
+
+    fn mounted(&mut self) -> ShouldRender {
+        self.fetch_tokens();
+        false
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::TokensLoaded(tokens) => {
+                self.state.tokens = tokens;
+                self.state.loaded = true;
+                true
+            }
+            Msg::NameChanged(name) => {
+                self.state.name = name;
+                false
+            }
+            Msg::ScopeChanged(scope) => {
+                self.state.scope = scope;
+                true
+            }
+            Msg::Create => {
+                if !self.state.name.trim().is_empty() {
+                    self.create_token();
+                }
+                false
+            }
+            Msg::Created(created) => {
+                self.state.name.clear();
+                self.state.created = Some(created);
+                self.fetch_tokens();
+                true
+            }
+            Msg::Revoke(token_id) => {
+                if self
+                    .dialog
+                    .confirm("Revoke this token? Anything using it will stop working.")
+                {
+                    self.revoke_token(token_id);
+                }
+                false
+            }
+            Msg::Revoked => {
+                self.fetch_tokens();
+                false
+            }
+            Msg::Error(e) => {
+                self.console.error(&e);
+                self.state.error = Some(e);
+                true
+            }
+            Msg::None => false,
+        }
+    }
+
+    fn view(&self) -> Html {
+        if let Some(e) = &self.state.error {
+            return html! {
+                <div class="error">{ e }</div>
+            };
+        }
+
+        html! {
+            <>
+                <div class="tag-headline">{ "API Tokens" }</div>
+                <div class="token-help">
+                    { "Tokens let scripts use the notes and tags API without logging in, " }
+                    { "by sending them as " }
+                    <code>{ "Authorization: Bearer <token>" }</code>
+                </div>
+                <div class="token-form">
+                    <input
+                        type="text"
+                        placeholder="Token name"
+                        value=&self.state.name
+                        oninput=self.link.callback(|e: InputData| Msg::NameChanged(e.value))
+                        onkeypress=self.link.callback(|e: KeyboardEvent| {
+                            if e.key() == "Enter" { Msg::Create } else { Msg::None }
+                        })
+                    />
+                    { self.view_scope(TokenScope::Read, "Read only") }
+                    { self.view_scope(TokenScope::Write, "Read & write") }
+                    <button onclick=self.link.callback(|_| Msg::Create)>{ "Create Token" }</button>
+                </div>
+                { self.view_created() }
+                {
+                    if self.state.loaded && self.state.tokens.is_empty() {
+                        html! { <div>{ "There are no tokens yet" }</div> }
+                    } else {
+                        html! {}
+                    }
+                }
+                { for self.state.tokens.iter().map(|token| self.view_token(token)) }
+            </>
+        }
+    }
+}
+
+impl TokenSettings {
+    fn view_scope(&self, scope: TokenScope, label: &str) -> Html {
+        html! {
+            <label class="token-scope">
+                <input
+                    type="radio"
+                    name="token-scope"
+                    checked=self.state.scope == scope
+                    onclick=self.link.callback(move |_| Msg::ScopeChanged(scope))
+                />
+                { label }
+            </label>
+        }
+    }
+
+    fn view_created(&self) -> Html {
+        match &self.state.created {
+            Some(created) => html! {
+                <div class="token-created">
+                    <div>
+                        { format!("Created \"{}\". Copy the token now, it won't be shown again:", created.token.name) }
+                    </div>
+                    <code class="token-secret">{ &created.secret }</code>
+                </div>
+            },
+            None => html! {},
+        }
+    }
+
+    fn view_token(&self, token: &ApiToken) -> Html {
+        let token_id = token.id;
+        let scope = match token.scope {
+            TokenScope::Read => "Read only",
+            TokenScope::Write => "Read & write",
+        };
+        let last_used = match token.last_used_at {
+            Some(t) => format!("Last used {}", get_js_date_string(t)),
+            None => String::from("Never used"),
+        };
+
+        html! {
+            <div class="token">
+                <div>
+                    <span class="token-name">{ &token.name }</span>
+                    <span class="token-scope">{ scope }</span>
+                </div>
+                <div class="tag-map-time">
+                    { format!("Created {} · {}", get_js_date_string(token.created_at), last_used) }
+                </div>
+                <button onclick=self.link.callback(move |_| Msg::Revoke(token_id))>
+                    { "Revoke" }
+                </button>
+            </div>
+        }
+    }
+
+    fn fetch_tokens(&mut self) {
+        let callback =
+            self.link
+                .callback(|result: Result<Vec<ApiToken>, api::Error>| match result {
+                    Ok(tokens) => Msg::TokensLoaded(tokens),
+                    Err(e) => Msg::Error(e.to_string()),
+                });
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &GetTokens, callback));
+    }
+
+    fn create_token(&mut self) {
+        let callback =
+            self.link
+                .callback(|result: Result<CreatedApiToken, api::Error>| match result {
+                    Ok(created) => Msg::Created(created),
+                    Err(e) => Msg::Error(e.to_string()),
+                });
+        let endpoint = CreateToken(NewApiToken {
+            name: self.state.name.trim().to_string(),
+            scope: self.state.scope,
+        });
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+
+    fn revoke_token(&mut self, token_id: i64) {
+        let callback = self
+            .link
+            .callback(|result: Result<(), api::Error>| match result {
+                Ok(()) => Msg::Revoked,
+                Err(e) => Msg::Error(e.to_string()),
+            });
+        let endpoint = DeleteToken { id: token_id };
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &endpoint, callback));
+    }
+}
@@ -0,0 +1,188 @@
+use super::NoteViewer;
+use crate::api;
+use crate::comm::{TagEvent, TagEventBus};
+use crate::js_util::get_js_date_string;
+use lenote_common::api::{EmptyTrash, Endpoint, GetTrash, PurgeNote, RestoreNote};
+use lenote_common::models::*;
+use std::rc::Rc;
+use yew::agent::{Dispatched, Dispatcher};
+use yew::services::fetch::{FetchService, FetchTask};
+use yew::services::{ConsoleService, DialogService};
+use yew::{html, Component, ComponentLink, Html, ShouldRender};
+
+pub enum Msg {
+    TrashLoaded(Vec<TrashedNote>),
+    Restore(i64),
+    Purge(i64),
+    EmptyTrash,
+    Changed,
+    Error(String),
+}
+
+struct State {
+    notes: Vec<(Rc<Note>, i64)>,
+    loaded: bool,
+    error: Option<String>,
+}
+
+pub struct TrashViewer {
+    state: State,
+    link: ComponentLink<Self>,
+    tag_events: Dispatcher<TagEventBus>,
+    console: ConsoleService,
+    dialog: DialogService,
+    fetch: FetchService,
+    fetch_task: Option<anyhow::Result<FetchTask>>,
+}
+
+impl Component for TrashViewer {
+    type Message = Msg;
+    type Properties = ();
+
+    fn create(_: Self::Properties, link: ComponentLink<Self>) -> Self {
+        Self {
+            state: State {
+                notes: vec![],
+                loaded: false,
+                error: None,
+            },
+            link,
+            tag_events: TagEventBus::dispatcher(),
+            console: ConsoleService::new(),
+            dialog: DialogService::new(),
+            fetch: FetchService::new(),
+            fetch_task: None,
+        }
+    }
+
+    fn mounted(&mut self) -> ShouldRender {
+        self.fetch_trash();
+        false
+    }
+
+    fn update(&mut self, msg: Self::Message) -> ShouldRender {
+        match msg {
+            Msg::TrashLoaded(notes) => {
+                self.console
+                    .log(&format!("Found {} notes in trash", notes.len()));
+                self.state.notes = notes
+                    .into_iter()
+                    .map(|t| (Rc::new(t.note), t.deleted_at))
+                    .collect();
+                self.state.loaded = true;
+                true
+            }
+            Msg::Restore(note_id) => {
+                self.send_request(&RestoreNote { id: note_id });
+                false
+            }
+            Msg::Purge(note_id) => {
+                if self.dialog.confirm("Delete this note forever?") {
+                    self.send_request(&PurgeNote { id: note_id });
+                }
+                false
+            }
+            Msg::EmptyTrash => {
+                if self
+                    .dialog
+                    .confirm("Delete all notes in the trash forever?")
+                {
+                    self.send_request(&EmptyTrash);
+                }
+                false
+            }
+            Msg::Changed => {
+                self.tag_events.send(TagEvent::TagsChanged);
+                self.fetch_trash();
+                false
+            }
+            Msg::Error(e) => {
+                self.console.error(&e);
+                self.state.error = Some(e);
+                true
+            }
+        }
+    }
+
+    fn view(&self) -> Html {
+        if let Some(e) = &self.state.error {
+            return html! {
+                <div class="error">{ e }</div>
+            };
+        }
+
+        html! {
+            <>
+                <div style="margin-bottom: 30px;">
+                    <div class="tag-headline">{ "Trash" }</div>
+                    <button
+                        disabled=self.state.notes.is_empty()
+                        onclick=self.link.callback(|_| Msg::EmptyTrash)
+                    >
+                        { "Empty Trash" }
+                    </button>
+                </div>
+                {
+                    if self.state.loaded && self.state.notes.is_empty() {
+                        html! { <div>{ "The trash is empty" }</div> }
+                    } else {
+                        html! {}
+                    }
+                }
+                { for self.state.notes.iter().map(|(note, deleted_at)| self.view_note(note, *deleted_at)) }
+            </>
+        }
+    }
+}
+
+impl TrashViewer {
+    fn view_note(&self, note: &Rc<Note>, deleted_at: i64) -> Html {
+        let note_id = note.id;
+        html! {
+            <div class="trashed-note">
+                <div class="tag-map-time">
+                    { format!("Deleted {}", get_js_date_string(deleted_at)) }
+                </div>
+                <div>
+                    <button onclick=self.link.callback(move |_| Msg::Restore(note_id))>
+                        { "Restore" }
+                    </button>
+                    <button
+                        style="margin-left: 5px;"
+                        onclick=self.link.callback(move |_| Msg::Purge(note_id))
+                    >
+                        { "Delete Forever" }
+                    </button>
+                </div>
+                <NoteViewer note={ note.clone() } show_time=true readonly=true />
+            </div>
+        }
+    }
+
+    fn fetch_trash(&mut self) {
+        self.console.log("Fetching trash");
+        let callback =
+            self.link.callback(
+                |result: Result<Vec<TrashedNote>, api::Error>| match result {
+                    Ok(notes) => Msg::TrashLoaded(notes),
+                    Err(e) => Msg::Error(e.to_string()),
+                },
+            );
+
+        self.fetch_task = Some(api::request(&mut self.fetch, &GetTrash, callback));
+    }
+
+    fn send_request<E: Endpoint>(&mut self, endpoint: &E)
+    where
+        E::Response: 'static,
+    {
+        let callback = self
+            .link
+            .callback(|result: Result<E::Response, api::Error>| match result {
+                Ok(_) => Msg::Changed,
+                Err(e) => Msg::Error(e.to_string()),
+            });
+
+        self.fetch_task = Some(api::request(&mut self.fetch, endpoint, callback));
+    }
+}
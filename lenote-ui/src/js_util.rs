@@ -1,4 +1,4 @@
-use js_sys::{Date, Number};
+use js_sys::{decode_uri_component, Date, Number};
 
 pub fn get_js_date_string(timestamp: i64) -> String {
     return String::from(Date::new(&Number::from(timestamp as f64 * 1000.0)).to_string());
@@ -7,3 +7,11 @@ pub fn get_js_date_string(timestamp: i64) -> String {
 pub fn now() -> i64 {
     (Date::new_0().get_time() / 1000.0) as i64
 }
+
+/// Decodes a percent-encoded URL component, e.g. a non-ASCII tag in the route
+pub fn decode_uri(encoded: &str) -> String {
+    match decode_uri_component(encoded) {
+        Ok(decoded) => String::from(decoded),
+        Err(_) => encoded.to_string(),
+    }
+}
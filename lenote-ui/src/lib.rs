@@ -1,8 +1,14 @@
 #![recursion_limit = "512"]
 
+#[macro_use]
+extern crate lazy_static;
+
+mod api;
 mod comm;
 mod components;
 mod js_util;
+mod markdown;
+mod text_diff;
 
 use wasm_bindgen::prelude::*;
 
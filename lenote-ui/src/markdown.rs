@@ -0,0 +1,151 @@
+//! Rendering of text notes as CommonMark.
+//!
+//! Line breaks are kept as they are typed, as notes are written like messages.
+//! Raw HTML in a note is shown as it was written rather than rendered, and the
+//! resulting HTML is sanitized once more before it is set as `inner_html`.
+//! Fenced code is highlighted with `hl-` classes, styled by `static/css/highlight.css`.
+
+use ammonia::Builder;
+use lenote_common::tags::{find_tags, tag_key, TagToken};
+use pulldown_cmark::escape::{escape_href, escape_html};
+use pulldown_cmark::{html, CodeBlockKind, CowStr, Event, Options, Parser, Tag};
+use std::ops::Range;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+
+lazy_static! {
+    // Only loaded once a note has fenced code
+    static ref SYNTAXES: SyntaxSet = SyntaxSet::load_defaults_newlines();
+    static ref SANITIZER: Builder<'static> = {
+        let mut builder = Builder::default();
+        builder
+            .add_tag_attributes("a", &["class"])
+            .add_tag_attributes("span", &["class"]);
+        builder
+    };
+}
+
+fn escaped(text: &str) -> String {
+    let mut result = String::new();
+    escape_html(&mut result, text).unwrap();
+    result
+}
+
+/// Highlights a code block, or only escapes it if its language is unknown
+fn code_block_html(lang: &str, code: &str) -> String {
+    let syntax = match SYNTAXES.find_syntax_by_token(lang) {
+        Some(syntax) if !lang.is_empty() => syntax,
+        _ => return format!("<pre><code>{}</code></pre>\n", escaped(code)),
+    };
+
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        if generator
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            return format!("<pre><code>{}</code></pre>\n", escaped(code));
+        }
+    }
+
+    format!("<pre><code>{}</code></pre>\n", generator.finalize())
+}
+
+fn tag_html(tag: &str) -> String {
+    let mut url = String::from("/app/tag/");
+    escape_href(&mut url, tag_key(tag).get(1..).unwrap()).unwrap();
+    format!(
+        "<a class=\"note-tag\" href=\"{}\">{}</a>",
+        url,
+        escaped(tag)
+    )
+}
+
+/// Turns the tags of the text at `range` of the note into links.
+/// Only text taken as it is from the note can be matched with its tags,
+/// so text with escapes or entities is left as it is.
+fn push_text<'a>(
+    events: &mut Vec<Event<'a>>,
+    text: CowStr<'a>,
+    range: Range<usize>,
+    source: &str,
+    tags: &[TagToken],
+) {
+    let inner: Vec<&TagToken> = tags
+        .iter()
+        .filter(|t| t.range.start >= range.start && t.range.end <= range.end)
+        .collect();
+    if inner.is_empty() || source.get(range.clone()) != Some(&*text) {
+        events.push(Event::Text(text));
+        return;
+    }
+
+    let mut last = 0;
+    for tag in inner {
+        let start = tag.range.start - range.start;
+        events.push(Event::Text(text[last..start].to_string().into()));
+        events.push(Event::Html(tag_html(tag.text).into()));
+        last = tag.range.end - range.start;
+    }
+    events.push(Event::Text(text[last..].to_string().into()));
+}
+
+/// Renders the text of a note as sanitized HTML
+pub fn render(source: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+
+    let tags = find_tags(source);
+    let mut events = vec![];
+    let mut code: Option<(String, String)> = None;
+    let mut link_depth = 0;
+    for (event, range) in Parser::new_ext(source, options).into_offset_iter() {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                let lang = match kind {
+                    CodeBlockKind::Fenced(info) => info
+                        .split_whitespace()
+                        .next()
+                        .unwrap_or_default()
+                        .to_string(),
+                    CodeBlockKind::Indented => String::new(),
+                };
+                code = Some((lang, String::new()));
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                if let Some((lang, code)) = code.take() {
+                    events.push(Event::Html(code_block_html(&lang, &code).into()));
+                }
+            }
+            Event::Text(text) => match &mut code {
+                Some((_, code)) => code.push_str(&text),
+                None if link_depth > 0 => events.push(Event::Text(text)),
+                None => push_text(&mut events, text, range, source, &tags),
+            },
+            Event::Start(Tag::Link(..)) => {
+                link_depth += 1;
+                events.push(event);
+            }
+            Event::End(Tag::Link(..)) => {
+                link_depth -= 1;
+                events.push(event);
+            }
+            Event::SoftBreak => events.push(Event::HardBreak),
+            // Shown as written, the sanitizer would drop most of it anyway
+            Event::Html(raw) => events.push(Event::Text(raw)),
+            Event::TaskListMarker(done) => {
+                events.push(Event::Text(if done { "☑ " } else { "☐ " }.into()))
+            }
+            event => events.push(event),
+        }
+    }
+
+    let mut result = String::new();
+    html::push_html(&mut result, events.into_iter());
+    SANITIZER.clean(&result).to_string()
+}
@@ -20,12 +20,52 @@ function handleInputPaste(id, event) {
       blob = items[i].getAsFile();
     }
   }
-  // load image if there is a pasted image
   if (blob !== null) {
-    var reader = new FileReader();
-    reader.onload = function (event) {
-      pasteCallback(event.target.result); // data url!
-    };
-    reader.readAsDataURL(blob);
+    pasteCallback(blob);
   }
 }
+
+// Uploads an image as multipart/form-data, which the browser streams from the file.
+// The UI passes the URL of the upload endpoint, so that it is only defined in lenote-common.
+function uploadImage(url, blob, onUploaded, onFailed) {
+  var form = new FormData();
+  form.append("file", blob, blob.name || "pasted-image");
+
+  fetch(url, { method: "POST", body: form })
+    .then(function (response) {
+      if (!response.ok) {
+        return response.text().then(function (text) {
+          throw new Error(response.status + " " + text);
+        });
+      }
+      return response.json();
+    })
+    .then(function (file) {
+      onUploaded(file.path);
+    })
+    .catch(function (e) {
+      onFailed(e.toString());
+    });
+}
+
+// Uploads a dropped file as an attachment, handing over the saved attachment as JSON
+function uploadFile(url, blob, onUploaded, onFailed) {
+  var form = new FormData();
+  form.append("file", blob, blob.name || "file");
+
+  fetch(url, { method: "POST", body: form })
+    .then(function (response) {
+      if (!response.ok) {
+        return response.text().then(function (text) {
+          throw new Error(response.status + " " + text);
+        });
+      }
+      return response.text();
+    })
+    .then(function (file) {
+      onUploaded(file);
+    })
+    .catch(function (e) {
+      onFailed(e.toString());
+    });
+}
@@ -1,4 +1,16 @@
 import init, { run_app } from '/ui/lenote_ui.js';
+
+// Sends the app back to the login page once its session is over
+const fetchWithSession = window.fetch;
+window.fetch = async function (...args) {
+  const response = await fetchWithSession(...args);
+  if (response.status === 401) {
+    const next = window.location.pathname + window.location.search;
+    window.location.href = "/login?next=" + encodeURIComponent(next);
+  }
+  return response;
+};
+
 async function main() {
   await init('/ui/lenote_ui_bg.wasm');
   run_app();